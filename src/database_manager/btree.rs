@@ -0,0 +1,671 @@
+//! Every node is the single record of its page. Page 1 holds whether the file was flushed
+//! completely, page 2 is always the root. Removing a key never merges nodes: underfull
+//! nodes are still valid, and rebuilding the index compacts it again.
+
+use std::{fs, io::ErrorKind, path::Path};
+
+use anyhow::{anyhow, bail};
+use tracing::warn;
+
+use super::{
+    document::RecordId,
+    format::{self, FileKind, FormatError},
+    page::{self, MAX_RECORD_SIZE},
+    pager::{PageId, Pager},
+};
+
+pub const MAX_KEY_SIZE: usize = 1024;
+
+// Length of the key, then the record or child it leads to
+const LEAF_ENTRY_SIZE: usize = 2 + 6;
+const INTERNAL_ENTRY_SIZE: usize = 2 + 4;
+
+// A node only splits once an entry pushed it past a page, and each half holds at most
+// half of it and one more entry
+const _: () = assert!(
+    (MAX_RECORD_SIZE + MAX_KEY_SIZE + LEAF_ENTRY_SIZE) / 2 + MAX_KEY_SIZE + LEAF_ENTRY_SIZE + 7
+        <= MAX_RECORD_SIZE
+);
+
+const META: PageId = 1;
+const ROOT: PageId = 2;
+
+const LEAF: u8 = 0;
+const INTERNAL: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Leaf {
+        keys: Vec<Vec<u8>>,
+        values: Vec<RecordId>,
+    },
+    /// `children[i]` holds the keys below `keys[i]`, and `children[i + 1]` those from it.
+    Internal {
+        keys: Vec<Vec<u8>>,
+        children: Vec<PageId>,
+    },
+}
+
+impl Node {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![];
+
+        match self {
+            Self::Leaf { keys, values } => {
+                bytes.push(LEAF);
+                bytes.extend_from_slice(&(keys.len() as u16).to_le_bytes());
+
+                for (key, value) in keys.iter().zip(values) {
+                    encode_key(key, &mut bytes);
+                    bytes.extend_from_slice(&value.page.to_le_bytes());
+                    bytes.extend_from_slice(&value.slot.to_le_bytes());
+                }
+            }
+            Self::Internal { keys, children } => {
+                bytes.push(INTERNAL);
+                bytes.extend_from_slice(&(keys.len() as u16).to_le_bytes());
+                bytes.extend_from_slice(&children[0].to_le_bytes());
+
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    encode_key(key, &mut bytes);
+                    bytes.extend_from_slice(&child.to_le_bytes());
+                }
+            }
+        }
+
+        bytes
+    }
+
+    fn decode(mut bytes: &[u8]) -> anyhow::Result<Self> {
+        let kind: u8 = take::<1>(&mut bytes)?[0];
+        let count: usize = u16::from_le_bytes(take(&mut bytes)?) as usize;
+
+        let mut keys: Vec<Vec<u8>> = vec![];
+
+        let node: Self = match kind {
+            LEAF => {
+                let mut values: Vec<RecordId> = vec![];
+
+                for _ in 0..count {
+                    keys.push(decode_key(&mut bytes)?);
+                    values.push(RecordId {
+                        page: PageId::from_le_bytes(take(&mut bytes)?),
+                        slot: u16::from_le_bytes(take(&mut bytes)?),
+                    });
+                }
+
+                Self::Leaf { keys, values }
+            }
+            INTERNAL => {
+                let mut children: Vec<PageId> = vec![PageId::from_le_bytes(take(&mut bytes)?)];
+
+                for _ in 0..count {
+                    keys.push(decode_key(&mut bytes)?);
+                    children.push(PageId::from_le_bytes(take(&mut bytes)?));
+                }
+
+                Self::Internal { keys, children }
+            }
+            kind => bail!("unknown node kind {kind}"),
+        };
+
+        if !bytes.is_empty() {
+            bail!("unexpected data after the node");
+        }
+
+        Ok(node)
+    }
+
+    fn split(&mut self) -> anyhow::Result<(Vec<u8>, Self)> {
+        let (separator, right): (Vec<u8>, Self) = match self {
+            Self::Leaf { keys, values } => {
+                let middle: usize = split_point(keys, LEAF_ENTRY_SIZE);
+
+                let right_keys: Vec<Vec<u8>> = keys.split_off(middle);
+
+                (
+                    right_keys[0].clone(),
+                    Self::Leaf {
+                        keys: right_keys,
+                        values: values.split_off(middle),
+                    },
+                )
+            }
+            Self::Internal { keys, children } => {
+                let middle: usize = split_point(keys, INTERNAL_ENTRY_SIZE);
+
+                let right_keys: Vec<Vec<u8>> = keys.split_off(middle + 1);
+                let separator: Vec<u8> = keys.pop().expect("internal nodes have keys");
+
+                (
+                    separator,
+                    Self::Internal {
+                        keys: right_keys,
+                        children: children.split_off(middle + 1),
+                    },
+                )
+            }
+        };
+
+        for half in [&*self, &right] {
+            let size: usize = half.encode().len();
+
+            if size > MAX_RECORD_SIZE {
+                bail!("index node is {size} bytes once split, at most {MAX_RECORD_SIZE}");
+            }
+        }
+
+        Ok((separator, right))
+    }
+}
+
+#[derive(Debug)]
+pub struct BTree {
+    pager: Pager,
+    /// Cleared, on disk first, before the first change after a flush.
+    clean: bool,
+}
+
+impl BTree {
+    /// Returns `false` along with the tree if it had to be created empty, and has to be
+    /// filled again by the caller.
+    pub fn open(path: &Path) -> anyhow::Result<(Self, bool)> {
+        match Self::open_existing(path) {
+            Ok(Some(tree)) => return Ok((tree, true)),
+            Ok(None) => {}
+            Err(err) if err.is::<FormatError>() => format::quarantine(path, &err),
+            Err(err) => warn!("Rebuilding \"{}\": {err}", path.display()),
+        }
+
+        Ok((Self::create(path)?, false))
+    }
+
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        remove_file(path)?;
+
+        let mut pager: Pager = Pager::open(path, FileKind::Index)?;
+
+        pager.allocate()?;
+        pager.allocate()?;
+
+        let mut tree: Self = Self {
+            pager,
+            clean: false,
+        };
+
+        tree.write_meta(false)?;
+        tree.write(
+            ROOT,
+            &Node::Leaf {
+                keys: vec![],
+                values: vec![],
+            },
+        )?;
+
+        Ok(tree)
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> anyhow::Result<Option<RecordId>> {
+        let mut page: PageId = ROOT;
+
+        loop {
+            match self.read(page)? {
+                Node::Leaf { keys, values } => {
+                    return Ok(keys
+                        .binary_search_by(|probe| probe.as_slice().cmp(key))
+                        .ok()
+                        .map(|index| values[index]));
+                }
+                Node::Internal { keys, children } => page = children[child_index(&keys, key)],
+            }
+        }
+    }
+
+    pub fn scan(&mut self, prefix: &[u8]) -> anyhow::Result<Vec<RecordId>> {
+        let mut records: Vec<RecordId> = vec![];
+
+        self.scan_from(ROOT, prefix, &mut records)?;
+
+        Ok(records)
+    }
+
+    /// At most `limit` of the keys starting with `prefix` that sort after `after`, in
+    /// order, with what they lead to.
+    pub fn scan_after(
+        &mut self,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(Vec<u8>, RecordId)>> {
+        let mut entries: Vec<(Vec<u8>, RecordId)> = vec![];
+
+        self.scan_after_from(ROOT, prefix, after, limit, &mut entries)?;
+
+        Ok(entries)
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: RecordId) -> anyhow::Result<()> {
+        if key.len() > MAX_KEY_SIZE {
+            bail!(
+                "index key is too large: {} bytes, at most {MAX_KEY_SIZE}",
+                key.len()
+            );
+        }
+
+        self.mark_dirty()?;
+
+        let Some((separator, right)) = self.insert_into(ROOT, key, value)? else {
+            return Ok(());
+        };
+
+        // The root keeps its page: its left half moves to a new page instead
+        let left: PageId = self.pager.allocate()?;
+        let root: Node = self.read(ROOT)?;
+
+        self.write(left, &root)?;
+        self.write(
+            ROOT,
+            &Node::Internal {
+                keys: vec![separator],
+                children: vec![left, right],
+            },
+        )
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> anyhow::Result<()> {
+        self.mark_dirty()?;
+
+        let mut page: PageId = ROOT;
+
+        loop {
+            match self.read(page)? {
+                Node::Leaf {
+                    mut keys,
+                    mut values,
+                } => {
+                    if let Ok(index) = keys.binary_search_by(|probe| probe.as_slice().cmp(key)) {
+                        keys.remove(index);
+                        values.remove(index);
+
+                        self.write(page, &Node::Leaf { keys, values })?;
+                    }
+
+                    return Ok(());
+                }
+                Node::Internal { keys, children } => page = children[child_index(&keys, key)],
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        if self.clean {
+            return Ok(());
+        }
+
+        self.pager.flush()?;
+
+        self.write_meta(true)?;
+        self.pager.flush()?;
+
+        self.clean = true;
+
+        Ok(())
+    }
+
+    /// Returns the split of the node if it outgrew its page.
+    fn insert_into(
+        &mut self,
+        page: PageId,
+        key: &[u8],
+        value: RecordId,
+    ) -> anyhow::Result<Option<(Vec<u8>, PageId)>> {
+        let mut node: Node = self.read(page)?;
+
+        match &mut node {
+            Node::Leaf { keys, values } => {
+                match keys.binary_search_by(|probe| probe.as_slice().cmp(key)) {
+                    Ok(index) => values[index] = value,
+                    Err(index) => {
+                        keys.insert(index, key.to_vec());
+                        values.insert(index, value);
+                    }
+                }
+            }
+            Node::Internal { keys, children } => {
+                let index: usize = child_index(keys, key);
+
+                if let Some((separator, right)) = self.insert_into(children[index], key, value)? {
+                    keys.insert(index, separator);
+                    children.insert(index + 1, right);
+                }
+            }
+        }
+
+        if node.encode().len() <= MAX_RECORD_SIZE {
+            self.write(page, &node)?;
+
+            return Ok(None);
+        }
+
+        let (separator, right_node): (Vec<u8>, Node) = node.split()?;
+        let right: PageId = self.pager.allocate()?;
+
+        self.write(page, &node)?;
+        self.write(right, &right_node)?;
+
+        Ok(Some((separator, right)))
+    }
+
+    fn scan_from(
+        &mut self,
+        page: PageId,
+        prefix: &[u8],
+        records: &mut Vec<RecordId>,
+    ) -> anyhow::Result<()> {
+        match self.read(page)? {
+            Node::Leaf { keys, values } => {
+                records.extend(
+                    keys.iter()
+                        .zip(values)
+                        .filter(|(key, _)| key.starts_with(prefix))
+                        .map(|(_, value)| value),
+                );
+            }
+            Node::Internal { keys, children } => {
+                // From the child holding the prefix itself to the last one whose lower
+                // bound sorts before the keys starting with it, or is one of them
+                let first: usize = child_index(&keys, prefix);
+                let last: usize = keys.partition_point(|separator| {
+                    separator.as_slice() <= prefix || separator.starts_with(prefix)
+                });
+
+                for child in &children[first..=last] {
+                    self.scan_from(*child, prefix, records)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn scan_after_from(
+        &mut self,
+        page: PageId,
+        prefix: &[u8],
+        after: Option<&[u8]>,
+        limit: usize,
+        entries: &mut Vec<(Vec<u8>, RecordId)>,
+    ) -> anyhow::Result<()> {
+        match self.read(page)? {
+            Node::Leaf { keys, values } => {
+                let left: usize = limit - entries.len();
+
+                entries.extend(
+                    keys.into_iter()
+                        .zip(values)
+                        .filter(|(key, _)| {
+                            key.starts_with(prefix)
+                                && after.is_none_or(|after| key.as_slice() > after)
+                        })
+                        .take(left),
+                );
+            }
+            Node::Internal { keys, children } => {
+                // Same children as a scan, but from the one holding `after`
+                let first: usize = child_index(&keys, after.unwrap_or(prefix).max(prefix));
+                let last: usize = keys.partition_point(|separator| {
+                    separator.as_slice() <= prefix || separator.starts_with(prefix)
+                });
+
+                for child in &children[first..=last] {
+                    if entries.len() >= limit {
+                        break;
+                    }
+
+                    self.scan_after_from(*child, prefix, after, limit, entries)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, page: PageId) -> anyhow::Result<Node> {
+        let record: &[u8] = page::get(self.pager.page(page)?, 0)
+            .ok_or_else(|| anyhow!("index page {page} is empty"))?;
+
+        Node::decode(record)
+    }
+
+    fn write(&mut self, page: PageId, node: &Node) -> anyhow::Result<()> {
+        write_record(&mut self.pager, page, &node.encode())
+    }
+
+    fn write_meta(&mut self, clean: bool) -> anyhow::Result<()> {
+        write_record(&mut self.pager, META, &[clean as u8])
+    }
+
+    /// A crash before the next flush then leads to a rebuild rather than to a half-written
+    /// tree.
+    fn mark_dirty(&mut self) -> anyhow::Result<()> {
+        if !self.clean {
+            return Ok(());
+        }
+
+        self.write_meta(false)?;
+        self.pager.flush()?;
+
+        self.clean = false;
+
+        Ok(())
+    }
+
+    fn open_existing(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let mut pager: Pager = Pager::open(path, FileKind::Index)?;
+
+        if pager.page_count() <= ROOT {
+            bail!("index has no root");
+        }
+
+        let clean: bool = page::get(pager.page(META)?, 0) == Some(&[1]);
+
+        if !clean {
+            bail!("index wasn't flushed completely");
+        }
+
+        Ok(Some(Self { pager, clean }))
+    }
+}
+
+/// Balances the halves by bytes, leaving at least one key in each.
+fn split_point(keys: &[Vec<u8>], entry_size: usize) -> usize {
+    if keys.len() < 2 {
+        return keys.len() / 2;
+    }
+
+    let total: usize = keys.iter().map(|key| key.len() + entry_size).sum();
+
+    let mut size: usize = 0;
+
+    for (index, key) in keys.iter().enumerate() {
+        size += key.len() + entry_size;
+
+        if size * 2 >= total {
+            return (index + 1).clamp(1, keys.len() - 1);
+        }
+    }
+
+    keys.len() / 2
+}
+
+fn child_index(keys: &[Vec<u8>], key: &[u8]) -> usize {
+    keys.partition_point(|separator| separator.as_slice() <= key)
+}
+
+fn write_record(pager: &mut Pager, page: PageId, record: &[u8]) -> anyhow::Result<()> {
+    let page_data: &mut page::Page = pager.page_mut(page)?;
+
+    let stored: bool = if page::get(page_data, 0).is_some() {
+        page::replace(page_data, 0, record)
+    } else {
+        page::insert(page_data, record) == Some(0)
+    };
+
+    if !stored {
+        bail!("index page {page} is full");
+    }
+
+    Ok(())
+}
+
+fn remove_file(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn encode_key(key: &[u8], bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(key);
+}
+
+fn decode_key(bytes: &mut &[u8]) -> anyhow::Result<Vec<u8>> {
+    let length: usize = u16::from_le_bytes(take(bytes)?) as usize;
+
+    if bytes.len() < length {
+        bail!("unexpected end of node");
+    }
+
+    let (key, rest) = bytes.split_at(length);
+
+    *bytes = rest;
+
+    Ok(key.to_vec())
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> anyhow::Result<[u8; N]> {
+    let Some((head, rest)) = bytes.split_first_chunk::<N>() else {
+        bail!("unexpected end of node");
+    };
+
+    *bytes = rest;
+
+    Ok(*head)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{split_point, BTree, Node, LEAF_ENTRY_SIZE, MAX_KEY_SIZE, ROOT};
+    use crate::database_manager::{document::RecordId, format, page::MAX_RECORD_SIZE};
+
+    fn key(i: u32) -> Vec<u8> {
+        // Every tenth key is as large as allowed, so nodes hold keys of uneven sizes
+        let mut key: Vec<u8> = format!("{i:06}").into_bytes();
+
+        if i.is_multiple_of(10) {
+            key.resize(MAX_KEY_SIZE, b'x');
+        }
+
+        key
+    }
+
+    fn record(i: u32) -> RecordId {
+        RecordId { page: i, slot: 0 }
+    }
+
+    #[test]
+    fn split_point_balances_bytes() {
+        let mut keys: Vec<Vec<u8>> = vec![vec![b'a'; MAX_KEY_SIZE]; 4];
+
+        keys.extend((0..200).map(|i| format!("{i:03}").into_bytes()));
+
+        let middle: usize = split_point(&keys, LEAF_ENTRY_SIZE);
+
+        // Half the keys would leave every large key in the left half
+        assert!(middle < 10, "{middle}");
+
+        let mut node: Node = Node::Leaf {
+            values: vec![record(0); keys.len()],
+            keys,
+        };
+
+        let (separator, right): (Vec<u8>, Node) = node.split().unwrap();
+
+        assert!(node.encode().len() <= MAX_RECORD_SIZE);
+        assert!(right.encode().len() <= MAX_RECORD_SIZE);
+
+        let Node::Leaf { keys, .. } = right else {
+            panic!("a leaf splits into leaves");
+        };
+
+        assert_eq!(separator, keys[0]);
+    }
+
+    #[test]
+    fn insert_splits_and_remove_keeps_order() {
+        let path: PathBuf = format::test_dir("insert_splits_and_remove_keeps_order").join("t.lidx");
+        let mut tree: BTree = BTree::create(&path).unwrap();
+
+        for i in (0..2000).rev() {
+            tree.insert(&key(i), record(i)).unwrap();
+        }
+
+        assert!(matches!(tree.read(ROOT).unwrap(), Node::Internal { .. }));
+
+        for i in (0..2000).step_by(3) {
+            tree.remove(&key(i)).unwrap();
+        }
+
+        tree.flush().unwrap();
+
+        drop(tree);
+
+        let (mut tree, complete): (BTree, bool) = BTree::open(&path).unwrap();
+
+        assert!(complete);
+
+        for i in 0..2000_u32 {
+            let expected: Option<RecordId> = (!i.is_multiple_of(3)).then(|| record(i));
+
+            assert_eq!(tree.get(&key(i)).unwrap(), expected, "key {i}");
+        }
+
+        let remaining: Vec<RecordId> = (0..2000_u32)
+            .filter(|i| !i.is_multiple_of(3))
+            .map(record)
+            .collect();
+
+        assert_eq!(tree.scan(b"").unwrap(), remaining);
+        assert_eq!(
+            tree.scan(b"0012").unwrap(),
+            (1200..1300_u32)
+                .filter(|i| !i.is_multiple_of(3))
+                .map(record)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn unflushed_tree_is_rebuilt() {
+        let path: PathBuf = format::test_dir("unflushed_tree_is_rebuilt").join("t.lidx");
+        let mut tree: BTree = BTree::create(&path).unwrap();
+
+        tree.insert(b"a", record(1)).unwrap();
+        tree.flush().unwrap();
+        tree.insert(b"b", record(2)).unwrap();
+
+        drop(tree);
+
+        let (mut tree, complete): (BTree, bool) = BTree::open(&path).unwrap();
+
+        assert!(!complete);
+        assert_eq!(tree.get(b"a").unwrap(), None);
+    }
+}
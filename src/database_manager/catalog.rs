@@ -0,0 +1,344 @@
+//! The catalog of a database: `catalog.lcat` in its directory records when it was created
+//! and its collections, with their creation time, options, schema version and indexes.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Local};
+use tracing::{info, warn};
+
+use super::{
+    format::{self, FileKind},
+    index::IndexDefinition,
+    value::Value,
+};
+
+/// The catalog is `catalog.lcat` in the database directory.
+pub const CATALOG_NAME: &str = "catalog";
+
+/// Version of the catalog contents.
+pub const CATALOG_VERSION: i64 = 1;
+
+/// Version of the files of a collection. Recorded per collection, so a later version can
+/// migrate them one at a time.
+pub const SCHEMA_VERSION: i64 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollectionEntry {
+    pub created_at: String,
+    /// Settings given when the collection was created. None exist yet.
+    pub options: BTreeMap<String, Value>,
+    pub schema_version: i64,
+    pub indexes: Vec<IndexDefinition>,
+}
+
+impl CollectionEntry {
+    fn new(created_at: String) -> Self {
+        Self {
+            created_at,
+            options: BTreeMap::new(),
+            schema_version: SCHEMA_VERSION,
+            indexes: vec![],
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+
+        fields.insert("created_at".into(), Value::String(self.created_at.clone()));
+        fields.insert("options".into(), Value::Object(self.options.clone()));
+        fields.insert("schema_version".into(), Value::Int(self.schema_version));
+        fields.insert(
+            "indexes".into(),
+            Value::Array(self.indexes.iter().map(IndexDefinition::to_value).collect()),
+        );
+
+        Value::Object(fields)
+    }
+
+    fn from_value(value: Value) -> anyhow::Result<Self> {
+        let Value::Object(mut fields) = value else {
+            bail!("collection entry is not an object");
+        };
+
+        let Some(Value::String(created_at)) = fields.remove("created_at") else {
+            bail!("collection entry has no creation time");
+        };
+
+        let Some(Value::Object(options)) = fields.remove("options") else {
+            bail!("collection entry has no options");
+        };
+
+        let Some(Value::Int(schema_version)) = fields.remove("schema_version") else {
+            bail!("collection entry has no schema version");
+        };
+
+        let Some(Value::Array(indexes)) = fields.remove("indexes") else {
+            bail!("collection entry has no indexes");
+        };
+
+        Ok(Self {
+            created_at,
+            options,
+            schema_version,
+            indexes: indexes
+                .into_iter()
+                .map(IndexDefinition::from_value)
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Catalog {
+    /// The database directory.
+    path: PathBuf,
+    pub created_at: String,
+    pub collections: BTreeMap<String, CollectionEntry>,
+}
+
+impl Catalog {
+    /// Writes the catalog of a new, empty database at `path`.
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let catalog: Self = Self {
+            path: path.to_path_buf(),
+            created_at: now(),
+            collections: BTreeMap::new(),
+        };
+
+        catalog.save()?;
+
+        Ok(catalog)
+    }
+
+    /// Reads the catalog of the database at `path`. Databases written before there was a
+    /// catalog, or whose catalog can't be read, get one listing their directories.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let catalog_path: PathBuf = catalog_path(path);
+
+        let bytes: Option<Vec<u8>> = match fs::read(&catalog_path) {
+            Ok(bytes) => Some(bytes),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some(bytes) = bytes {
+            match Self::decode(path, &bytes) {
+                Ok(catalog) => return Ok(catalog),
+                // A catalog written by a newer version is left alone
+                Err(err) if err.is::<UnsupportedVersion>() => return Err(err),
+                Err(err) => format::quarantine(&catalog_path, &err),
+            }
+        }
+
+        info!("Building the catalog of \"{}\"", path.display());
+
+        let catalog: Self = Self::discover(path)?;
+
+        catalog.save()?;
+
+        Ok(catalog)
+    }
+
+    pub fn collection(&self, name: &str) -> Option<&CollectionEntry> {
+        self.collections.get(name)
+    }
+
+    /// Records a new collection. Does nothing if it is already recorded.
+    pub fn add_collection(&mut self, name: &str) -> anyhow::Result<()> {
+        if self.collections.contains_key(name) {
+            return Ok(());
+        }
+
+        self.collections
+            .insert(name.to_string(), CollectionEntry::new(now()));
+
+        self.save()
+    }
+
+    pub fn remove_collection(&mut self, name: &str) -> anyhow::Result<()> {
+        if self.collections.remove(name).is_some() {
+            self.save()?;
+        }
+
+        Ok(())
+    }
+
+    /// Records a new index, replacing any index with the same name.
+    pub fn add_index(
+        &mut self,
+        collection: &str,
+        definition: IndexDefinition,
+    ) -> anyhow::Result<()> {
+        let indexes: &mut Vec<IndexDefinition> = &mut self.entry_mut(collection)?.indexes;
+
+        indexes.retain(|index| index.name != definition.name);
+        indexes.push(definition);
+
+        self.save()
+    }
+
+    pub fn remove_index(&mut self, collection: &str, name: &str) -> anyhow::Result<()> {
+        self.entry_mut(collection)?
+            .indexes
+            .retain(|index| index.name != name);
+
+        self.save()
+    }
+
+    /// Replaces the catalog on disk. The new file is written aside and renamed over the
+    /// old one, so a crash leaves either of them.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let catalog_path: PathBuf = catalog_path(&self.path);
+        let temporary_path: PathBuf = catalog_path.with_extension("tmp");
+
+        let mut payload: Vec<u8> = vec![];
+
+        self.to_value().encode(&mut payload);
+
+        let mut bytes: Vec<u8> = vec![];
+
+        format::write_header(FileKind::Catalog, &mut bytes);
+        format::write_record(&payload, &mut bytes);
+
+        let mut file: fs::File = fs::File::create(&temporary_path)?;
+
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+
+        fs::rename(&temporary_path, &catalog_path)?;
+
+        format::sync_dir(&self.path)
+    }
+
+    fn entry_mut(&mut self, collection: &str) -> anyhow::Result<&mut CollectionEntry> {
+        self.collections
+            .get_mut(collection)
+            .ok_or_else(|| anyhow!("no such collection \"{collection}\""))
+    }
+
+    fn to_value(&self) -> Value {
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+
+        fields.insert("version".into(), Value::Int(CATALOG_VERSION));
+        fields.insert("created_at".into(), Value::String(self.created_at.clone()));
+        fields.insert(
+            "collections".into(),
+            Value::Object(
+                self.collections
+                    .iter()
+                    .map(|(name, entry)| (name.clone(), entry.to_value()))
+                    .collect(),
+            ),
+        );
+
+        Value::Object(fields)
+    }
+
+    fn decode(path: &Path, bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut rest: &[u8] = format::read_header(bytes, FileKind::Catalog)?;
+
+        let Some(mut record) = format::read_record(&mut rest)? else {
+            bail!("catalog is empty");
+        };
+
+        let Value::Object(mut fields) = Value::decode(&mut record)? else {
+            bail!("catalog is not an object");
+        };
+
+        let Some(Value::Int(version)) = fields.remove("version") else {
+            bail!("catalog has no version");
+        };
+
+        if version > CATALOG_VERSION {
+            return Err(UnsupportedVersion(version).into());
+        }
+
+        let Some(Value::String(created_at)) = fields.remove("created_at") else {
+            bail!("catalog has no creation time");
+        };
+
+        let Some(Value::Object(collections)) = fields.remove("collections") else {
+            bail!("catalog has no collections");
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            created_at,
+            collections: collections
+                .into_iter()
+                .map(|(name, entry)| Ok((name, CollectionEntry::from_value(entry)?)))
+                .collect::<anyhow::Result<_>>()?,
+        })
+    }
+
+    /// A catalog listing the collection directories at `path`, dated from the directories
+    /// themselves. Their indexes aren't known anymore.
+    fn discover(path: &Path) -> anyhow::Result<Self> {
+        let mut collections: BTreeMap<String, CollectionEntry> = BTreeMap::new();
+
+        for entry in fs::read_dir(path)? {
+            let entry: fs::DirEntry = entry?;
+
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let Ok(name) = entry.file_name().into_string() else {
+                warn!("Ignoring \"{}\": invalid UTF-8", entry.path().display());
+                continue;
+            };
+
+            collections.insert(name, CollectionEntry::new(created_at(&entry.path())));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            created_at: created_at(path),
+            collections,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct UnsupportedVersion(i64);
+
+impl std::fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "catalog version {} is newer than this LilDB supports ({CATALOG_VERSION})",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+fn catalog_path(path: &Path) -> PathBuf {
+    path.join(CATALOG_NAME)
+        .with_extension(FileKind::Catalog.extension())
+}
+
+fn now() -> String {
+    format_time(Local::now())
+}
+
+/// When the file at `path` was created, or last modified where that isn't known.
+fn created_at(path: &Path) -> String {
+    let time: Option<SystemTime> = fs::metadata(path)
+        .and_then(|metadata| metadata.created().or_else(|_| metadata.modified()))
+        .ok();
+
+    time.map_or_else(now, |time| format_time(DateTime::<Local>::from(time)))
+}
+
+fn format_time(time: DateTime<Local>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
@@ -1,22 +1,1147 @@
-use super::document::Document;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
 
-#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+use anyhow::{anyhow, bail};
+use tracing::{info, warn};
+
+use super::{
+    btree::BTree,
+    document::{self, Document, RecordId, ID_FIELD},
+    error::DbError,
+    filter::Filter,
+    format::{self, FileKind, FormatError},
+    index::{self, Index, IndexDefinition},
+    mvcc::History,
+    page::{self, PAGE_SIZE},
+    pager::{self, PageId, Pager},
+    value::Value,
+};
+
+pub const DATA_NAME: &str = "data";
+pub const ID_INDEX_NAME: &str = ID_FIELD;
+/// How many keys a read in parts goes through at a time.
+pub const PART_SIZE: usize = 256;
+
+#[derive(Debug)]
 pub struct Collection {
     pub name: String,
     pub path: String,
-    pub created_at: String,
-    documents: Vec<Document>,
+    /// Behind a mutex since readers share the buffer pool.
+    pager: Mutex<Pager>,
+    /// Only gathered by the first write.
+    free_space: Option<Vec<usize>>,
+    id_index: Mutex<BTree>,
+    indexes: Vec<Index>,
+    /// 0 if the collection already existed when the database was opened.
+    pub created: u64,
+    pub history: History,
+}
+
+/// How far a read in parts of the documents matching a filter got. It goes through the
+/// keys starting with `prefix` of a single tree, the index named `index` or else the `_id`
+/// index, so the writes between parts don't make it skip or repeat documents.
+#[derive(Clone, Debug, Default)]
+pub struct Position {
+    index: Option<String>,
+    prefix: Vec<u8>,
+    after: Option<Vec<u8>>,
+    pub done: bool,
 }
 
 impl Collection {
-    pub fn new(name: String, path: String, documents: Vec<Document>) -> Self {
-        Self {
+    /// Only the indexes that are missing or weren't flushed completely require reading the
+    /// documents, to rebuild them.
+    pub fn open(
+        name: String,
+        path: String,
+        definitions: Vec<IndexDefinition>,
+    ) -> anyhow::Result<Self> {
+        let data_path: PathBuf = Path::new(&path)
+            .join(DATA_NAME)
+            .with_extension(FileKind::Data.extension());
+
+        // Left behind by a vacuum that didn't finish
+        remove_paged(&data_path.with_extension("tmp"))?;
+
+        let id_index_path: PathBuf = Path::new(&path)
+            .join(ID_INDEX_NAME)
+            .with_extension(FileKind::Index.extension());
+
+        let (pager, (id_index, complete)): (Pager, (BTree, bool)) =
+            match Pager::open(&data_path, FileKind::Data) {
+                Ok(pager) => (pager, BTree::open(&id_index_path)?),
+                Err(err) if err.is::<FormatError>() => {
+                    format::quarantine(&data_path, &err);
+
+                    // The indexes point into the quarantined file
+                    (
+                        Pager::open(&data_path, FileKind::Data)?,
+                        (BTree::create(&id_index_path)?, false),
+                    )
+                }
+                Err(err) => return Err(err),
+            };
+
+        let mut indexes: Vec<Index> = vec![];
+        let mut incomplete: Vec<usize> = vec![];
+
+        for definition in definitions {
+            let (tree, complete): (BTree, bool) =
+                BTree::open(&index::tree_path(Path::new(&path), &definition.name))?;
+
+            if !complete {
+                incomplete.push(indexes.len());
+            }
+
+            indexes.push(Index::new(definition, tree));
+        }
+
+        let mut collection: Self = Self {
             name,
             path,
-            created_at: chrono::prelude::Local::now()
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string(),
-            documents,
+            pager: Mutex::new(pager),
+            free_space: None,
+            id_index: Mutex::new(id_index),
+            indexes,
+            created: 0,
+            history: History::default(),
+        };
+
+        // The _id index is only incomplete after a crash or a quarantine, which may have
+        // left anything behind in the data file: every other index is rebuilt along with it
+        if !complete {
+            info!(
+                "Rebuilding the _id index of collection \"{}\"",
+                collection.name
+            );
+
+            collection.rebuild_id_index()?;
+
+            incomplete = (0..collection.indexes.len()).collect();
+        }
+
+        for position in incomplete {
+            info!(
+                "Rebuilding index \"{}\" of collection \"{}\"",
+                collection.indexes[position].definition.name, collection.name
+            );
+
+            collection.rebuild_index(position)?;
+        }
+
+        Ok(collection)
+    }
+
+    pub fn documents(&self) -> anyhow::Result<Vec<Document>> {
+        let mut documents: Vec<Document> = vec![];
+
+        self.scan(|document| {
+            documents.push(document);
+
+            Ok(())
+        })?;
+
+        Ok(documents)
+    }
+
+    pub fn indexes(&self) -> impl Iterator<Item = &IndexDefinition> {
+        self.indexes.iter().map(|index| &index.definition)
+    }
+
+    pub fn get(&self, id: &str) -> anyhow::Result<Option<Document>> {
+        let location: Option<RecordId> = self.id_index().get(id.as_bytes())?;
+
+        location.map(|location| self.read(location)).transpose()
+    }
+
+    pub fn matching(&self, filter: Option<&Filter>) -> anyhow::Result<Vec<Document>> {
+        let Some(filter) = filter else {
+            return self.documents();
+        };
+
+        let candidates: Vec<Document> = match filter.equality(&[ID_FIELD.into()]) {
+            Some(Value::String(id)) => self.get(id)?.into_iter().collect(),
+            // Ids are always strings
+            Some(_) => vec![],
+            None => match self.lookup(filter)? {
+                Some(locations) => locations
+                    .into_iter()
+                    .map(|location| self.read(location))
+                    .collect::<anyhow::Result<_>>()?,
+                None => {
+                    let mut documents: Vec<Document> = vec![];
+
+                    self.scan(|document| {
+                        if filter.matches(&document.body) {
+                            documents.push(document);
+                        }
+
+                        Ok(())
+                    })?;
+
+                    return Ok(documents);
+                }
+            },
+        };
+
+        Ok(candidates
+            .into_iter()
+            .filter(|document| filter.matches(&document.body))
+            .collect())
+    }
+
+    pub fn matching_at(
+        &self,
+        filter: Option<&Filter>,
+        snapshot: u64,
+    ) -> anyhow::Result<Vec<Value>> {
+        let previous: HashMap<&str, Option<&Value>> = self.history.as_of(snapshot);
+
+        let mut bodies: Vec<Value> = vec![];
+
+        for document in self.matching(filter)? {
+            if !previous.contains_key(document.id.as_str()) {
+                bodies.push(document.body);
+            }
+        }
+
+        for body in previous.into_values().flatten() {
+            if filter.is_none_or(|filter| filter.matches(body)) {
+                bodies.push(body.clone());
+            }
+        }
+
+        Ok(bodies)
+    }
+
+    /// Where a read in parts of the documents matching `filter` starts.
+    pub fn position(&self, filter: Option<&Filter>) -> Position {
+        let Some(filter) = filter else {
+            return Position::default();
+        };
+
+        match filter.equality(&[ID_FIELD.into()]) {
+            Some(Value::String(id)) => Position {
+                prefix: id.as_bytes().to_vec(),
+                ..Position::default()
+            },
+            // Ids are always strings
+            Some(_) => Position {
+                done: true,
+                ..Position::default()
+            },
+            None => match self.index_for(filter) {
+                Some((index, prefix)) => Position {
+                    index: Some(index.definition.name.clone()),
+                    prefix,
+                    ..Position::default()
+                },
+                None => Position::default(),
+            },
+        }
+    }
+
+    /// The next part of a read at `snapshot`: the documents matching `filter` among the
+    /// next `limit` keys of `position`, along with their versions from before `snapshot`.
+    pub fn matching_part_at(
+        &self,
+        filter: Option<&Filter>,
+        snapshot: u64,
+        position: &mut Position,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Value>> {
+        if position.done {
+            return Ok(vec![]);
+        }
+
+        let index: Option<&Index> = match &position.index {
+            Some(name) => Some(
+                self.indexes
+                    .iter()
+                    .find(|index| index.definition.name == *name)
+                    .ok_or_else(|| {
+                        DbError::FailedPrecondition(format!(
+                            "index \"{name}\" was dropped during the read"
+                        ))
+                    })?,
+            ),
+            None => None,
+        };
+
+        let entries: Vec<(Vec<u8>, RecordId)> = match index {
+            Some(index) => {
+                index
+                    .tree()
+                    .scan_after(&position.prefix, position.after.as_deref(), limit)?
+            }
+            None => {
+                self.id_index()
+                    .scan_after(&position.prefix, position.after.as_deref(), limit)?
+            }
+        };
+
+        let previous: HashMap<&str, Option<&Value>> = self.history.as_of(snapshot);
+
+        let mut bodies: Vec<Value> = vec![];
+
+        for (_, location) in &entries {
+            let document: Document = self.read(*location)?;
+
+            if !previous.contains_key(document.id.as_str())
+                && filter.is_none_or(|filter| filter.matches(&document.body))
+            {
+                bodies.push(document.body);
+            }
+        }
+
+        // The last part also takes every version sorting after it
+        let last: Option<&[u8]> = match entries.len() < limit {
+            true => None,
+            false => entries.last().map(|(key, _)| key.as_slice()),
+        };
+
+        for (id, body) in previous {
+            let Some(body) = body else {
+                continue;
+            };
+
+            // Documents whose key was too large were left out of the index
+            let key: Vec<u8> = match index {
+                Some(index) => match index.definition.key(body, id) {
+                    Ok(key) => key,
+                    Err(_) => continue,
+                },
+                None => id.as_bytes().to_vec(),
+            };
+
+            if key.starts_with(&position.prefix)
+                && position.after.as_ref().is_none_or(|after| key > *after)
+                && last.is_none_or(|last| key.as_slice() <= last)
+                && filter.is_none_or(|filter| filter.matches(body))
+            {
+                bodies.push(body.clone());
+            }
+        }
+
+        position.after = last.map(<[u8]>::to_vec);
+        position.done = last.is_none();
+
+        Ok(bodies)
+    }
+
+    pub fn commit(
+        &mut self,
+        version: u64,
+        ids: &[String],
+        bodies: Vec<Value>,
+    ) -> anyhow::Result<()> {
+        for id in ids {
+            if let Some(previous) = self.get(id)? {
+                self.history
+                    .record(version, id.clone(), Some(previous.body));
+            }
+        }
+
+        self.delete(ids)?;
+
+        for body in bodies {
+            let id: String = document::id_of(&body)?.to_string();
+
+            match self.get(&id)? {
+                Some(previous) => {
+                    self.history.record(version, id, Some(previous.body));
+
+                    self.replace(previous.location, body)?;
+                }
+                None => {
+                    self.history.record(version, id.clone(), None);
+
+                    self.insert(id, body)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn insert(&mut self, id: String, body: Value) -> anyhow::Result<()> {
+        let record: Vec<u8> = document::encode(&body)?;
+        let location: RecordId = self.place(&record)?;
+
+        let document: Document = Document::new(id, location, body);
+
+        self.id_index().insert(document.id.as_bytes(), location)?;
+        self.index_document(&document)
+    }
+
+    pub fn replace(&mut self, location: RecordId, body: Value) -> anyhow::Result<()> {
+        let record: Vec<u8> = document::encode(&body)?;
+        let previous: Document = self.read(location)?;
+
+        self.unindex_document(&previous)?;
+
+        let page: &mut page::Page = self.pager_mut().page_mut(location.page)?;
+
+        let new_location: RecordId = if page::replace(page, location.slot, &record) {
+            let free_space: usize = page::free_space(page);
+
+            self.set_free_space(location.page, free_space)?;
+
+            location
+        } else {
+            self.remove_record(location)?;
+
+            let new_location: RecordId = self.place(&record)?;
+
+            self.id_index()
+                .insert(previous.id.as_bytes(), new_location)?;
+
+            new_location
+        };
+
+        self.index_document(&Document::new(previous.id, new_location, body))
+    }
+
+    pub fn upsert(&mut self, body: Value) -> anyhow::Result<()> {
+        let id: String = document::id_of(&body)?.to_string();
+        let location: Option<RecordId> = self.id_index().get(id.as_bytes())?;
+
+        match location {
+            Some(location) => self.replace(location, body),
+            None => self.insert(id, body),
+        }
+    }
+
+    pub fn delete(&mut self, ids: &[String]) -> anyhow::Result<()> {
+        for id in ids {
+            let Some(location) = self.id_index().get(id.as_bytes())? else {
+                continue;
+            };
+
+            let document: Document = self.read(location)?;
+
+            self.remove_record(location)?;
+            self.id_index().remove(id.as_bytes())?;
+            self.unindex_document(&document)?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs before the writes are logged, so nothing that can't be applied gets logged.
+    pub fn check_writes(
+        &self,
+        writes: &[(Option<RecordId>, &Value)],
+        removed: &[RecordId],
+    ) -> anyhow::Result<()> {
+        let replaced: HashSet<RecordId> = writes
+            .iter()
+            .filter_map(|(location, _)| *location)
+            .chain(removed.iter().copied())
+            .collect();
+
+        for index in &self.indexes {
+            let mut keys: HashSet<Vec<u8>> = HashSet::new();
+
+            for (_, body) in writes {
+                let key: Vec<u8> = index.definition.key(body, document::id_of(body)?)?;
+
+                if !index.definition.unique {
+                    continue;
+                }
+
+                // The document holding the key may be one of those being replaced
+                let taken: bool = keys.contains(&key)
+                    || index
+                        .tree()
+                        .get(&key)?
+                        .is_some_and(|owner| !replaced.contains(&owner));
+
+                if taken {
+                    bail!(DbError::Conflict(format!(
+                        "duplicate {} in unique index \"{}\"",
+                        index.definition.describe(body),
+                        index.definition.name
+                    )));
+                }
+
+                keys.insert(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_index(&self, definition: &IndexDefinition) -> anyhow::Result<()> {
+        if definition.name == ID_INDEX_NAME || self.index(&definition.name).is_some() {
+            bail!(DbError::AlreadyExists(format!(
+                "index \"{}\" already exists on \"{}\"",
+                definition.name, self.name
+            )));
+        }
+
+        let mut keys: HashSet<Vec<u8>> = HashSet::new();
+
+        self.scan(|document| {
+            let key: Vec<u8> = definition.key(&document.body, &document.id)?;
+
+            if !keys.insert(key) {
+                bail!(DbError::Conflict(format!(
+                    "duplicate {} in unique index \"{}\"",
+                    definition.describe(&document.body),
+                    definition.name
+                )));
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn index(&self, name: &str) -> Option<&IndexDefinition> {
+        self.indexes().find(|definition| definition.name == name)
+    }
+
+    /// Recording the index in the catalog is up to the caller.
+    pub fn create_index(&mut self, definition: IndexDefinition) -> anyhow::Result<()> {
+        let tree: BTree =
+            BTree::create(&index::tree_path(Path::new(&self.path), &definition.name))?;
+        let index: Index = Index::new(definition, tree);
+
+        self.fill(&index)?;
+        self.indexes.push(index);
+
+        Ok(())
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> anyhow::Result<()> {
+        self.indexes.retain(|index| index.definition.name != name);
+
+        remove_paged(&index::tree_path(Path::new(&self.path), name))
+    }
+
+    /// The indexes go last, so they are only marked complete once the data they point to
+    /// is on disk.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.pager().flush()?;
+        self.id_index().flush()?;
+
+        for index in &self.indexes {
+            index.tree().flush()?;
+        }
+
+        format::sync_dir(Path::new(&self.path))
+    }
+
+    pub fn unused_space(&mut self) -> anyhow::Result<(u64, u64)> {
+        let unused: usize = self.free_space()?.iter().sum();
+        let size: u64 = self.pager().page_count() as u64 * PAGE_SIZE as u64;
+
+        Ok((unused as u64, size))
+    }
+
+    /// The new data file is written aside and renamed over the old one once the indexes
+    /// are deleted, so a crash leaves either file with indexes that are rebuilt on open.
+    /// Log records name documents by `_id`, so they can still be replayed on the new file.
+    /// Fails without touching the old files if any document can't be read, rather than
+    /// dropping it from the new file.
+    pub fn vacuum(&mut self) -> anyhow::Result<u64> {
+        // Pages still in the buffer pool count too
+        self.flush()?;
+
+        let before: u64 = files_size(Path::new(&self.path))?;
+
+        let data_path: PathBuf = Path::new(&self.path)
+            .join(DATA_NAME)
+            .with_extension(FileKind::Data.extension());
+        let temporary_path: PathBuf = data_path.with_extension("tmp");
+
+        remove_paged(&temporary_path)?;
+
+        let copied: anyhow::Result<()> = self.copy_documents(&temporary_path);
+
+        if copied.is_err() {
+            remove_paged(&temporary_path)?;
+        }
+
+        copied?;
+
+        remove_file(
+            &Path::new(&self.path)
+                .join(ID_INDEX_NAME)
+                .with_extension(FileKind::Index.extension()),
+        )?;
+
+        for index in &self.indexes {
+            remove_file(&index::tree_path(
+                Path::new(&self.path),
+                &index.definition.name,
+            ))?;
+        }
+
+        format::sync_dir(Path::new(&self.path))?;
+
+        fs::rename(&temporary_path, &data_path)?;
+        remove_file(&pager::double_write_path(&temporary_path))?;
+
+        format::sync_dir(Path::new(&self.path))?;
+
+        let mut vacuumed: Self = Self::open(
+            self.name.clone(),
+            self.path.clone(),
+            self.indexes().cloned().collect(),
+        )?;
+
+        vacuumed.created = self.created;
+        vacuumed.history = std::mem::take(&mut self.history);
+
+        *self = vacuumed;
+
+        // The rebuilt indexes are only marked complete once flushed
+        self.flush()?;
+
+        let after: u64 = files_size(Path::new(&self.path))?;
+
+        Ok(before.saturating_sub(after))
+    }
+
+    fn copy_documents(&self, path: &Path) -> anyhow::Result<()> {
+        let mut pager: Pager = Pager::open(path, FileKind::Data)?;
+        let mut last_page: Option<PageId> = None;
+
+        self.scan_strict(|document| {
+            let record: Vec<u8> = document::encode(&document.body)?;
+
+            let placed: bool = match last_page {
+                Some(id) => page::insert(pager.page_mut(id)?, &record).is_some(),
+                None => false,
+            };
+
+            if !placed {
+                let id: PageId = pager.allocate()?;
+
+                page::insert(pager.page_mut(id)?, &record)
+                    .ok_or_else(|| anyhow!("record doesn't fit in an empty page"))?;
+
+                last_page = Some(id);
+            }
+
+            Ok(())
+        })?;
+
+        pager.flush()
+    }
+
+    /// Pages and records that can't be read are skipped.
+    fn scan(&self, visit: impl FnMut(Document) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.scan_pages(true, visit)
+    }
+
+    /// Like `scan`, but fails on the first page or record that can't be read.
+    fn scan_strict(&self, visit: impl FnMut(Document) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        self.scan_pages(false, visit)
+    }
+
+    fn scan_pages(
+        &self,
+        skip_damaged: bool,
+        mut visit: impl FnMut(Document) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let page_count: PageId = self.pager().page_count();
+
+        for id in 1..page_count {
+            // Decoded before visiting, so the pool isn't locked while indexes are
+            let documents: Vec<Document> = {
+                let mut pager = self.pager();
+
+                let page: &page::Page = match pager.page(id) {
+                    Ok(page) => page,
+                    Err(err) if skip_damaged => {
+                        warn!("Skipping page {id} of collection \"{}\": {err}", self.name);
+
+                        continue;
+                    }
+                    Err(err) => bail!(damaged(&self.name, &format!("page {id}"), &err)),
+                };
+
+                let mut documents: Vec<Document> = vec![];
+
+                for (slot, record) in page::records(page) {
+                    match Document::decode(RecordId { page: id, slot }, record) {
+                        Ok(document) => documents.push(document),
+                        Err(err) if skip_damaged => warn!(
+                            "Skipping record {id}:{slot} of collection \"{}\": {err}",
+                            self.name
+                        ),
+                        Err(err) => {
+                            bail!(damaged(&self.name, &format!("record {id}:{slot}"), &err))
+                        }
+                    }
+                }
+
+                documents
+            };
+
+            for document in documents {
+                visit(document)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, location: RecordId) -> anyhow::Result<Document> {
+        let mut pager = self.pager();
+
+        let record: &[u8] = page::get(pager.page(location.page)?, location.slot)
+            .ok_or_else(|| anyhow!("no document at {}:{}", location.page, location.slot))?;
+
+        Document::decode(location, record)
+    }
+
+    fn place(&mut self, record: &[u8]) -> anyhow::Result<RecordId> {
+        let page_id: PageId = match self
+            .free_space()?
+            .iter()
+            .position(|free_space| *free_space >= record.len())
+        {
+            Some(index) => index as PageId + 1,
+            None => {
+                let id: PageId = self.pager_mut().allocate()?;
+
+                self.free_space()?.push(0);
+
+                id
+            }
+        };
+
+        let page: &mut page::Page = self.pager_mut().page_mut(page_id)?;
+
+        let slot: u16 =
+            page::insert(page, record).ok_or_else(|| anyhow!("page {page_id} is full"))?;
+        let free_space: usize = page::free_space(page);
+
+        self.set_free_space(page_id, free_space)?;
+
+        Ok(RecordId {
+            page: page_id,
+            slot,
+        })
+    }
+
+    fn remove_record(&mut self, location: RecordId) -> anyhow::Result<()> {
+        let page: &mut page::Page = self.pager_mut().page_mut(location.page)?;
+
+        page::delete(page, location.slot);
+
+        let free_space: usize = page::free_space(page);
+
+        self.set_free_space(location.page, free_space)
+    }
+
+    /// Reads the free space of every page the first time it is needed. Pages that can't
+    /// be read are left alone, with no free space so nothing is added to them.
+    fn free_space(&mut self) -> anyhow::Result<&mut Vec<usize>> {
+        if self.free_space.is_none() {
+            let pager: &mut Pager = self.pager_mut();
+
+            let free_space: Vec<usize> = (1..pager.page_count())
+                .map(|id| pager.page(id).map_or(0, page::free_space))
+                .collect();
+
+            self.free_space = Some(free_space);
+        }
+
+        Ok(self.free_space.get_or_insert_default())
+    }
+
+    fn set_free_space(&mut self, page: PageId, free_space: usize) -> anyhow::Result<()> {
+        self.free_space()?[page as usize - 1] = free_space;
+
+        Ok(())
+    }
+
+    /// Fills the empty `_id` index from the data file. A crash while a document moved
+    /// between pages can leave both copies on disk: either is fine to drop, since the log
+    /// holds its latest version.
+    fn rebuild_id_index(&mut self) -> anyhow::Result<()> {
+        let mut ids: HashSet<String> = HashSet::new();
+        let mut duplicates: Vec<RecordId> = vec![];
+
+        self.scan(|document| {
+            if ids.insert(document.id.clone()) {
+                self.id_index()
+                    .insert(document.id.as_bytes(), document.location)?;
+            } else {
+                duplicates.push(document.location);
+            }
+
+            Ok(())
+        })?;
+
+        for location in duplicates {
+            warn!(
+                "Dropping duplicate record {}:{} of collection \"{}\"",
+                location.page, location.slot, self.name
+            );
+
+            self.remove_record(location)?;
+        }
+
+        Ok(())
+    }
+
+    fn rebuild_index(&mut self, position: usize) -> anyhow::Result<()> {
+        let definition: IndexDefinition = self.indexes[position].definition.clone();
+        let tree: BTree =
+            BTree::create(&index::tree_path(Path::new(&self.path), &definition.name))?;
+
+        self.indexes[position] = Index::new(definition, tree);
+
+        self.fill(&self.indexes[position])
+    }
+
+    fn lookup(&self, filter: &Filter) -> anyhow::Result<Option<Vec<RecordId>>> {
+        let Some((index, prefix)) = self.index_for(filter) else {
+            return Ok(None);
+        };
+
+        let mut locations: Vec<RecordId> = index.tree().scan(&prefix)?;
+
+        // Same order as a scan
+        locations.sort_unstable();
+
+        Ok(Some(locations))
+    }
+
+    /// The index whose leading fields the filter pins down the most, with the prefix of
+    /// the keys it matches.
+    fn index_for(&self, filter: &Filter) -> Option<(&Index, Vec<u8>)> {
+        let (index, values): (&Index, Vec<&Value>) = self
+            .indexes
+            .iter()
+            .map(|index| {
+                let values: Vec<&Value> = index
+                    .definition
+                    .fields
+                    .iter()
+                    .map_while(|path| filter.equality(path))
+                    .collect();
+
+                (index, values)
+            })
+            .filter(|(_, values)| !values.is_empty())
+            .max_by_key(|(_, values)| values.len())?;
+
+        Some((index, index.definition.prefix(values.into_iter().map(Some))))
+    }
+
+    fn fill(&self, index: &Index) -> anyhow::Result<()> {
+        self.scan(|document| {
+            match index.definition.key(&document.body, &document.id) {
+                Ok(key) => index.tree().insert(&key, document.location)?,
+                Err(err) => warn!(
+                    "Leaving document \"{}\" out of index \"{}\": {err}",
+                    document.id, index.definition.name
+                ),
+            }
+
+            Ok(())
+        })
+    }
+
+    fn index_document(&self, document: &Document) -> anyhow::Result<()> {
+        for index in &self.indexes {
+            index.tree().insert(
+                &index.definition.key(&document.body, &document.id)?,
+                document.location,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn unindex_document(&self, document: &Document) -> anyhow::Result<()> {
+        for index in &self.indexes {
+            let key: Vec<u8> = index.definition.key(&document.body, &document.id)?;
+            let mut tree = index.tree();
+
+            // Until the log is replayed after a crash, the key may belong to another
+            // document of a unique index
+            if tree.get(&key)? == Some(document.location) {
+                tree.remove(&key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pager(&self) -> MutexGuard<'_, Pager> {
+        self.pager.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The pager, without locking since writers have the collection to themselves.
+    fn pager_mut(&mut self) -> &mut Pager {
+        self.pager.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn id_index(&self) -> MutexGuard<'_, BTree> {
+        self.id_index.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn damaged(collection: &str, part: &str, err: &anyhow::Error) -> DbError {
+    DbError::FailedPrecondition(format!(
+        "{part} of collection \"{collection}\" can't be read, so it can't be vacuumed: {err}"
+    ))
+}
+
+fn files_size(path: &Path) -> anyhow::Result<u64> {
+    let mut size: u64 = 0;
+
+    for entry in fs::read_dir(path)? {
+        size += entry?.metadata()?.len();
+    }
+
+    Ok(size)
+}
+
+/// Removes a paged file along with its double-write file.
+fn remove_paged(path: &Path) -> anyhow::Result<()> {
+    remove_file(path)?;
+    remove_file(&pager::double_write_path(path))
+}
+
+fn remove_file(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::{Seek, SeekFrom, Write},
+        path::{Path, PathBuf},
+    };
+
+    use super::{Collection, Position, DATA_NAME};
+    use crate::database_manager::{
+        document::{self, Document},
+        error::DbError,
+        filter::{Filter, Operator},
+        format::{self, FileKind},
+        index::IndexDefinition,
+        mvcc::History,
+        page::PAGE_SIZE,
+        value::Value,
+    };
+
+    fn open(path: &Path) -> Collection {
+        open_indexed(path, vec![])
+    }
+
+    fn open_indexed(path: &Path, definitions: Vec<IndexDefinition>) -> Collection {
+        Collection::open(
+            "test".to_string(),
+            path.to_string_lossy().into_owned(),
+            definitions,
+        )
+        .unwrap()
+    }
+
+    fn fill(collection: &mut Collection, count: usize) {
+        for i in 0..count {
+            let body: Value = Document::new_body(
+                &format!("{i:04}"),
+                [("text".to_string(), Value::String("x".repeat(200)))].into(),
+            );
+
+            collection.upsert(body).unwrap();
+        }
+    }
+
+    #[test]
+    fn vacuum_packs_the_documents() {
+        let path: PathBuf = format::test_dir("vacuum_packs_the_documents");
+        let mut collection: Collection = open(&path);
+
+        fill(&mut collection, 200);
+
+        let removed: Vec<String> = (0..200).step_by(2).map(|i| format!("{i:04}")).collect();
+
+        collection.delete(&removed).unwrap();
+
+        assert!(collection.vacuum().unwrap() > 0);
+
+        let ids: Vec<String> = collection
+            .documents()
+            .unwrap()
+            .into_iter()
+            .map(|document| document.id)
+            .collect();
+
+        assert_eq!(ids.len(), 100);
+        assert!(collection.get("0001").unwrap().is_some());
+        assert!(collection.get("0002").unwrap().is_none());
+
+        drop(collection);
+
+        assert_eq!(open(&path).documents().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn vacuum_keeps_a_damaged_file() {
+        let path: PathBuf = format::test_dir("vacuum_keeps_a_damaged_file");
+        let mut collection: Collection = open(&path);
+
+        fill(&mut collection, 100);
+
+        collection.flush().unwrap();
+
+        drop(collection);
+
+        let data_path: PathBuf = path
+            .join(DATA_NAME)
+            .with_extension(FileKind::Data.extension());
+        let before: Vec<u8> = std::fs::read(&data_path).unwrap();
+
+        let mut file = OpenOptions::new().write(true).open(&data_path).unwrap();
+
+        file.seek(SeekFrom::Start(PAGE_SIZE as u64 + 100)).unwrap();
+        file.write_all(b"damage").unwrap();
+
+        drop(file);
+
+        let mut collection: Collection = open(&path);
+
+        // Reads skip the damaged page
+        assert!(collection.documents().unwrap().len() < 100);
+
+        let err: DbError = collection.vacuum().unwrap_err().into();
+
+        assert!(matches!(err, DbError::FailedPrecondition(_)), "{err:?}");
+
+        let after: Vec<u8> = std::fs::read(&data_path).unwrap();
+
+        assert_eq!(after.len(), before.len());
+        assert!(!data_path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn quarantine_rebuilds_the_indexes() {
+        let path: PathBuf = format::test_dir("quarantine_rebuilds_the_indexes");
+        let definition: IndexDefinition = IndexDefinition {
+            name: "text".to_string(),
+            fields: vec![vec!["text".to_string()]],
+            unique: false,
+        };
+        let mut collection: Collection = open(&path);
+
+        collection.create_index(definition.clone()).unwrap();
+
+        fill(&mut collection, 10);
+
+        collection.flush().unwrap();
+
+        drop(collection);
+
+        let data_path: PathBuf = path
+            .join(DATA_NAME)
+            .with_extension(FileKind::Data.extension());
+
+        std::fs::write(&data_path, b"not a data file").unwrap();
+
+        let collection: Collection = open_indexed(&path, vec![definition]);
+        let filter: Filter = Filter::Compare {
+            path: vec!["text".to_string()],
+            operator: Operator::Equal,
+            value: Value::String("x".repeat(200)),
+        };
+
+        assert!(collection.get("0001").unwrap().is_none());
+        assert!(collection.matching(Some(&filter)).unwrap().is_empty());
+        assert!(data_path
+            .with_extension(format!(
+                "{}.{}",
+                FileKind::Data.extension(),
+                format::QUARANTINE_EXTENSION
+            ))
+            .exists());
+    }
+
+    #[test]
+    fn parts_read_their_snapshot() {
+        let path: PathBuf = format::test_dir("parts_read_their_snapshot");
+        let definition: IndexDefinition = IndexDefinition {
+            name: "text".to_string(),
+            fields: vec![vec!["text".to_string()]],
+            unique: false,
+        };
+        let mut collection: Collection = open(&path);
+
+        collection.create_index(definition).unwrap();
+
+        fill(&mut collection, 10);
+
+        let filter: Filter = Filter::Compare {
+            path: vec!["text".to_string()],
+            operator: Operator::Equal,
+            value: Value::String("x".repeat(200)),
+        };
+
+        for filter in [None, Some(&filter)] {
+            let mut position: Position = collection.position(filter);
+            let mut bodies: Vec<Value> = collection
+                .matching_part_at(filter, 0, &mut position, 3)
+                .unwrap();
+            let changed: Value = Document::new_body(
+                "0007",
+                [("text".to_string(), Value::String("y".to_string()))].into(),
+            );
+            let added: Value = Document::new_body(
+                "0001a",
+                [("text".to_string(), Value::String("x".repeat(200)))].into(),
+            );
+
+            // Around the keys read so far and those left
+            collection
+                .commit(
+                    1,
+                    &["0001".to_string(), "0005".to_string()],
+                    vec![changed, added],
+                )
+                .unwrap();
+
+            while !position.done {
+                bodies.extend(
+                    collection
+                        .matching_part_at(filter, 0, &mut position, 3)
+                        .unwrap(),
+                );
+            }
+
+            let mut ids: Vec<String> = bodies
+                .iter()
+                .map(|body| document::id_of(body).unwrap().to_string())
+                .collect();
+
+            ids.sort();
+
+            let expected: Vec<String> = (0..10).map(|i| format!("{i:04}")).collect();
+
+            assert_eq!(ids, expected);
+
+            // Back to how it was for the next filter
+            collection.history = History::default();
+            collection.delete(&["0001a".to_string()]).unwrap();
+            fill(&mut collection, 10);
         }
     }
 }
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use tonic::transport::Identity;
 
 use crate::database_manager::address::Address;
@@ -7,14 +9,42 @@ pub struct Config {
     pub store_path: String,
     pub address: Address,
     pub id: Option<Identity>,
+    pub compaction: Compaction,
+    /// How long a cursor can be left unused before it is closed, `None` to keep cursors
+    /// until their session ends.
+    pub cursor_timeout: Option<Duration>,
+    /// How long a transaction can be left unused before it is rolled back, `None` to keep
+    /// it until its session ends.
+    pub transaction_timeout: Option<Duration>,
 }
 
 impl Config {
-    pub fn new(store_path: String, address: Address, id: Option<Identity>) -> Self {
+    pub fn new(
+        store_path: String,
+        address: Address,
+        id: Option<Identity>,
+        compaction: Compaction,
+        cursor_timeout: Option<Duration>,
+        transaction_timeout: Option<Duration>,
+    ) -> Self {
         Self {
             store_path,
             address,
             id,
+            compaction,
+            cursor_timeout,
+            transaction_timeout,
         }
     }
 }
+
+/// When the background task vacuums a collection.
+#[derive(Clone, Debug)]
+pub struct Compaction {
+    /// Time between two passes, `None` if the task is disabled.
+    pub interval: Option<Duration>,
+    /// Share of the data file, in percent, that has to be unused.
+    pub free_percent: u8,
+    /// Data files smaller than this, in bytes, are left alone.
+    pub min_size: u64,
+}
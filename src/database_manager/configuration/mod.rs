@@ -1,5 +1,5 @@
 mod config;
 mod raw_config;
 
-pub use config::Config;
+pub use config::{Compaction, Config};
 pub use raw_config::RawConfig;
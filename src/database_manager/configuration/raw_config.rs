@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::{path::Path, time::Duration};
 
 use anyhow::bail;
 use serde::{Deserialize, Serialize};
@@ -8,10 +8,17 @@ use tracing::{error, info, warn};
 
 use crate::database_manager::address::Address;
 
-use super::Config;
+use super::{Compaction, Config};
 
 pub const DEFAULT_PORT: u16 = 44080;
 
+pub const DEFAULT_COMPACTION_INTERVAL_SECS: u64 = 60;
+pub const DEFAULT_COMPACTION_FREE_PERCENT: u8 = 50;
+pub const DEFAULT_COMPACTION_MIN_SIZE: u64 = 1024 * 1024;
+
+pub const DEFAULT_CURSOR_TIMEOUT_SECS: u64 = 600;
+pub const DEFAULT_TRANSACTION_TIMEOUT_SECS: u64 = 300;
+
 #[derive(Serialize, Deserialize, Clone, Debug, Eq, Hash, PartialEq)]
 pub struct RawConfig {
     pub store_path: Option<String>,
@@ -20,6 +27,14 @@ pub struct RawConfig {
     pub show_public_ip: Option<bool>,
     pub tls_cert_path: Option<String>,
     pub tls_key_path: Option<String>,
+    /// Seconds between two compaction passes, 0 to disable them.
+    pub compaction_interval_secs: Option<u64>,
+    pub compaction_free_percent: Option<u8>,
+    pub compaction_min_size: Option<u64>,
+    /// Seconds a cursor can stay unused before it is closed, 0 to keep it open.
+    pub cursor_timeout_secs: Option<u64>,
+    /// Seconds a transaction can stay unused before it is rolled back, 0 to keep it open.
+    pub transaction_timeout_secs: Option<u64>,
 }
 
 impl Default for RawConfig {
@@ -31,6 +46,11 @@ impl Default for RawConfig {
             show_public_ip: Some(false),
             tls_cert_path: None,
             tls_key_path: None,
+            compaction_interval_secs: Some(DEFAULT_COMPACTION_INTERVAL_SECS),
+            compaction_free_percent: Some(DEFAULT_COMPACTION_FREE_PERCENT),
+            compaction_min_size: Some(DEFAULT_COMPACTION_MIN_SIZE),
+            cursor_timeout_secs: Some(DEFAULT_CURSOR_TIMEOUT_SECS),
+            transaction_timeout_secs: Some(DEFAULT_TRANSACTION_TIMEOUT_SECS),
         }
     }
 }
@@ -124,6 +144,46 @@ impl RawConfig {
             bail!("Exiting...");
         }
 
+        let free_percent: u8 = self
+            .compaction_free_percent
+            .unwrap_or(DEFAULT_COMPACTION_FREE_PERCENT);
+
+        if free_percent > 100 {
+            error!("compaction_free_percent must be at most 100, got {free_percent}");
+
+            bail!("Exiting...");
+        }
+
+        let compaction: Compaction = Compaction {
+            interval: match self
+                .compaction_interval_secs
+                .unwrap_or(DEFAULT_COMPACTION_INTERVAL_SECS)
+            {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            free_percent,
+            min_size: self
+                .compaction_min_size
+                .unwrap_or(DEFAULT_COMPACTION_MIN_SIZE),
+        };
+
+        let cursor_timeout: Option<Duration> = match self
+            .cursor_timeout_secs
+            .unwrap_or(DEFAULT_CURSOR_TIMEOUT_SECS)
+        {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
+        let transaction_timeout: Option<Duration> = match self
+            .transaction_timeout_secs
+            .unwrap_or(DEFAULT_TRANSACTION_TIMEOUT_SECS)
+        {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+
         let id =
             if let (Some(cert_path), Some(key_path)) = (&self.tls_cert_path, &self.tls_key_path) {
                 let cert: String = tokio::fs::read_to_string(cert_path).await?;
@@ -134,6 +194,13 @@ impl RawConfig {
                 None
             };
 
-        Ok(Config::new(path, address, id))
+        Ok(Config::new(
+            path,
+            address,
+            id,
+            compaction,
+            cursor_timeout,
+            transaction_timeout,
+        ))
     }
 }
@@ -0,0 +1,182 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+    vec,
+};
+
+use anyhow::bail;
+
+use super::{error::DbError, value::Value};
+
+/// How many bytes of documents, once encoded, the cursors of a session can hold together.
+pub const MAX_CURSOR_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Debug)]
+struct Cursor {
+    documents: vec::IntoIter<Value>,
+    batch_size: usize,
+    last_used: Instant,
+    size: usize,
+}
+
+#[derive(Debug)]
+pub struct Batch {
+    pub documents: Vec<Value>,
+    pub cursor: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cursors {
+    last_id: u64,
+    cursors: HashMap<u64, Cursor>,
+}
+
+impl Cursors {
+    pub fn open(&mut self, documents: Vec<Value>, batch_size: usize) -> anyhow::Result<Batch> {
+        let mut documents: vec::IntoIter<Value> = documents.into_iter();
+
+        let batch: Vec<Value> = documents.by_ref().take(batch_size).collect();
+
+        if documents.len() == 0 {
+            return Ok(Batch {
+                documents: batch,
+                cursor: None,
+            });
+        }
+
+        let size: usize = size_of(documents.as_slice());
+        let held: usize = self.cursors.values().map(|cursor| cursor.size).sum();
+
+        if held + size > MAX_CURSOR_BYTES {
+            bail!(DbError::FailedPrecondition(format!(
+                "the documents left after the first batch take {size} bytes, and the cursors of a session can hold {MAX_CURSOR_BYTES} bytes ({held} in use): narrow the filter or close other cursors"
+            )));
+        }
+
+        self.last_id += 1;
+        self.cursors.insert(
+            self.last_id,
+            Cursor {
+                documents,
+                batch_size,
+                last_used: Instant::now(),
+                size,
+            },
+        );
+
+        Ok(Batch {
+            documents: batch,
+            cursor: Some(self.last_id),
+        })
+    }
+
+    /// The cursor is closed once it has nothing left.
+    pub fn next(&mut self, id: u64) -> anyhow::Result<Batch> {
+        let cursor: &mut Cursor = self
+            .cursors
+            .get_mut(&id)
+            .ok_or_else(|| no_such_cursor(id))?;
+
+        let documents: Vec<Value> = cursor.documents.by_ref().take(cursor.batch_size).collect();
+
+        cursor.size -= size_of(&documents);
+        cursor.last_used = Instant::now();
+
+        if cursor.documents.len() == 0 {
+            self.cursors.remove(&id);
+
+            return Ok(Batch {
+                documents,
+                cursor: None,
+            });
+        }
+
+        Ok(Batch {
+            documents,
+            cursor: Some(id),
+        })
+    }
+
+    pub fn close(&mut self, id: u64) -> anyhow::Result<()> {
+        self.cursors.remove(&id).ok_or_else(|| no_such_cursor(id))?;
+
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.cursors.clear();
+    }
+
+    pub fn expire(&mut self, timeout: Duration) -> usize {
+        let count: usize = self.cursors.len();
+
+        self.cursors
+            .retain(|_, cursor| cursor.last_used.elapsed() < timeout);
+
+        count - self.cursors.len()
+    }
+}
+
+fn size_of(documents: &[Value]) -> usize {
+    let mut bytes: Vec<u8> = vec![];
+
+    for document in documents {
+        document.encode(&mut bytes);
+    }
+
+    bytes.len()
+}
+
+fn no_such_cursor(id: u64) -> DbError {
+    DbError::NotFound(format!("no such cursor {id}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Batch, Cursors, MAX_CURSOR_BYTES};
+    use crate::database_manager::{error::DbError, value::Value};
+
+    fn documents(count: i64) -> Vec<Value> {
+        (0..count).map(Value::Int).collect()
+    }
+
+    #[test]
+    fn batches_until_empty() {
+        let mut cursors: Cursors = Cursors::default();
+
+        let first: Batch = cursors.open(documents(5), 2).unwrap();
+
+        assert_eq!(first.documents, documents(2));
+
+        let id: u64 = first.cursor.unwrap();
+
+        assert_eq!(cursors.next(id).unwrap().cursor, Some(id));
+
+        let last: Batch = cursors.next(id).unwrap();
+
+        assert_eq!(last.documents, vec![Value::Int(4)]);
+        assert_eq!(last.cursor, None);
+        assert!(cursors.next(id).is_err());
+    }
+
+    #[test]
+    fn memory_is_limited() {
+        let mut cursors: Cursors = Cursors::default();
+        let large: Value = Value::String("x".repeat(MAX_CURSOR_BYTES / 4));
+
+        let first: u64 = cursors
+            .open(vec![large.clone(); 3], 1)
+            .unwrap()
+            .cursor
+            .unwrap();
+
+        let err: DbError = cursors.open(vec![large.clone(); 3], 1).unwrap_err().into();
+
+        assert!(matches!(err, DbError::FailedPrecondition(_)), "{err:?}");
+
+        // Reading a batch frees its documents
+        cursors.next(first).unwrap();
+
+        assert!(cursors.open(vec![large; 2], 1).is_ok());
+    }
+}
@@ -1,11 +1,137 @@
-#[derive(Clone, Debug, Default, Eq, Hash, PartialEq)]
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail};
+
+use super::{
+    btree::MAX_KEY_SIZE, error::DbError, page::MAX_RECORD_SIZE, pager::PageId, value::Value,
+};
+
+pub const ID_FIELD: &str = "_id";
+
+/// Where a document is stored: a page of its collection's data file and a slot in it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RecordId {
+    pub page: PageId,
+    pub slot: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Document {
-    pub name: String,
-    pub path: String,
+    pub id: String,
+    pub location: RecordId,
+    pub body: Value,
 }
 
 impl Document {
-    pub fn new(name: String, path: String) -> Self {
-        Self { name, path }
+    pub fn new(id: String, location: RecordId, body: Value) -> Self {
+        Self { id, location, body }
+    }
+
+    /// Builds the body of a new document from an object literal, storing `id` under `_id`
+    /// so it is written to disk along with the other fields.
+    pub fn new_body(id: &str, mut fields: BTreeMap<String, Value>) -> Value {
+        fields.insert(ID_FIELD.into(), Value::String(id.to_string()));
+
+        Value::Object(fields)
+    }
+
+    /// Reads a document back from the record stored at `location`.
+    pub fn decode(location: RecordId, mut record: &[u8]) -> anyhow::Result<Self> {
+        let body: Value = Value::decode(&mut record)?;
+
+        if !record.is_empty() {
+            bail!("unexpected data after the document body");
+        }
+
+        let id: String = id_of(&body)?.to_string();
+
+        Ok(Self::new(id, location, body))
+    }
+}
+
+/// The `_id` stored in a document body.
+pub fn id_of(body: &Value) -> anyhow::Result<&str> {
+    let Value::Object(fields) = body else {
+        return Err(anyhow!("document is not an object"));
+    };
+
+    let Some(Value::String(id)) = fields.get(ID_FIELD) else {
+        return Err(anyhow!("document has no \"{ID_FIELD}\""));
+    };
+
+    Ok(id)
+}
+
+/// Checks that `body` can be stored, before its write is logged.
+pub fn check(body: &Value) -> anyhow::Result<()> {
+    let id: &str = id_of(body).map_err(|err| DbError::InvalidArgument(err.to_string()))?;
+
+    // It is the key of the _id index
+    if id.len() > MAX_KEY_SIZE {
+        bail!(DbError::InvalidArgument(format!(
+            "\"{ID_FIELD}\" is too large: {} bytes, at most {MAX_KEY_SIZE}",
+            id.len()
+        )));
+    }
+
+    encode(body)?;
+
+    Ok(())
+}
+
+/// Encodes a document body as the record stored in a page.
+pub fn encode(body: &Value) -> anyhow::Result<Vec<u8>> {
+    let mut record: Vec<u8> = vec![];
+
+    body.encode(&mut record);
+
+    if record.len() > MAX_RECORD_SIZE {
+        bail!(DbError::InvalidArgument(format!(
+            "document is too large: {} bytes once encoded, at most {MAX_RECORD_SIZE}",
+            record.len()
+        )));
+    }
+
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{check, Document, ID_FIELD};
+    use crate::database_manager::{btree::MAX_KEY_SIZE, error::DbError, value::Value};
+
+    #[test]
+    fn check_requires_a_string_id() {
+        assert!(check(&Document::new_body("a", BTreeMap::new())).is_ok());
+
+        for body in [
+            Value::Int(1),
+            Value::Object(BTreeMap::new()),
+            Value::Object([(ID_FIELD.to_string(), Value::Int(1))].into()),
+        ] {
+            let err: DbError = check(&body).unwrap_err().into();
+
+            assert!(matches!(err, DbError::InvalidArgument(_)), "{err:?}");
+        }
+    }
+
+    #[test]
+    fn check_limits_the_id_size() {
+        assert!(check(&Document::new_body(
+            &"a".repeat(MAX_KEY_SIZE),
+            BTreeMap::new()
+        ))
+        .is_ok());
+
+        let err: DbError = check(&Document::new_body(
+            &"a".repeat(MAX_KEY_SIZE + 1),
+            BTreeMap::new(),
+        ))
+        .unwrap_err()
+        .into();
+
+        assert!(matches!(err, DbError::InvalidArgument(_)), "{err:?}");
     }
 }
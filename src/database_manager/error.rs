@@ -0,0 +1,100 @@
+//! The errors commands fail with, by what a client can do about them. The engine raises
+//! them through `anyhow` like any other error; whatever isn't one of them, such as failed
+//! I/O or a damaged file, is internal.
+
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{lexer::token::Span, parser::ParseError};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DbError {
+    /// The statement doesn't parse. `span` points into the command text.
+    Syntax {
+        message: String,
+        span: Span,
+    },
+    NotFound(String),
+    AlreadyExists(String),
+    /// A value the command can't use, such as a document too large to store.
+    InvalidArgument(String),
+    /// The session isn't in a state to run the command, such as `COMMIT` without `BEGIN`.
+    FailedPrecondition(String),
+    /// Another write got in the way: a duplicate in a unique index, or a document changed
+    /// by another session since the transaction began.
+    Conflict(String),
+    Internal(String),
+}
+
+impl DbError {
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Syntax { message, .. }
+            | Self::NotFound(message)
+            | Self::AlreadyExists(message)
+            | Self::InvalidArgument(message)
+            | Self::FailedPrecondition(message)
+            | Self::Conflict(message)
+            | Self::Internal(message) => message,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::Syntax { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+
+    /// Internal errors leave the state of the database unknown, so the session can't go on.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Self::Internal(_))
+    }
+
+    /// The same error with `f` applied to its message.
+    pub fn map_message(self, f: impl FnOnce(String) -> String) -> Self {
+        match self {
+            Self::Syntax { message, span } => Self::Syntax {
+                message: f(message),
+                span,
+            },
+            Self::NotFound(message) => Self::NotFound(f(message)),
+            Self::AlreadyExists(message) => Self::AlreadyExists(f(message)),
+            Self::InvalidArgument(message) => Self::InvalidArgument(f(message)),
+            Self::FailedPrecondition(message) => Self::FailedPrecondition(f(message)),
+            Self::Conflict(message) => Self::Conflict(f(message)),
+            Self::Internal(message) => Self::Internal(f(message)),
+        }
+    }
+}
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl Error for DbError {}
+
+impl From<ParseError> for DbError {
+    fn from(err: ParseError) -> Self {
+        Self::Syntax {
+            message: err.to_string(),
+            span: err.span,
+        }
+    }
+}
+
+impl From<anyhow::Error> for DbError {
+    fn from(err: anyhow::Error) -> Self {
+        match err.downcast::<Self>() {
+            Ok(err) => err,
+            Err(err) => match err.downcast::<ParseError>() {
+                Ok(err) => err.into(),
+                Err(err) => Self::Internal(err.to_string()),
+            },
+        }
+    }
+}
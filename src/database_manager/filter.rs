@@ -0,0 +1,88 @@
+use std::cmp::Ordering;
+
+use super::value::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Filter {
+    Compare {
+        path: Vec<String>,
+        operator: Operator,
+        value: Value,
+    },
+    In {
+        path: Vec<String>,
+        values: Vec<Value>,
+    },
+    Exists {
+        path: Vec<String>,
+    },
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    pub fn matches(&self, document: &Value) -> bool {
+        match self {
+            Self::Compare {
+                path,
+                operator,
+                value,
+            } => {
+                let Some(field) = document.get_path(path) else {
+                    return *operator == Operator::NotEqual;
+                };
+
+                let ordering: Option<Ordering> = field.compare(value);
+
+                match operator {
+                    Operator::Equal => ordering == Some(Ordering::Equal),
+                    Operator::NotEqual => ordering != Some(Ordering::Equal),
+                    Operator::Less => ordering == Some(Ordering::Less),
+                    Operator::LessEqual => {
+                        matches!(ordering, Some(Ordering::Less | Ordering::Equal))
+                    }
+                    Operator::Greater => ordering == Some(Ordering::Greater),
+                    Operator::GreaterEqual => {
+                        matches!(ordering, Some(Ordering::Greater | Ordering::Equal))
+                    }
+                }
+            }
+            Self::In { path, values } => document.get_path(path).is_some_and(|field| {
+                values
+                    .iter()
+                    .any(|value| field.compare(value) == Some(Ordering::Equal))
+            }),
+            Self::Exists { path } => document.get_path(path).is_some(),
+            Self::And(left, right) => left.matches(document) && right.matches(document),
+            Self::Or(left, right) => left.matches(document) || right.matches(document),
+        }
+    }
+
+    /// The value the field at `path` has to equal for any document to match, if the
+    /// filter pins it down.
+    pub fn equality(&self, path: &[String]) -> Option<&Value> {
+        match self {
+            Self::Compare {
+                path: field,
+                operator: Operator::Equal,
+                value,
+            } if field == path => Some(value),
+            Self::In {
+                path: field,
+                values,
+            } if field == path && values.len() == 1 => values.first(),
+            Self::And(left, right) => left.equality(path).or_else(|| right.equality(path)),
+            _ => None,
+        }
+    }
+}
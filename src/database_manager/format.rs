@@ -0,0 +1,193 @@
+use std::{
+    ffi::OsString,
+    fmt::{self, Display, Formatter},
+    fs::{self, File},
+    io::ErrorKind,
+    path::Path,
+};
+
+use tracing::warn;
+
+/// Every file LilDB writes starts with `MAGIC`, the format version and the kind of file.
+pub const MAGIC: [u8; 4] = *b"LilD";
+pub const FORMAT_VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 8;
+
+/// Files that fail their checks are renamed with this extension appended, so they are
+/// kept for inspection but never loaded again.
+pub const QUARANTINE_EXTENSION: &str = "quarantined";
+
+/// The kinds of files in the store, each with its registered extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Data,
+    Log,
+    Index,
+    Catalog,
+    DoubleWrite,
+}
+
+impl FileKind {
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Data => "ldat",
+            Self::Log => "lwal",
+            Self::Index => "lidx",
+            Self::Catalog => "lcat",
+            Self::DoubleWrite => "ldwb",
+        }
+    }
+
+    const fn tag(self) -> u8 {
+        match self {
+            Self::Data => 3,
+            Self::Log => 2,
+            Self::Index => 4,
+            Self::Catalog => 5,
+            Self::DoubleWrite => 6,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FormatError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    WrongKind(u8),
+    /// The file ends in the middle of a header or record, as left by a crash during a write.
+    Truncated,
+    ChecksumMismatch,
+    /// The checksum matches but the contents make no sense.
+    Malformed,
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a LilDB file"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {version}")
+            }
+            Self::WrongKind(tag) => write!(f, "unexpected file kind {tag}"),
+            Self::Truncated => write!(f, "file is truncated"),
+            Self::ChecksumMismatch => write!(f, "checksum mismatch"),
+            Self::Malformed => write!(f, "malformed data"),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+pub fn write_header(kind: FileKind, out: &mut Vec<u8>) {
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.push(kind.tag());
+    out.push(0); // Reserved
+}
+
+/// Checks the header of `bytes` and returns what follows it.
+pub fn read_header(bytes: &[u8], kind: FileKind) -> Result<&[u8], FormatError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(if MAGIC.starts_with(bytes) {
+            FormatError::Truncated
+        } else {
+            FormatError::BadMagic
+        });
+    }
+
+    if bytes[..4] != MAGIC {
+        return Err(FormatError::BadMagic);
+    }
+
+    let version: u16 = u16::from_le_bytes([bytes[4], bytes[5]]);
+
+    if version != FORMAT_VERSION {
+        return Err(FormatError::UnsupportedVersion(version));
+    }
+
+    if bytes[6] != kind.tag() {
+        return Err(FormatError::WrongKind(bytes[6]));
+    }
+
+    Ok(&bytes[HEADER_SIZE..])
+}
+
+/// Appends a record: its length, the CRC-32 of its payload, then the payload.
+pub fn write_record(payload: &[u8], out: &mut Vec<u8>) {
+    let length: u32 = u32::try_from(payload.len()).expect("record larger than 4 GiB");
+
+    out.extend_from_slice(&length.to_le_bytes());
+    out.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Reads the next record of `bytes` and advances past it. Returns `None` at the end.
+pub fn read_record<'a>(bytes: &mut &'a [u8]) -> Result<Option<&'a [u8]>, FormatError> {
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+
+    if bytes.len() < 8 {
+        return Err(FormatError::Truncated);
+    }
+
+    let length: usize = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let checksum: u32 = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+
+    let Some(payload) = bytes[8..].get(..length) else {
+        return Err(FormatError::Truncated);
+    };
+
+    if crc32fast::hash(payload) != checksum {
+        return Err(FormatError::ChecksumMismatch);
+    }
+
+    *bytes = &bytes[8 + length..];
+
+    Ok(Some(payload))
+}
+
+/// Moves a file that can't be read out of the way, so it is never loaded again but stays
+/// around for inspection.
+pub fn quarantine(path: &Path, err: &dyn Display) {
+    let mut quarantined: OsString = path.as_os_str().to_owned();
+
+    quarantined.push(".");
+    quarantined.push(QUARANTINE_EXTENSION);
+
+    match fs::rename(path, &quarantined) {
+        Ok(()) => warn!(
+            "Quarantined \"{}\": {err}",
+            Path::new(&quarantined).display()
+        ),
+        Err(rename_err) => warn!(
+            "Couldn't read \"{}\": {err}, nor quarantine it: {rename_err}",
+            path.display()
+        ),
+    }
+}
+
+/// Makes the creation and removal of the entries of a directory durable.
+pub fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    match File::open(path) {
+        Ok(dir) => dir.sync_all()?,
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    Ok(())
+}
+
+/// An empty directory for a test, named after it.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> std::path::PathBuf {
+    let path: std::path::PathBuf = std::env::temp_dir()
+        .join(format!("lildb-test-{}", std::process::id()))
+        .join(name);
+
+    let _ = fs::remove_dir_all(&path);
+
+    fs::create_dir_all(&path).expect("the test directory can be created");
+
+    path
+}
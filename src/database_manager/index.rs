@@ -0,0 +1,304 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::{anyhow, bail};
+
+use super::{
+    btree::{BTree, MAX_KEY_SIZE},
+    error::DbError,
+    format::FileKind,
+    value::Value,
+};
+
+const MISSING: u8 = 0;
+const NULL: u8 = 1;
+const BOOL: u8 = 2;
+const NUMBER: u8 = 3;
+const STRING: u8 = 4;
+const OTHER: u8 = 5;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexDefinition {
+    pub name: String,
+    /// Dotted paths, in key order.
+    pub fields: Vec<Vec<String>>,
+    pub unique: bool,
+}
+
+impl IndexDefinition {
+    /// Documents of a non-unique index can share field values, so `id` is appended to tell
+    /// them apart.
+    pub fn key(&self, body: &Value, id: &str) -> anyhow::Result<Vec<u8>> {
+        let mut key: Vec<u8> = self.prefix(self.fields.iter().map(|path| body.get_path(path)));
+
+        if !self.unique {
+            key.extend_from_slice(id.as_bytes());
+        }
+
+        if key.len() > MAX_KEY_SIZE {
+            bail!(DbError::InvalidArgument(format!(
+                "key of index \"{}\" is too large: {} bytes, at most {MAX_KEY_SIZE}",
+                self.name,
+                key.len()
+            )));
+        }
+
+        Ok(key)
+    }
+
+    /// The start shared by the keys of every document whose leading fields hold `values`.
+    pub fn prefix<'a>(&self, values: impl Iterator<Item = Option<&'a Value>>) -> Vec<u8> {
+        let mut key: Vec<u8> = vec![];
+
+        for value in values {
+            encode_key_part(value, &mut key);
+        }
+
+        key
+    }
+
+    /// The fields of `body` this index holds, for error messages.
+    pub fn describe(&self, body: &Value) -> String {
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|path| match body.get_path(path) {
+                Some(value) => format!("{} = {value}", path.join(".")),
+                None => format!("missing {}", path.join(".")),
+            })
+            .collect();
+
+        fields.join(", ")
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+
+        fields.insert("name".into(), Value::String(self.name.clone()));
+        fields.insert(
+            "fields".into(),
+            Value::Array(
+                self.fields
+                    .iter()
+                    .map(|path| Value::String(path.join(".")))
+                    .collect(),
+            ),
+        );
+        fields.insert("unique".into(), Value::Bool(self.unique));
+
+        Value::Object(fields)
+    }
+
+    pub fn from_value(value: Value) -> anyhow::Result<Self> {
+        let Value::Object(mut fields) = value else {
+            bail!("index definition is not an object");
+        };
+
+        let Some(Value::String(name)) = fields.remove("name") else {
+            bail!("index definition has no name");
+        };
+
+        let Some(Value::Array(paths)) = fields.remove("fields") else {
+            bail!("index definition has no fields");
+        };
+
+        let Some(Value::Bool(unique)) = fields.remove("unique") else {
+            bail!("index definition doesn't say whether it is unique");
+        };
+
+        let fields: Vec<Vec<String>> = paths
+            .into_iter()
+            .map(|path| match path {
+                Value::String(path) => Ok(path.split('.').map(String::from).collect()),
+                path => Err(anyhow!("invalid field path {path}")),
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            name,
+            fields,
+            unique,
+        })
+    }
+}
+
+impl Display for IndexDefinition {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let fields: Vec<String> = self.fields.iter().map(|path| path.join(".")).collect();
+
+        write!(f, "{} ({})", self.name, fields.join(", "))?;
+
+        if self.unique {
+            write!(f, " unique")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Index {
+    pub definition: IndexDefinition,
+    /// Behind a mutex for the same reason as the `_id` index.
+    tree: Mutex<BTree>,
+}
+
+impl Index {
+    pub fn new(definition: IndexDefinition, tree: BTree) -> Self {
+        Self {
+            definition,
+            tree: Mutex::new(tree),
+        }
+    }
+
+    pub fn tree(&self) -> MutexGuard<'_, BTree> {
+        self.tree.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub fn tree_path(collection_path: &Path, name: &str) -> PathBuf {
+    collection_path
+        .join(name)
+        .with_extension(FileKind::Index.extension())
+}
+
+/// Appends one field to a key. Keys compare bytewise in the order `Value::compare` gives
+/// their fields, and values that compare equal get the same bytes.
+fn encode_key_part(value: Option<&Value>, key: &mut Vec<u8>) {
+    match value {
+        None => key.push(MISSING),
+        Some(Value::Null) => key.push(NULL),
+        Some(Value::Bool(boolean)) => key.extend_from_slice(&[BOOL, *boolean as u8]),
+        Some(Value::Int(integer)) => encode_int(*integer, key),
+        Some(Value::Float(float)) => encode_number(*float, 0, key),
+        Some(Value::String(string)) => {
+            key.push(STRING);
+            encode_bytes(string.as_bytes(), key);
+        }
+        // Arrays and objects only ever compare equal, so their order doesn't matter
+        Some(value) => {
+            let mut bytes: Vec<u8> = vec![];
+
+            value.encode(&mut bytes);
+
+            key.push(OTHER);
+            encode_bytes(&bytes, key);
+        }
+    }
+}
+
+/// Keys an integer by the largest float not above it, then by what it exceeds that float
+/// by, which floats don't: integers that floats can't represent sort between them.
+fn encode_int(integer: i64, key: &mut Vec<u8>) {
+    let mut float: f64 = integer as f64;
+
+    if float as i128 > integer as i128 {
+        float = float.next_down();
+    }
+
+    encode_number(float, (integer as i128 - float as i128) as u64, key);
+}
+
+fn encode_number(number: f64, remainder: u64, key: &mut Vec<u8>) {
+    // 0.0 and -0.0 compare equal
+    let bits: u64 = if number == 0.0 { 0 } else { number.to_bits() };
+
+    // Flipping the sign bit of positive numbers and every bit of negative ones makes
+    // their bytes sort like the numbers
+    let ordered: u64 = if bits >> 63 == 0 {
+        bits | 1 << 63
+    } else {
+        !bits
+    };
+
+    key.push(NUMBER);
+    key.extend_from_slice(&ordered.to_be_bytes());
+    key.extend_from_slice(&remainder.to_be_bytes());
+}
+
+/// Escapes zero bytes and terminates the bytes with two zeros, so no encoded value is a
+/// prefix of another.
+fn encode_bytes(bytes: &[u8], key: &mut Vec<u8>) {
+    for byte in bytes {
+        key.push(*byte);
+
+        if *byte == 0 {
+            key.push(0xFF);
+        }
+    }
+
+    key.extend_from_slice(&[0, 0]);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::encode_key_part;
+    use crate::database_manager::value::Value;
+
+    fn key(value: &Value) -> Vec<u8> {
+        let mut key: Vec<u8> = vec![];
+
+        encode_key_part(Some(value), &mut key);
+
+        key
+    }
+
+    #[test]
+    fn numbers_sort_exactly() {
+        const TWO_53: i64 = 1 << 53;
+
+        let numbers: Vec<Value> = vec![
+            Value::Float(f64::NEG_INFINITY),
+            Value::Int(i64::MIN),
+            Value::Int(i64::MIN + 1),
+            Value::Float(-(TWO_53 as f64) - 2.0),
+            Value::Int(-TWO_53 - 1),
+            Value::Int(-TWO_53),
+            Value::Float(-1.5),
+            Value::Int(-1),
+            Value::Float(-0.0),
+            Value::Int(0),
+            Value::Float(0.5),
+            Value::Int(1),
+            Value::Int(TWO_53 - 1),
+            Value::Float(TWO_53 as f64),
+            Value::Int(TWO_53),
+            Value::Int(TWO_53 + 1),
+            Value::Float(TWO_53 as f64 + 2.0),
+            Value::Int(TWO_53 + 3),
+            Value::Int(i64::MAX - 1),
+            Value::Int(i64::MAX),
+            Value::Float(i64::MAX as f64),
+            Value::Float(f64::INFINITY),
+        ];
+
+        for pair in numbers.windows(2) {
+            assert_ne!(
+                pair[0].compare(&pair[1]),
+                Some(Ordering::Greater),
+                "{pair:?}"
+            );
+        }
+
+        for left in &numbers {
+            for right in &numbers {
+                assert_eq!(
+                    key(left).cmp(&key(right)),
+                    left.compare(right).unwrap(),
+                    "{left} and {right}"
+                );
+            }
+        }
+
+        assert_eq!(
+            Value::Int(TWO_53 + 1).compare(&Value::Float(TWO_53 as f64)),
+            Some(Ordering::Greater)
+        );
+    }
+}
@@ -1,39 +1,144 @@
 use std::{
-    collections::HashSet,
-    io,
+    collections::BTreeMap,
+    iter,
     path::{Path, PathBuf},
+    pin::Pin,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use collection::Collection;
+use anyhow::bail;
+use catalog::{Catalog, CollectionEntry};
+use collection::{Collection, Position, PART_SIZE};
 use configuration::Config;
-use document::Document;
-use tokio::{fs, sync::mpsc, task};
-use waitgroup::WaitGroup;
-
-use crate::{lexer::token::TokenType, token_list::TokenList};
+use cursor::{Batch, Cursors};
+use document::{Document, RecordId, ID_FIELD};
+use error::DbError;
+use filter::Filter;
+use index::IndexDefinition;
+use mvcc::{Clock, Snapshot};
+use store::{SharedDatabase, Store};
+use tokio::{fs, sync::mpsc};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use transaction::Transaction;
+use update::Update;
+use value::Value;
+use wal::{Record, Wal};
+
+use crate::parser::ast::Command;
 
 pub mod address;
+pub mod btree;
+pub mod catalog;
 pub mod collection;
 pub mod configuration;
+pub mod cursor;
 pub mod document;
+pub mod error;
+pub mod filter;
+pub mod format;
+pub mod index;
+pub mod mvcc;
+pub mod object_id;
+pub mod page;
+pub mod pager;
+pub mod store;
+pub mod transaction;
+pub mod update;
+pub mod users;
+pub mod value;
+pub mod wal;
+
+/// Lines of a result as they are produced. Producing one may still fail, after the lines
+/// before it were sent.
+pub type Rows = Pin<Box<dyn Stream<Item = anyhow::Result<String>> + Send>>;
+
+/// What a command gives back.
+pub enum Output {
+    Message(String),
+    /// One line per document or entry, to be sent a few at a time. `empty` is sent instead
+    /// when there are none.
+    Rows {
+        rows: Rows,
+        empty: &'static str,
+        /// The cursor holding the documents left, after a batch.
+        cursor: Option<u64>,
+    },
+}
+
+impl Output {
+    pub fn rows(
+        rows: impl IntoIterator<Item = String, IntoIter: Send + 'static>,
+        empty: &'static str,
+    ) -> Self {
+        Self::Rows {
+            rows: Box::pin(tokio_stream::iter(rows.into_iter().map(Ok))),
+            empty,
+            cursor: None,
+        }
+    }
 
+    /// The lines a task sends over `rows` as it produces them. The channel is bounded, so
+    /// the task waits for the client instead of holding the whole result.
+    pub fn streamed(rows: mpsc::Receiver<anyhow::Result<String>>, empty: &'static str) -> Self {
+        Self::Rows {
+            rows: Box::pin(ReceiverStream::new(rows)),
+            empty,
+            cursor: None,
+        }
+    }
+
+    /// The documents of a batch, then how to get the next one if there is any.
+    pub fn batch(batch: Batch) -> Self {
+        let next: Option<String> = batch
+            .cursor
+            .map(|id| format!("More documents in cursor {id}, get them with \"next {id}\""));
+
+        Self::Rows {
+            rows: Box::pin(tokio_stream::iter(
+                batch
+                    .documents
+                    .into_iter()
+                    .map(|body| body.to_string())
+                    .chain(next)
+                    .map(Ok),
+            )),
+            empty: "No documents found",
+            cursor: batch.cursor,
+        }
+    }
+}
+
+impl From<String> for Output {
+    fn from(message: String) -> Self {
+        Self::Message(message)
+    }
+}
+
+/// The context of one session: the database it is using and where to find the data shared
+/// with the other sessions.
 #[derive(Clone, Debug)]
 pub struct Database {
     pub name: String,
     pub path: String,
-    pub collections: HashSet<Collection>,
     pub current_collection: usize,
     pub config: Arc<Config>,
+    pub store: Arc<Store>,
+    /// Started by `BEGIN`, until `COMMIT` or `ROLLBACK`.
+    pub transaction: Option<Transaction>,
+    /// Whether the transaction was rolled back for being idle, which the next command has
+    /// to report instead of running outside of it.
+    pub transaction_expired: bool,
+    pub cursors: Cursors,
 }
 
 impl PartialEq for Database {
     fn eq(&self, other: &Self) -> bool {
         self.name == other.name
             && self.path == other.path
-            && self.collections == other.collections
             && self.current_collection == other.current_collection
             && Arc::ptr_eq(&self.config, &other.config)
+            && Arc::ptr_eq(&self.store, &other.store)
     }
 }
 
@@ -41,199 +146,490 @@ impl Database {
     pub fn new(
         name: String,
         path: String,
-        collections: HashSet<Collection>,
         current_collection: usize,
         config: Arc<Config>,
+        store: Arc<Store>,
     ) -> Self {
         Self {
             name,
             path,
-            collections,
             current_collection,
             config,
+            store,
+            transaction: None,
+            transaction_expired: false,
+            cursors: Cursors::default(),
         }
     }
 
-    pub async fn process_tokens(
-        &mut self,
-        token_list: TokenList<'_>,
-    ) -> anyhow::Result<(String, bool)> {
-        let result: String = match token_list.tokens[0].tok_type {
-            TokenType::Create => self.f_create(token_list).await?,
-            TokenType::Drop => self.f_drop(token_list).await?,
-            TokenType::Use => self.f_use(token_list).await?,
-            TokenType::Show => self.f_show(token_list).await?,
-            // TokenType::Delete => {
-            //     result = f_delete::f_delete(token_list, database)?;
-            // }
-            TokenType::Help => self.f_help(),
-            // TokenType::Insert => {
-            //     result = f_insert::f_insert(token_list, database)?;
-            // }
-            // TokenType::Update => {
-            //     result = f_update::f_update(token_list, database)?;
-            // }
-            // TokenType::Find => {
-            //     result = f_find::f_find(token_list, database)?;
-            // }
-            _ => format!("Unknown command: {}\n\r", token_list.tokens[0].slice),
+    /// Runs a command, returning its output and whether the session asked to exit.
+    pub async fn process_command(&mut self, command: Command) -> anyhow::Result<(Output, bool)> {
+        if command == Command::Exit {
+            return Ok((self.f_exit().into(), true));
+        }
+
+        if self.transaction_expired {
+            self.transaction_expired = false;
+
+            if command == Command::Rollback {
+                return Ok((String::from("Rolled back transaction\n\r").into(), false));
+            }
+
+            bail!(DbError::FailedPrecondition(
+                "the transaction was idle for too long and was rolled back, the command didn't run"
+                    .into()
+            ));
+        }
+
+        if let Some(transaction) = &mut self.transaction {
+            transaction.last_used = Instant::now();
+        }
+
+        // Only documents are written by transactions
+        if self.transaction.is_some()
+            && matches!(
+                command,
+                Command::CreateDatabase { .. }
+                    | Command::CreateCollection { .. }
+                    | Command::DropDatabase { .. }
+                    | Command::DropCollection { .. }
+                    | Command::CreateIndex { .. }
+                    | Command::DropIndex { .. }
+                    | Command::Use { .. }
+                    | Command::Vacuum { .. }
+                    | Command::CreateUser { .. }
+                    | Command::DropUser { .. }
+            )
+        {
+            bail!(DbError::FailedPrecondition(
+                "this command can't run inside a transaction, commit or roll it back first".into()
+            ));
+        }
+
+        let output: Output = match command {
+            Command::CreateDatabase { name } => self.f_create_db(&name).await?.into(),
+            Command::CreateCollection { name } => self.f_create_collection(&name).await?.into(),
+            Command::DropDatabase { name } => self.f_drop_db(&name).await?.into(),
+            Command::DropCollection { name } => self.f_drop_collection(&name).await?.into(),
+            Command::CreateIndex {
+                collection,
+                definition,
+            } => self.f_create_index(&collection, definition).await?.into(),
+            Command::DropIndex { collection, name } => {
+                self.f_drop_index(&collection, &name).await?.into()
+            }
+            Command::Use { name } => self.f_use(&name).await?.into(),
+            Command::ShowDatabases => self.f_show_dbs().await?,
+            Command::ShowCollections { database } => {
+                self.f_show_collections(database.as_deref()).await?
+            }
+            Command::ShowIndexes { collection } => self.f_show_indexes(&collection).await?,
+            Command::Vacuum { collection } => self.f_vacuum(&collection).await?.into(),
+            Command::CreateUser { name, password } => {
+                self.f_create_user(&name, &password).await?.into()
+            }
+            Command::DropUser { name } => self.f_drop_user(&name).await?.into(),
+            Command::Help => self.f_help().into(),
+            Command::Exit => unreachable!("handled above"),
+            Command::Begin => self.f_begin().await?.into(),
+            Command::Commit => self.f_commit().await?.into(),
+            Command::Rollback => self.f_rollback()?.into(),
+            Command::Next { cursor } => Output::batch(self.cursors.next(cursor)?),
+            Command::Close { cursor } => self.f_close(cursor)?.into(),
+            Command::Insert {
+                collection,
+                document,
+            } => self.f_insert(&collection, document).await?.into(),
+            Command::Find {
+                collection,
+                filter,
+                batch,
+            } => self.f_find(&collection, filter.as_ref(), batch).await?,
+            Command::Update {
+                collection,
+                update,
+                filter,
+            } => self
+                .f_update(&collection, &update, filter.as_ref())
+                .await?
+                .into(),
+            Command::Delete { collection, filter } => {
+                self.f_delete(&collection, filter.as_ref()).await?.into()
+            }
         };
 
-        Ok((result, false))
+        Ok((output, false))
     }
 
-    // TODO: add path management and fix name files
-    async fn f_create(&mut self, mut token_list: TokenList<'_>) -> anyhow::Result<String> {
-        token_list.next(1);
+    /// Rolls back the transaction if it was left unused for `timeout`, returning whether
+    /// it was.
+    pub fn expire_transaction(&mut self, timeout: Duration) -> bool {
+        if self
+            .transaction
+            .as_ref()
+            .is_none_or(|transaction| transaction.last_used.elapsed() < timeout)
+        {
+            return false;
+        }
 
-        let created_type: String = match token_list.current_token.tok_type {
-            TokenType::Db => {
-                token_list.next(1);
+        self.transaction = None;
+        self.transaction_expired = true;
 
-                let name: &str = token_list.current_token.slice;
+        true
+    }
 
-                if fs::read_dir(format!("{}/{}", self.config.store_path, name))
-                    .await
-                    .is_ok()
-                {
-                    return Ok(format!("Database \"{name}\" already exists\n\r"));
-                }
+    async fn f_create_db(&self, name: &str) -> anyhow::Result<String> {
+        self.create_database(name).await?;
 
-                fs::create_dir_all(format!("{}/{}", self.config.store_path, name)).await?;
+        Ok(format!("Created database \"{name}\"\n\r"))
+    }
 
-                "Created database \"".into()
-            }
-            TokenType::Collection => {
-                if self.name.is_empty() {
-                    return Ok(String::from(
-                        "Error: no database provided. Select one with \"use <name>\"\n\r",
-                    ));
-                }
+    pub async fn create_database(&self, name: &str) -> anyhow::Result<()> {
+        let path: String = format!("{}/{}", self.config.store_path, name);
+
+        if fs::read_dir(&path).await.is_ok() {
+            bail!(DbError::AlreadyExists(format!(
+                "database \"{name}\" already exists"
+            )));
+        }
+
+        Wal::open(Path::new(&self.config.store_path))
+            .await?
+            .append(&Record::CreateDatabase {
+                name: name.to_string(),
+            })
+            .await?;
+
+        fs::create_dir_all(&path).await?;
+
+        store::blocking(move || Catalog::create(Path::new(&path))).await?;
+
+        Ok(())
+    }
+
+    async fn f_create_collection(&self, name: &str) -> anyhow::Result<String> {
+        self.create_collection(name).await?;
+
+        Ok(format!("Created collection \"{name}\"\n\r"))
+    }
+
+    pub async fn create_collection(&self, name: &str) -> anyhow::Result<()> {
+        let database: SharedDatabase = self.database().await?;
+        let _writer = database.writer.lock().await;
+        let mut collections = database.collections.clone().write_owned().await;
+        let mut catalog = database.catalog.clone().lock_owned().await;
+
+        let path: String = format!("{}/{}", self.path, name);
+
+        if catalog.collection(name).is_some() {
+            bail!(DbError::AlreadyExists(format!(
+                "collection \"{name}\" already exists"
+            )));
+        }
+
+        database
+            .wal
+            .append(&Record::CreateCollection {
+                name: name.to_string(),
+            })
+            .await?;
+
+        // A directory the catalog doesn't list is left over from a collection that was
+        // dropped before its removal finished
+        wal::remove_dir_all(Path::new(&path)).await?;
+        fs::create_dir_all(&path).await?;
+
+        let clock: Arc<Clock> = database.clock.clone();
+        let name: String = name.to_string();
+
+        store::blocking(move || {
+            let mut collection: Collection = Collection::open(name.clone(), path, vec![])?;
 
-                token_list.next(1);
+            // Snapshots taken before don't see it
+            collection.created = clock.tick();
+
+            collections.insert(name.clone(), collection);
+
+            catalog.add_collection(&name)
+        })
+        .await
+    }
+
+    async fn f_show_dbs(&self) -> anyhow::Result<Output> {
+        Ok(Output::rows(self.databases().await?, "No databases found"))
+    }
+
+    /// The names of the databases in the store, without the system database.
+    pub async fn databases(&self) -> anyhow::Result<Vec<String>> {
+        let mut names: Vec<String> = vec![];
+
+        let mut entries: fs::ReadDir = fs::read_dir(&self.config.store_path).await?;
+
+        while let Some(db_entry) = entries.next_entry().await? {
+            let db_path: PathBuf = db_entry.path();
+
+            if db_entry.file_type().await?.is_dir() {
+                let name: &str = db_path
+                    .file_name()
+                    .ok_or(anyhow::anyhow!("Invalid filename"))?
+                    .to_str()
+                    .ok_or(anyhow::anyhow!("Invalid UTF-8"))?;
+
+                if name != users::SYSTEM_DATABASE && !name.starts_with(wal::DROPPED_PREFIX) {
+                    names.push(name.to_string());
+                }
+            }
+        }
 
-                let name: &str = token_list.current_token.slice;
+        Ok(names)
+    }
 
-                fs::create_dir_all(format!("{}/{}/{}", self.config.store_path, self.name, name))
-                    .await?;
+    async fn f_show_collections(&self, database: Option<&str>) -> anyhow::Result<Output> {
+        let collections: BTreeMap<String, CollectionEntry> = self.collections(database).await?;
 
-                self.collections.insert(Collection::new(
-                    name.to_string(),
-                    name.to_string(),
-                    vec![],
-                ));
+        Ok(Output::rows(
+            collections
+                .into_iter()
+                .map(|(name, entry)| format!("{name} (created {})", entry.created_at)),
+            "No collections found",
+        ))
+    }
 
-                "Created collection \"".into()
+    /// The catalog entries of the collections of `database`, or of the current database.
+    pub async fn collections(
+        &self,
+        database: Option<&str>,
+    ) -> anyhow::Result<BTreeMap<String, CollectionEntry>> {
+        let name: &str = match database {
+            Some(name) => name,
+            None if !self.name.is_empty() => &self.name,
+            None => {
+                bail!(DbError::FailedPrecondition(
+                    "no database provided. Use \"show <name>\" or select one with \"use <name>\""
+                        .into()
+                ))
             }
-            _ => "You need to specify either \"db\" or \"collection\" before \"".into(),
         };
 
+        let path: String = format!("{}/{}", self.config.store_path, name);
+
+        let database: SharedDatabase = self.store.open(name, &path).await?;
+
+        let collections: BTreeMap<String, CollectionEntry> =
+            database.catalog.lock().await.collections.clone();
+
+        Ok(collections)
+    }
+
+    async fn f_show_indexes(&self, collection_name: &str) -> anyhow::Result<Output> {
+        let indexes: Vec<IndexDefinition> = self.indexes(collection_name).await?;
+
+        Ok(Output::rows(
+            iter::once(format!("{ID_FIELD} ({ID_FIELD}) unique"))
+                .chain(indexes.into_iter().map(|definition| definition.to_string())),
+            "No indexes found",
+        ))
+    }
+
+    /// The secondary indexes of a collection, without the one on `_id` every collection has.
+    pub async fn indexes(&self, collection_name: &str) -> anyhow::Result<Vec<IndexDefinition>> {
+        let database: SharedDatabase = self.database().await?;
+        database.load(collection_name).await?;
+
+        let collections = database.collections.read().await;
+
+        let collection: &Collection = collections
+            .get(collection_name)
+            .ok_or_else(|| no_such_collection(collection_name))?;
+
+        Ok(collection.indexes().cloned().collect())
+    }
+
+    async fn f_vacuum(&self, collection_name: &str) -> anyhow::Result<String> {
+        let reclaimed: u64 = self.database().await?.vacuum(collection_name).await?;
+
         Ok(format!(
-            "{}{}\"\n\r",
-            created_type, token_list.current_token.slice
+            "Vacuumed \"{collection_name}\", reclaimed {reclaimed} bytes\n\r"
         ))
     }
 
-    async fn f_show(&self, mut token_list: TokenList<'_>) -> anyhow::Result<String> {
-        token_list.next(1);
+    async fn f_create_user(&self, name: &str, password: &str) -> anyhow::Result<String> {
+        users::create(self, name, password).await?;
 
-        let mut output_stream = String::new();
+        Ok(format!("Created user \"{name}\"\n\r"))
+    }
 
-        match token_list.current_token.tok_type {
-            TokenType::Dbs => {
-                let mut entries: fs::ReadDir = fs::read_dir(&self.config.store_path).await?;
+    async fn f_drop_user(&self, name: &str) -> anyhow::Result<String> {
+        users::remove(self, name).await?;
 
-                while let Some(db_entry) = entries.next_entry().await? {
-                    let db_path: PathBuf = db_entry.path();
+        Ok(format!("Dropped user \"{name}\"\n\r"))
+    }
 
-                    if db_path.is_dir() {
-                        let name: &str = db_path
-                            .file_name()
-                            .ok_or(anyhow::anyhow!("Invalid filename"))?
-                            .to_str()
-                            .ok_or(anyhow::anyhow!("Invalid UTF-8"))?;
+    async fn f_drop_db(&mut self, name: &str) -> anyhow::Result<String> {
+        self.drop_database(name).await?;
 
-                        output_stream.push_str(format!("{name}\n\r").as_str());
-                    }
-                }
+        Ok(format!("Dropped database \"{name}\"\n\r"))
+    }
 
-                if output_stream.is_empty() {
-                    output_stream = String::from("No databases found\n\r");
-                }
-            }
-            TokenType::Identifier(_) => {
-                let name: &str = token_list.current_token.slice;
+    /// Deletes a database, leaving it first if this session is using it.
+    pub async fn drop_database(&mut self, name: &str) -> anyhow::Result<()> {
+        let path: String = format!("{}/{}", self.config.store_path, name);
 
-                let is_error: String = self.read_names(&mut output_stream, name).await?;
+        self.store
+            .drop_database(name, &path, Path::new(&self.config.store_path))
+            .await?;
 
-                if !is_error.is_empty() {
-                    return Ok(is_error);
-                }
+        if self.name == name {
+            self.name = String::new();
+            self.path = String::new();
 
-                if output_stream.is_empty() {
-                    output_stream = String::from("No collections found\n\r");
-                }
-            }
-            _ => {
-                if self.name.is_empty() {
-                    return Ok(String::from("Error: invalid syntax\n\r"));
-                }
-            }
+            self.current_collection = 0;
         }
 
-        Ok(output_stream[..output_stream.len() - 1].into())
+        Ok(())
     }
 
-    async fn f_drop(&mut self, mut token_list: TokenList<'_>) -> anyhow::Result<String> {
-        token_list.next(1);
+    async fn f_drop_collection(&self, name: &str) -> anyhow::Result<String> {
+        self.drop_collection(name).await?;
 
-        let config_store_path: &String = &self.config.store_path;
+        Ok(format!("Dropped collection \"{name}\"\n\r"))
+    }
 
-        let output_stream: String = match token_list.current_token.tok_type {
-            TokenType::Db => {
-                token_list.next(1);
+    pub async fn drop_collection(&self, name: &str) -> anyhow::Result<()> {
+        let database: SharedDatabase = self.database().await?;
+        let _writer = database.writer.lock().await;
+        let mut collections = database.collections.clone().write_owned().await;
+        let mut catalog = database.catalog.clone().lock_owned().await;
 
-                fs::remove_dir_all(format!(
-                    "{}/{}",
-                    config_store_path, token_list.current_token.slice
-                ))
-                .await?;
+        if catalog.collection(name).is_none() {
+            bail!(no_such_collection(name));
+        }
 
-                self.name = String::new();
-                self.path = String::new();
+        database
+            .wal
+            .append(&Record::DropCollection {
+                name: name.to_string(),
+            })
+            .await?;
 
-                self.current_collection = 0;
-                self.collections = HashSet::new();
+        let removed: String = name.to_string();
 
-                format!(
-                    "Dropped database \"{}\"\n\r",
-                    token_list.current_token.slice
-                )
-            }
-            TokenType::Collection => {
-                token_list.next(1);
-
-                self.collections.remove(&Collection::new(
-                    token_list.current_token.slice.to_string(),
-                    self.name.clone(),
-                    vec![],
-                ));
-
-                fs::remove_dir_all(format!(
-                    "{}/{}/{}",
-                    config_store_path, self.name, token_list.current_token.slice
-                ))
-                .await?;
+        store::blocking(move || {
+            collections.remove(&removed);
+            catalog.remove_collection(&removed)
+        })
+        .await?;
 
-                format!(
-                    "Dropped collection \"{}\"\n\r",
-                    token_list.current_token.slice
-                )
-            }
-            _ => String::from("Error: invalid syntax\n\r"),
-        };
+        wal::remove_dir_all(Path::new(&format!("{}/{}", self.path, name))).await?;
+
+        Ok(())
+    }
+
+    async fn f_create_index(
+        &self,
+        collection_name: &str,
+        definition: IndexDefinition,
+    ) -> anyhow::Result<String> {
+        let name: String = definition.name.clone();
+
+        self.create_index(collection_name, definition).await?;
 
-        Ok(output_stream)
+        Ok(format!(
+            "Created index \"{name}\" on \"{collection_name}\"\n\r"
+        ))
+    }
+
+    pub async fn create_index(
+        &self,
+        collection_name: &str,
+        definition: IndexDefinition,
+    ) -> anyhow::Result<()> {
+        let database: SharedDatabase = self.database().await?;
+        database.load(collection_name).await?;
+
+        let _writer = database.writer.lock().await;
+        let collections = database.collections.clone().write_owned().await;
+        let name: String = collection_name.to_string();
+        let checked: IndexDefinition = definition.clone();
+
+        let mut collections = store::blocking(move || {
+            collections
+                .get(&name)
+                .ok_or_else(|| no_such_collection(&name))?
+                .check_index(&checked)?;
+
+            Ok(collections)
+        })
+        .await?;
+
+        database
+            .wal
+            .append(&Record::CreateIndex {
+                collection: collection_name.to_string(),
+                definition: definition.clone(),
+            })
+            .await?;
+
+        let mut catalog = database.catalog.clone().lock_owned().await;
+        let name: String = collection_name.to_string();
+
+        store::blocking(move || {
+            collections
+                .get_mut(&name)
+                .ok_or_else(|| no_such_collection(&name))?
+                .create_index(definition.clone())?;
+
+            catalog.add_index(&name, definition)
+        })
+        .await
+    }
+
+    async fn f_drop_index(&self, collection_name: &str, name: &str) -> anyhow::Result<String> {
+        self.drop_index(collection_name, name).await?;
+
+        Ok(format!(
+            "Dropped index \"{name}\" on \"{collection_name}\"\n\r"
+        ))
+    }
+
+    pub async fn drop_index(&self, collection_name: &str, name: &str) -> anyhow::Result<()> {
+        let database: SharedDatabase = self.database().await?;
+        database.load(collection_name).await?;
+
+        let _writer = database.writer.lock().await;
+        let mut collections = database.collections.clone().write_owned().await;
+
+        let collection: &mut Collection = collections
+            .get_mut(collection_name)
+            .ok_or_else(|| no_such_collection(collection_name))?;
+
+        if collection.index(name).is_none() {
+            bail!(DbError::NotFound(format!(
+                "no such index \"{name}\" on \"{collection_name}\""
+            )));
+        }
+
+        database
+            .wal
+            .append(&Record::DropIndex {
+                collection: collection_name.to_string(),
+                name: name.to_string(),
+            })
+            .await?;
+
+        let mut catalog = database.catalog.clone().lock_owned().await;
+        let collection_name: String = collection_name.to_string();
+        let name: String = name.to_string();
+
+        store::blocking(move || {
+            collections
+                .get_mut(&collection_name)
+                .ok_or_else(|| no_such_collection(&collection_name))?
+                .drop_index(&name)?;
+
+            catalog.remove_index(&collection_name, &name)
+        })
+        .await
     }
 
     #[allow(clippy::unused_self)]
@@ -246,163 +642,561 @@ impl Database {
          DROP COLLECTION <collection_name>   - Deletes a collection from the current database.\n\r\
          USE <database_name>                 - Switches the current context to the specified database.\n\r\
          SHOW DBS                            - Lists all available databases.\n\r\
-         SHOW <database_name>                - Lists all collections within the specified database. (Currently needs the db name even if you are using one)\n\r\
+         SHOW [database_name]                - Lists all collections within the specified or current database.\n\r\
+         CREATE [UNIQUE] INDEX <index_name> ON <collection_name>(<field>, ...) - Indexes the documents of a collection by the given fields.\n\r\
+         DROP INDEX <index_name> ON <collection_name> - Deletes an index.\n\r\
+         SHOW INDEXES <collection_name>      - Lists the indexes of a collection.\n\r\
+         VACUUM <collection_name>            - Rewrites the files of a collection without their unused space.\n\r\
+         CREATE USER <user_name> PASSWORD \"<password>\" - Creates a user. Once there is one, clients have to log in when they connect.\n\r\
+         DROP USER <user_name>               - Deletes a user and ends its logins.\n\r\
+         INSERT INTO <collection_name> {...} - Inserts a document into a collection of the current database.\n\r\
+         FIND <collection_name> [WHERE ...] [BATCH <size>] - Lists the documents matching the filter, with BATCH only the first <size> of them and a cursor holding the rest.\n\r\
+         NEXT <cursor>                       - Lists the next batch of documents of a cursor.\n\r\
+         CLOSE <cursor>                      - Discards the documents left in a cursor.\n\r\
+         UPDATE <collection_name> [SET <field> = <value>, ...] [UNSET <field>, ...] [INC <field> <amount>, ...] [WHERE ...]\n\r\
+         DELETE FROM <collection_name> [WHERE ...] - Deletes the matching documents.\n\r\
+         BEGIN                               - Starts a transaction: the following writes are applied together by COMMIT.\n\r\
+         COMMIT                              - Applies the writes of the transaction.\n\r\
+         ROLLBACK                            - Discards the writes of the transaction.\n\r\
          HELP                                - Shows this help message.\n\r\
-         EXIT                                - Exits the program.\n\r\
+         EXIT | QUIT                         - Closes the command stream.\n\r\
          \n\r\
-         Unavailable commands (coming soon): DELETE, INSERT, UPDATE, FIND\n\r\
+         Filters compare fields with =, !=, <, <=, >, >=, IN [...] or EXISTS and can be combined with AND and OR.\n\r\
+         Keywords are case-insensitive, database, collection and field names are not.\n\r\
+         Several statements can be sent at once by separating them with \";\".\n\r\
          "    )
     }
 
-    async fn f_use(&mut self, mut token_list: TokenList<'_>) -> anyhow::Result<String> {
-        token_list.next(1);
+    /// Leaves the current database, so nothing of this session's state outlives the stream.
+    /// A transaction still open is rolled back, and the client told so.
+    fn f_exit(&mut self) -> String {
+        let rolled_back: bool = self.transaction.take().is_some();
 
-        let config_store_path: &String = &self.config.store_path;
-        let requested_db_name = token_list.current_token.slice;
-        let path_string: String = format!("{config_store_path}/{requested_db_name}");
-        let path: &Path = Path::new(&path_string);
+        self.transaction_expired = false;
+        self.cursors.clear();
+        self.name = String::new();
+        self.path = String::new();
+        self.current_collection = 0;
 
-        // 1. Check if the database directory exists asynchronously
-        if let Ok(metadata) = tokio::fs::metadata(path).await {
-            if metadata.is_dir() {
-                let name: &str = path
-                    .file_name()
-                    .ok_or(anyhow::anyhow!("Invalid filename"))?
-                    .to_str()
-                    .ok_or(anyhow::anyhow!("Invalid UTF-8"))?;
+        if rolled_back {
+            return String::from("Rolled back the open transaction\n\rBye!\n\r");
+        }
+
+        String::from("Bye!\n\r")
+    }
 
-                if name == requested_db_name {
-                    self.path = path
-                        .to_str()
-                        .ok_or(anyhow::anyhow!("Invalid UTF-8"))?
-                        .to_string();
-
-                    self.current_collection = 0;
-                    self.name = name.to_string();
-
-                    let mut db_entries: fs::ReadDir = tokio::fs::read_dir(&self.path).await?;
-                    let mut collections: HashSet<Collection> = HashSet::new();
-
-                    while let Some(db_entry) = db_entries.next_entry().await? {
-                        let db_path: PathBuf = db_entry.path();
-
-                        if db_entry.file_type().await?.is_dir() {
-                            let collection_name: String = db_path
-                                .file_name()
-                                .ok_or(anyhow::anyhow!("Invalid filename"))?
-                                .to_str()
-                                .ok_or(anyhow::anyhow!("Invalid UTF-8"))?
-                                .to_string();
-
-                            let (tx, mut rx): (mpsc::Sender<Document>, mpsc::Receiver<Document>) =
-                                mpsc::channel(4);
-
-                            let wg: WaitGroup = WaitGroup::new();
-
-                            let mut doc_entries: fs::ReadDir =
-                                tokio::fs::read_dir(&db_path).await?;
-                            let mut has_files: bool = false;
-
-                            while let Some(doc_entry) = doc_entries.next_entry().await? {
-                                let doc_path: PathBuf = doc_entry.path();
-
-                                if doc_entry.file_type().await?.is_file() {
-                                    has_files = true;
-
-                                    let tx: mpsc::Sender<Document> = tx.clone();
-                                    let worker: waitgroup::Worker = wg.worker();
-
-                                    task::spawn(async move {
-                                        if let Some(os_name) = doc_path.file_name() {
-                                            if let Some(name_str) = os_name.to_str() {
-                                                let document_name = name_str
-                                                    .split('.')
-                                                    .next()
-                                                    .ok_or(anyhow::anyhow!("Invalid UTF-8"))?
-                                                    .to_string();
-
-                                                tx.send(Document::new(
-                                                    document_name,
-                                                    doc_path
-                                                        .to_str()
-                                                        .ok_or(anyhow::anyhow!("Invalid UTF-8"))?
-                                                        .to_string(),
-                                                ))
-                                                .await?;
-                                            }
-                                        }
-
-                                        drop(worker);
-                                        Ok::<(), anyhow::Error>(())
-                                    });
-                                }
-                            }
-
-                            // 4. Only process results if we actually found files
-                            if has_files {
-                                wg.wait().await;
-                                drop(tx); // Close the channel so the loop below terminates
-
-                                let mut documents: Vec<Document> = vec![];
-
-                                // Note: This loop blocks the thread if the channel is slow,
-                                // but since we awaited 'wg', the buffer should be ready or closed.
-                                while let Some(document) = rx.recv().await {
-                                    documents.push(document);
-                                }
-
-                                let collection: Collection = Collection::new(
-                                    collection_name,
-                                    db_path
-                                        .to_str()
-                                        .ok_or(anyhow::anyhow!("Invalid UTF-8"))?
-                                        .to_string(),
-                                    documents,
-                                );
-
-                                collections.insert(collection);
-                            }
-                        }
+    async fn f_insert(
+        &mut self,
+        collection_name: &str,
+        fields: BTreeMap<String, Value>,
+    ) -> anyhow::Result<String> {
+        let ids: Vec<String> = self.insert(collection_name, vec![fields]).await?;
+
+        Ok(format!(
+            "Inserted document \"{}\" into \"{collection_name}\"\n\r",
+            ids[0]
+        ))
+    }
+
+    /// Inserts documents built from `documents`, all or none of them, and returns the
+    /// `_id` each one was given.
+    pub async fn insert(
+        &mut self,
+        collection_name: &str,
+        documents: Vec<BTreeMap<String, Value>>,
+    ) -> anyhow::Result<Vec<String>> {
+        let database: SharedDatabase = self.database().await?;
+        database.load(collection_name).await?;
+
+        let mut ids: Vec<String> = vec![];
+        let mut bodies: Vec<Value> = vec![];
+
+        for fields in documents {
+            if fields.contains_key(ID_FIELD) {
+                bail!(DbError::InvalidArgument(format!(
+                    "\"{ID_FIELD}\" is assigned by the database"
+                )));
+            }
+
+            let id: String = object_id::generate();
+            let body: Value = Document::new_body(&id, fields);
+
+            document::encode(&body)?;
+
+            ids.push(id);
+            bodies.push(body);
+        }
+
+        if let Some(transaction) = &mut self.transaction {
+            transaction.write(collection_name, bodies).await?;
+
+            return Ok(ids);
+        }
+
+        let _writer = database.writer.lock().await;
+
+        let collections = database.collections.clone().read_owned().await;
+        let name: String = collection_name.to_string();
+
+        let bodies: Vec<Value> = store::blocking(move || {
+            let collection: &Collection = collections
+                .get(&name)
+                .ok_or_else(|| no_such_collection(&name))?;
+
+            // Checked before logging, so the log never holds a write that can't be applied
+            collection.check_writes(
+                &bodies.iter().map(|body| (None, body)).collect::<Vec<_>>(),
+                &[],
+            )?;
+
+            Ok(bodies)
+        })
+        .await?;
+
+        if !bodies.is_empty() {
+            database
+                .commit(vec![Record::Write {
+                    collection: collection_name.to_string(),
+                    documents: bodies,
+                }])
+                .await?;
+        }
+
+        Ok(ids)
+    }
+
+    async fn f_find(
+        &mut self,
+        collection_name: &str,
+        filter: Option<&Filter>,
+        batch: Option<usize>,
+    ) -> anyhow::Result<Output> {
+        let Some(batch_size) = batch else {
+            return Ok(Output::streamed(
+                self.find_streamed(collection_name, filter).await?,
+                "No documents found",
+            ));
+        };
+
+        let bodies: Vec<Value> = self.find(collection_name, filter).await?;
+
+        Ok(Output::batch(self.cursors.open(bodies, batch_size)?))
+    }
+
+    /// The bodies `find` gives, as lines sent by a task reading them a part at a time.
+    /// Within a transaction they are all read first, to see its own writes.
+    async fn find_streamed(
+        &self,
+        collection_name: &str,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<mpsc::Receiver<anyhow::Result<String>>> {
+        let (tx, rx) = mpsc::channel(PART_SIZE);
+
+        if self.transaction.is_some() {
+            let bodies: Vec<Value> = self.find(collection_name, filter).await?;
+
+            tokio::spawn(async move {
+                for body in bodies {
+                    if tx.send(Ok(body.to_string())).await.is_err() {
+                        return;
                     }
-                    self.collections = collections;
                 }
-            } else {
-                // Path exists but is not a directory
-                return Ok(String::from("Error: database not found\n\r"));
+            });
+
+            return Ok(rx);
+        }
+
+        let database: SharedDatabase = self.database().await?;
+        database.load(collection_name).await?;
+
+        let snapshot: Snapshot = database.clock.snapshot();
+        let name: String = collection_name.to_string();
+        let filter: Option<Filter> = filter.cloned();
+
+        // The first part is read here, so a missing collection fails the statement itself
+        let (mut part, mut position): (Vec<Value>, Position) =
+            read_part(&database, &name, filter.as_ref(), snapshot.at, None).await?;
+
+        tokio::spawn(async move {
+            // Kept until the last part is read
+            let snapshot: Snapshot = snapshot;
+
+            loop {
+                for body in part {
+                    if tx.send(Ok(body.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+
+                if position.done {
+                    return;
+                }
+
+                (part, position) = match read_part(
+                    &database,
+                    &name,
+                    filter.as_ref(),
+                    snapshot.at,
+                    Some(position),
+                )
+                .await
+                {
+                    Ok(next) => next,
+                    Err(err) => {
+                        let _ = tx.send(Err(err)).await;
+
+                        return;
+                    }
+                };
             }
-        } else {
-            // Path does not exist
-            return Ok(String::from("Error: database not found\n\r"));
+        });
+
+        Ok(rx)
+    }
+
+    /// The bodies of the documents matching `filter`, as the transaction sees them if
+    /// there is one.
+    pub async fn find(
+        &self,
+        collection_name: &str,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<Vec<Value>> {
+        if let Some(transaction) = &self.transaction {
+            return transaction.matching(collection_name, filter).await;
         }
 
-        Ok(format!("Using database: {}\n\r", self.name))
+        let database: SharedDatabase = self.database().await?;
+        database.load(collection_name).await?;
+
+        // Read in parts at a snapshot, so writers get in between parts
+        let snapshot: Snapshot = database.clock.snapshot();
+        let mut position: Option<Position> = None;
+        let mut bodies: Vec<Value> = vec![];
+
+        loop {
+            let (part, next): (Vec<Value>, Position) =
+                read_part(&database, collection_name, filter, snapshot.at, position).await?;
+
+            bodies.extend(part);
+
+            if next.done {
+                return Ok(bodies);
+            }
+
+            position = Some(next);
+        }
     }
 
-    // Utilities
-    async fn read_names(&self, output_stream: &mut String, name: &str) -> anyhow::Result<String> {
-        let dir_res: Result<fs::ReadDir, io::Error> =
-            tokio::fs::read_dir(format!("{}/{}", self.config.store_path, name)).await;
+    async fn f_update(
+        &mut self,
+        collection_name: &str,
+        update: &Update,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<String> {
+        let (matched, modified): (usize, usize) =
+            self.update(collection_name, update, filter).await?;
 
-        let mut dir: fs::ReadDir = match dir_res {
-            Ok(dir) => dir,
-            Err(_) => return Ok(String::from("Error: no such database\n\r")),
-        };
+        Ok(format!(
+            "Matched {matched} documents, modified {modified}\n\r"
+        ))
+    }
 
-        while let Some(db_entry) = dir.next_entry().await? {
-            let db_path: PathBuf = db_entry.path();
+    /// Applies `update` to the documents matching `filter` and returns how many matched
+    /// and how many it changed.
+    pub async fn update(
+        &mut self,
+        collection_name: &str,
+        update: &Update,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<(usize, usize)> {
+        // The parser rejects these too, but not the typed service
+        if update.modifications.iter().any(|modification| {
+            modification
+                .path()
+                .first()
+                .is_some_and(|key| key == ID_FIELD)
+        }) {
+            bail!(DbError::InvalidArgument(format!(
+                "\"{ID_FIELD}\" cannot be modified"
+            )));
+        }
 
-            if db_entry.file_type().await?.is_dir() {
-                let name: &str = db_path
-                    .file_name()
-                    .ok_or(anyhow::anyhow!("Invalid filename"))?
-                    .to_str()
-                    .ok_or(anyhow::anyhow!("Invalid UTF-8"))?;
+        if let Some(transaction) = &mut self.transaction {
+            let mut matched: usize = 0;
+            let mut changes: Vec<Value> = vec![];
+
+            for body in transaction.matching(collection_name, filter).await? {
+                matched += 1;
 
-                output_stream.push_str(name);
-                output_stream.push_str("\n\r");
+                if let Some(body) = updated(update, &body)? {
+                    changes.push(body);
+                }
             }
+
+            let modified: usize = changes.len();
+
+            transaction.write(collection_name, changes).await?;
+
+            return Ok((matched, modified));
         }
 
-        Ok(String::new())
+        let database: SharedDatabase = self.database().await?;
+        database.load(collection_name).await?;
+
+        let _writer = database.writer.lock().await;
+
+        let collections = database.collections.clone().read_owned().await;
+        let name: String = collection_name.to_string();
+        let update: Update = update.clone();
+        let filter: Option<Filter> = filter.cloned();
+
+        let (matched, changes): (usize, Vec<(RecordId, Value)>) = store::blocking(move || {
+            let collection: &Collection = collections
+                .get(&name)
+                .ok_or_else(|| no_such_collection(&name))?;
+
+            let mut matched: usize = 0;
+            let mut changes: Vec<(RecordId, Value)> = vec![];
+
+            // Every document is updated on a copy first, so a failing modification leaves
+            // the whole collection untouched
+            for document in collection.matching(filter.as_ref())? {
+                matched += 1;
+
+                if let Some(body) = updated(&update, &document.body)? {
+                    changes.push((document.location, body));
+                }
+            }
+
+            collection.check_writes(
+                &changes
+                    .iter()
+                    .map(|(location, body)| (Some(*location), body))
+                    .collect::<Vec<_>>(),
+                &[],
+            )?;
+
+            Ok((matched, changes))
+        })
+        .await?;
+
+        let modified: usize = changes.len();
+
+        if modified > 0 {
+            database
+                .commit(vec![Record::Write {
+                    collection: collection_name.to_string(),
+                    documents: changes.into_iter().map(|(_, body)| body).collect(),
+                }])
+                .await?;
+        }
+
+        Ok((matched, modified))
+    }
+
+    async fn f_delete(
+        &mut self,
+        collection_name: &str,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<String> {
+        let deleted: usize = self.delete(collection_name, filter).await?;
+
+        Ok(format!("Deleted {deleted} documents\n\r"))
+    }
+
+    /// Deletes the documents matching `filter` and returns how many there were.
+    pub async fn delete(
+        &mut self,
+        collection_name: &str,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<usize> {
+        if let Some(transaction) = &mut self.transaction {
+            let ids: Vec<String> = transaction
+                .matching(collection_name, filter)
+                .await?
+                .iter()
+                .map(|body| document::id_of(body).map(String::from))
+                .collect::<anyhow::Result<_>>()?;
+
+            let deleted: usize = ids.len();
+
+            transaction.delete(collection_name, ids).await?;
+
+            return Ok(deleted);
+        }
+
+        let database: SharedDatabase = self.database().await?;
+        database.load(collection_name).await?;
+
+        let _writer = database.writer.lock().await;
+
+        let collections = database.collections.clone().read_owned().await;
+        let name: String = collection_name.to_string();
+        let filter: Option<Filter> = filter.cloned();
+
+        let ids: Vec<String> = store::blocking(move || {
+            Ok(collections
+                .get(&name)
+                .ok_or_else(|| no_such_collection(&name))?
+                .matching(filter.as_ref())?
+                .into_iter()
+                .map(|document| document.id)
+                .collect())
+        })
+        .await?;
+
+        let deleted: usize = ids.len();
+
+        if deleted > 0 {
+            database
+                .commit(vec![Record::Delete {
+                    collection: collection_name.to_string(),
+                    ids,
+                }])
+                .await?;
+        }
+
+        Ok(deleted)
+    }
+
+    async fn f_begin(&mut self) -> anyhow::Result<String> {
+        if self.transaction.is_some() {
+            bail!(DbError::FailedPrecondition(
+                "a transaction is already in progress".into()
+            ));
+        }
+
+        self.transaction = Some(Transaction::new(self.database().await?));
+
+        Ok(String::from("Started transaction\n\r"))
+    }
+
+    async fn f_commit(&mut self) -> anyhow::Result<String> {
+        let Some(transaction) = self.transaction.take() else {
+            bail!(no_transaction());
+        };
+
+        let written: usize = transaction.commit().await.map_err(|err| {
+            DbError::from(err)
+                .map_message(|message| format!("{message}, the transaction was rolled back"))
+        })?;
+
+        Ok(format!(
+            "Committed transaction, wrote {written} documents\n\r"
+        ))
+    }
+
+    fn f_close(&mut self, cursor: u64) -> anyhow::Result<String> {
+        self.cursors.close(cursor)?;
+
+        Ok(format!("Closed cursor {cursor}\n\r"))
+    }
+
+    fn f_rollback(&mut self) -> anyhow::Result<String> {
+        if self.transaction.take().is_none() {
+            bail!(no_transaction());
+        }
+
+        Ok(String::from("Rolled back transaction\n\r"))
+    }
+
+    async fn f_use(&mut self, name: &str) -> anyhow::Result<String> {
+        self.use_database(name).await?;
+
+        Ok(format!("Using database: {}\n\r", self.name))
     }
+
+    /// Makes `name` the database the following commands work in.
+    pub async fn use_database(&mut self, name: &str) -> anyhow::Result<()> {
+        let path: String = format!("{}/{}", self.config.store_path, name);
+
+        self.store.open(name, &path).await?;
+
+        self.name = name.to_string();
+        self.path = path;
+        self.current_collection = 0;
+
+        Ok(())
+    }
+
+    // Utilities
+    fn require_database(&self) -> anyhow::Result<()> {
+        if self.name.is_empty() {
+            bail!(DbError::FailedPrecondition(
+                "no database provided. Select one with \"use <name>\"".into()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The database this session is using.
+    async fn database(&self) -> anyhow::Result<SharedDatabase> {
+        self.require_database()?;
+
+        self.store.open(&self.name, &self.path).await
+    }
+}
+
+/// A copy of `body` with `update` applied, or `None` if it doesn't change anything.
+fn updated(update: &Update, body: &Value) -> anyhow::Result<Option<Value>> {
+    let id: &str = document::id_of(body)?;
+    let mut updated: Value = body.clone();
+
+    match update.apply(&mut updated) {
+        Ok(true) => {}
+        Ok(false) => return Ok(None),
+        Err(err) => bail!(DbError::InvalidArgument(format!(
+            "couldn't update document \"{id}\": {err}"
+        ))),
+    }
+
+    document::encode(&updated).map_err(|err| {
+        DbError::InvalidArgument(format!("couldn't update document \"{id}\": {err}"))
+    })?;
+
+    Ok(Some(updated))
+}
+
+/// The next part of a read of `collection_name` at `snapshot`, starting at `position` or
+/// else from the beginning, and where the one after it starts. The read lock is only held
+/// for one part.
+async fn read_part(
+    database: &SharedDatabase,
+    collection_name: &str,
+    filter: Option<&Filter>,
+    snapshot: u64,
+    position: Option<Position>,
+) -> anyhow::Result<(Vec<Value>, Position)> {
+    let collections = database.collections.clone().read_owned().await;
+    let name: String = collection_name.to_string();
+    let filter: Option<Filter> = filter.cloned();
+
+    store::blocking(move || {
+        let collection: &Collection = collections
+            .get(&name)
+            .ok_or_else(|| no_such_collection(&name))?;
+
+        if collection.created > snapshot {
+            bail!(DbError::Conflict(format!(
+                "collection \"{name}\" was created during the read"
+            )));
+        }
+
+        let mut position: Position =
+            position.unwrap_or_else(|| collection.position(filter.as_ref()));
+        let part: Vec<Value> =
+            collection.matching_part_at(filter.as_ref(), snapshot, &mut position, PART_SIZE)?;
+
+        Ok((part, position))
+    })
+    .await
+}
+
+fn no_such_collection(name: &str) -> DbError {
+    DbError::NotFound(format!("no such collection \"{name}\""))
+}
+
+fn no_transaction() -> DbError {
+    DbError::FailedPrecondition("no transaction in progress".into())
 }
@@ -0,0 +1,217 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use super::value::Value;
+
+#[derive(Debug, Default)]
+pub struct Clock {
+    now: AtomicU64,
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl Clock {
+    pub fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    /// Called by writers holding the write lock on the collections, so readers never see a
+    /// commit half done.
+    pub fn tick(&self) -> u64 {
+        self.now.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// The versions the snapshot sees are kept until it is dropped.
+    pub fn snapshot(self: &Arc<Self>) -> Snapshot {
+        let mut snapshots = self.snapshots();
+
+        // Read with the snapshots locked, so a commit can't collect the versions this one
+        // needs before it is registered
+        let at: u64 = self.now();
+
+        *snapshots.entry(at).or_default() += 1;
+
+        Snapshot {
+            clock: self.clone(),
+            at,
+        }
+    }
+
+    /// The oldest snapshot in use, or now if there is none.
+    pub fn oldest(&self) -> u64 {
+        let snapshots = self.snapshots();
+
+        snapshots
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or_else(|| self.now())
+    }
+
+    fn snapshots(&self) -> MutexGuard<'_, BTreeMap<u64, usize>> {
+        self.snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[derive(Debug)]
+pub struct Snapshot {
+    clock: Arc<Clock>,
+    pub at: u64,
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut snapshots = self.clock.snapshots();
+
+        if let Some(count) = snapshots.get_mut(&self.at) {
+            *count -= 1;
+
+            if *count == 0 {
+                snapshots.remove(&self.at);
+            }
+        }
+    }
+}
+
+/// The versions replaced by commits, kept in memory while a snapshot older than the commit
+/// is in use. The latest versions are on disk.
+#[derive(Debug, Default)]
+pub struct History {
+    /// By commit: the `_id` of every document the commit wrote, with its previous
+    /// version, `None` for the ones it inserted.
+    changes: BTreeMap<u64, Vec<(String, Option<Value>)>>,
+}
+
+impl History {
+    pub fn record(&mut self, version: u64, id: String, previous: Option<Value>) {
+        self.changes
+            .entry(version)
+            .or_default()
+            .push((id, previous));
+    }
+
+    /// `None` for the documents that didn't exist yet at `snapshot`.
+    pub fn as_of(&self, snapshot: u64) -> HashMap<&str, Option<&Value>> {
+        let mut documents: HashMap<&str, Option<&Value>> = HashMap::new();
+
+        // The first commit after the snapshot replaced the version it sees
+        for changes in self
+            .changes
+            .range(snapshot + 1..)
+            .map(|(_, changes)| changes)
+        {
+            for (id, previous) in changes {
+                documents.entry(id).or_insert(previous.as_ref());
+            }
+        }
+
+        documents
+    }
+
+    pub fn changed_after(&self, snapshot: u64, id: &str) -> bool {
+        self.changes
+            .range(snapshot + 1..)
+            .any(|(_, changes)| changes.iter().any(|(changed, _)| changed == id))
+    }
+
+    /// Drops the versions that no snapshot taken at `oldest` or later can see.
+    pub fn collect(&mut self, oldest: u64) {
+        self.changes = self.changes.split_off(&(oldest + 1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::{Clock, History, Snapshot};
+    use crate::database_manager::value::Value;
+
+    #[test]
+    fn snapshots_hold_back_the_oldest() {
+        let clock: Arc<Clock> = Arc::new(Clock::default());
+
+        clock.tick();
+
+        let first: Snapshot = clock.snapshot();
+
+        clock.tick();
+
+        let second: Snapshot = clock.snapshot();
+        let again: Snapshot = clock.snapshot();
+
+        clock.tick();
+
+        assert_eq!(clock.oldest(), first.at);
+
+        drop(first);
+        drop(second);
+
+        assert_eq!(clock.oldest(), again.at);
+
+        drop(again);
+
+        assert_eq!(clock.oldest(), clock.now());
+    }
+
+    #[test]
+    fn snapshot_sees_the_versions_of_its_time() {
+        let mut history: History = History::default();
+
+        // Commit 1 inserted "a", commit 2 changed it and inserted "b", commit 3 changed "a"
+        history.record(1, "a".into(), None);
+        history.record(2, "a".into(), Some(Value::Int(1)));
+        history.record(2, "b".into(), None);
+        history.record(3, "a".into(), Some(Value::Int(2)));
+
+        assert_eq!(
+            history.as_of(1),
+            HashMap::from([("a", Some(&Value::Int(1))), ("b", None)])
+        );
+        assert_eq!(
+            history.as_of(2),
+            HashMap::from([("a", Some(&Value::Int(2)))])
+        );
+        assert!(history.as_of(3).is_empty());
+
+        assert!(history.changed_after(1, "b"));
+        assert!(!history.changed_after(2, "b"));
+        assert!(history.changed_after(2, "a"));
+    }
+
+    #[test]
+    fn collect_keeps_what_the_oldest_snapshot_sees() {
+        let mut history: History = History::default();
+
+        history.record(1, "a".into(), None);
+        history.record(2, "a".into(), Some(Value::Int(1)));
+        history.record(3, "a".into(), Some(Value::Int(2)));
+
+        let before: HashMap<&str, Option<&Value>> = history.as_of(1);
+        let expected: Vec<(String, Option<Value>)> = before
+            .into_iter()
+            .map(|(id, value)| (id.to_string(), value.cloned()))
+            .collect();
+
+        history.collect(1);
+
+        let after: Vec<(String, Option<Value>)> = history
+            .as_of(1)
+            .into_iter()
+            .map(|(id, value)| (id.to_string(), value.cloned()))
+            .collect();
+
+        assert_eq!(after, expected);
+        assert!(history.changed_after(1, "a"));
+
+        history.collect(3);
+
+        assert!(history.as_of(0).is_empty());
+    }
+}
@@ -0,0 +1,40 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    process,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static COUNTER: AtomicU32 = AtomicU32::new(0);
+static PROCESS_UNIQUE: OnceLock<u64> = OnceLock::new();
+
+/// Generates a 24 character hex id laid out like a MongoDB ObjectId: 4 bytes of unix
+/// seconds, 5 bytes unique to this process and a 3 byte counter, so ids sort by
+/// creation time.
+pub fn generate() -> String {
+    let seconds: u64 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    let process_unique: u64 = *PROCESS_UNIQUE.get_or_init(|| {
+        let mut hasher = RandomState::new().build_hasher();
+
+        hasher.write_u32(process::id());
+        hasher.write_u64(seconds);
+
+        hasher.finish()
+    });
+
+    let counter: u32 = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    format!(
+        "{:08x}{:010x}{:06x}",
+        seconds & 0xffff_ffff,
+        process_unique & 0xff_ffff_ffff,
+        counter & 0xff_ffff
+    )
+}
@@ -0,0 +1,306 @@
+//! Slotted pages. Records keep their slot when they move within a page, so a (page, slot)
+//! pair stays a stable address.
+
+use super::format::FormatError;
+
+pub const PAGE_SIZE: usize = 8192;
+
+// Header: CRC-32 of the rest of the page, number of slots, start of the record area
+const CHECKSUM_OFFSET: usize = 0;
+const SLOT_COUNT_OFFSET: usize = 4;
+const DATA_START_OFFSET: usize = 6;
+const HEADER_SIZE: usize = 8;
+
+// Slot: offset of the record, 0 for a free slot, and its length
+const SLOT_SIZE: usize = 4;
+
+pub const MAX_RECORD_SIZE: usize = PAGE_SIZE - HEADER_SIZE - SLOT_SIZE;
+
+pub type Page = [u8; PAGE_SIZE];
+
+pub fn init(page: &mut Page) {
+    page.fill(0);
+
+    set_slot_count(page, 0);
+    set_data_start(page, PAGE_SIZE);
+}
+
+pub fn seal(page: &mut Page) {
+    let checksum: u32 = crc32fast::hash(&page[SLOT_COUNT_OFFSET..]);
+
+    page[CHECKSUM_OFFSET..SLOT_COUNT_OFFSET].copy_from_slice(&checksum.to_le_bytes());
+}
+
+/// A page that was never written reads as zeroes and is turned into an empty one.
+pub fn verify(page: &mut Page) -> Result<(), FormatError> {
+    if page.iter().all(|byte| *byte == 0) {
+        init(page);
+
+        return Ok(());
+    }
+
+    let checksum: u32 = u32::from_le_bytes([page[0], page[1], page[2], page[3]]);
+
+    if crc32fast::hash(&page[SLOT_COUNT_OFFSET..]) != checksum {
+        return Err(FormatError::ChecksumMismatch);
+    }
+
+    let slot_count: usize = slot_count(page);
+    let data_start: usize = data_start(page);
+
+    if HEADER_SIZE + slot_count * SLOT_SIZE > data_start || data_start > PAGE_SIZE {
+        return Err(FormatError::Malformed);
+    }
+
+    for slot in 0..slot_count {
+        let (offset, length): (usize, usize) = slot_entry(page, slot);
+
+        if offset != 0 && (offset < data_start || offset + length > PAGE_SIZE) {
+            return Err(FormatError::Malformed);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn records(page: &Page) -> impl Iterator<Item = (u16, &[u8])> {
+    (0..slot_count(page))
+        .filter_map(|slot| get(page, slot as u16).map(|record| (slot as u16, record)))
+}
+
+pub fn get(page: &Page, slot: u16) -> Option<&[u8]> {
+    let slot: usize = slot as usize;
+
+    if slot >= slot_count(page) {
+        return None;
+    }
+
+    match slot_entry(page, slot) {
+        (0, _) => None,
+        (offset, length) => Some(&page[offset..offset + length]),
+    }
+}
+
+/// The size of the largest record `insert` can still store.
+pub fn free_space(page: &Page) -> usize {
+    let new_slot: usize = if free_slot(page).is_some() {
+        0
+    } else {
+        SLOT_SIZE
+    };
+
+    unused(page).saturating_sub(new_slot)
+}
+
+pub fn insert(page: &mut Page, record: &[u8]) -> Option<u16> {
+    if record.len() > free_space(page) {
+        return None;
+    }
+
+    let slot: usize = match free_slot(page) {
+        Some(slot) => slot,
+        None => {
+            let slot: usize = slot_count(page);
+
+            // Make room for the new slot before counting it
+            if contiguous(page) < SLOT_SIZE {
+                compact(page);
+            }
+
+            set_slot_count(page, slot + 1);
+            set_slot_entry(page, slot, 0, 0);
+
+            slot
+        }
+    };
+
+    place(page, slot, record);
+
+    Some(slot as u16)
+}
+
+/// Returns `false`, leaving the page untouched, if the new record doesn't fit.
+pub fn replace(page: &mut Page, slot: u16, record: &[u8]) -> bool {
+    let slot: usize = slot as usize;
+    let (offset, length): (usize, usize) = slot_entry(page, slot);
+
+    if record.len() <= length {
+        page[offset..offset + record.len()].copy_from_slice(record);
+        set_slot_entry(page, slot, offset, record.len());
+
+        return true;
+    }
+
+    if record.len() > unused(page) + length {
+        return false;
+    }
+
+    set_slot_entry(page, slot, 0, 0);
+    place(page, slot, record);
+
+    true
+}
+
+pub fn delete(page: &mut Page, slot: u16) {
+    set_slot_entry(page, slot as usize, 0, 0);
+
+    // Trailing free slots are given back to the record area
+    let mut slot_count: usize = slot_count(page);
+
+    while slot_count > 0 && slot_entry(page, slot_count - 1).0 == 0 {
+        slot_count -= 1;
+    }
+
+    set_slot_count(page, slot_count);
+}
+
+/// The caller has checked that the record fits.
+fn place(page: &mut Page, slot: usize, record: &[u8]) {
+    if contiguous(page) < record.len() {
+        compact(page);
+    }
+
+    let offset: usize = data_start(page) - record.len();
+
+    page[offset..offset + record.len()].copy_from_slice(record);
+
+    set_data_start(page, offset);
+    set_slot_entry(page, slot, offset, record.len());
+}
+
+fn compact(page: &mut Page) {
+    let records: Vec<(usize, Vec<u8>)> = (0..slot_count(page))
+        .filter_map(|slot| get(page, slot as u16).map(|record| (slot, record.to_vec())))
+        .collect();
+
+    let mut data_start: usize = PAGE_SIZE;
+
+    for (slot, record) in records {
+        data_start -= record.len();
+
+        page[data_start..data_start + record.len()].copy_from_slice(&record);
+        set_slot_entry(page, slot, data_start, record.len());
+    }
+
+    set_data_start(page, data_start);
+}
+
+/// Space not used by the header, the slots or live records, contiguous or not.
+fn unused(page: &Page) -> usize {
+    let live: usize = records(page).map(|(_, record)| record.len()).sum();
+
+    PAGE_SIZE - HEADER_SIZE - slot_count(page) * SLOT_SIZE - live
+}
+
+/// Space between the slots and the record area.
+fn contiguous(page: &Page) -> usize {
+    data_start(page) - HEADER_SIZE - slot_count(page) * SLOT_SIZE
+}
+
+fn free_slot(page: &Page) -> Option<usize> {
+    (0..slot_count(page)).find(|slot| slot_entry(page, *slot).0 == 0)
+}
+
+fn slot_count(page: &Page) -> usize {
+    read_u16(page, SLOT_COUNT_OFFSET)
+}
+
+fn set_slot_count(page: &mut Page, slot_count: usize) {
+    write_u16(page, SLOT_COUNT_OFFSET, slot_count);
+}
+
+fn data_start(page: &Page) -> usize {
+    read_u16(page, DATA_START_OFFSET)
+}
+
+fn set_data_start(page: &mut Page, data_start: usize) {
+    write_u16(page, DATA_START_OFFSET, data_start);
+}
+
+fn slot_entry(page: &Page, slot: usize) -> (usize, usize) {
+    let position: usize = HEADER_SIZE + slot * SLOT_SIZE;
+
+    (read_u16(page, position), read_u16(page, position + 2))
+}
+
+fn set_slot_entry(page: &mut Page, slot: usize, offset: usize, length: usize) {
+    let position: usize = HEADER_SIZE + slot * SLOT_SIZE;
+
+    write_u16(page, position, offset);
+    write_u16(page, position + 2, length);
+}
+
+fn read_u16(page: &Page, position: usize) -> usize {
+    u16::from_le_bytes([page[position], page[position + 1]]) as usize
+}
+
+fn write_u16(page: &mut Page, position: usize, value: usize) {
+    page[position..position + 2].copy_from_slice(&(value as u16).to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{delete, get, init, insert, records, replace, seal, verify, Page, PAGE_SIZE};
+    use crate::database_manager::format::FormatError;
+
+    fn page_with(records: &[&[u8]]) -> Page {
+        let mut page: Page = [0; PAGE_SIZE];
+
+        init(&mut page);
+
+        for record in records {
+            insert(&mut page, record).unwrap();
+        }
+
+        page
+    }
+
+    #[test]
+    fn sealed_page_verifies() {
+        let mut page: Page = page_with(&[b"first", b"second"]);
+
+        seal(&mut page);
+
+        assert_eq!(verify(&mut page), Ok(()));
+        assert_eq!(get(&page, 1), Some(&b"second"[..]));
+    }
+
+    #[test]
+    fn any_changed_byte_is_caught() {
+        let mut sealed: Page = page_with(&[b"first", b"second"]);
+
+        seal(&mut sealed);
+
+        for position in [0, 5, 8, PAGE_SIZE / 2, PAGE_SIZE - 1] {
+            let mut page: Page = sealed;
+
+            page[position] ^= 0x01;
+
+            assert_eq!(verify(&mut page), Err(FormatError::ChecksumMismatch));
+        }
+    }
+
+    #[test]
+    fn unwritten_page_reads_as_empty() {
+        let mut page: Page = [0; PAGE_SIZE];
+
+        assert_eq!(verify(&mut page), Ok(()));
+        assert_eq!(records(&page).count(), 0);
+        assert!(insert(&mut page, b"record").is_some());
+    }
+
+    #[test]
+    fn slots_survive_compaction() {
+        let big: Vec<u8> = vec![7; PAGE_SIZE / 3];
+        let mut page: Page = page_with(&[&big, b"kept", &big]);
+
+        delete(&mut page, 0);
+
+        // Only fits once the hole left by the first record is merged into the free space
+        assert!(replace(&mut page, 1, &vec![9; PAGE_SIZE / 2]));
+
+        assert_eq!(get(&page, 0), None);
+        assert_eq!(get(&page, 1), Some(&vec![9; PAGE_SIZE / 2][..]));
+        assert_eq!(get(&page, 2), Some(&big[..]));
+    }
+}
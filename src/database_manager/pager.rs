@@ -0,0 +1,435 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::bail;
+use tracing::warn;
+
+use super::{
+    format::{self, FileKind, FormatError, HEADER_SIZE},
+    page::{self, Page, PAGE_SIZE},
+};
+
+pub const BUFFER_POOL_PAGES: usize = 256;
+
+pub type PageId = u32;
+
+struct Frame {
+    page: Box<Page>,
+    dirty: bool,
+    last_used: u64,
+}
+
+/// Modified pages are only written back when they are evicted or on `flush`: the
+/// write-ahead log covers them until then. Page 0 holds the file header.
+///
+/// Pages are only written in place once their images are synced to the double-write file
+/// next to it. A crash tearing a page can then be repaired on `open`, even when the log no
+/// longer holds its records.
+pub struct Pager {
+    file: File,
+    double_write: File,
+    page_count: PageId,
+    frames: HashMap<PageId, Frame>,
+    clock: u64,
+}
+
+impl std::fmt::Debug for Pager {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Pager")
+            .field("page_count", &self.page_count)
+            .field("cached", &self.frames.len())
+            .finish()
+    }
+}
+
+impl Pager {
+    pub fn open(path: &Path, kind: FileKind) -> anyhow::Result<Self> {
+        let mut file: File = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+
+        let length: u64 = file.metadata()?.len();
+
+        let double_write_path: PathBuf = double_write_path(path);
+        let created: bool = !double_write_path.exists();
+
+        let mut double_write: File = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&double_write_path)?;
+
+        // It has to still be there after a crash that tears a page
+        if created {
+            format::sync_dir(path.parent().unwrap_or(Path::new(".")))?;
+        }
+
+        if length == 0 {
+            let mut header: Box<Page> = Box::new([0; PAGE_SIZE]);
+            let mut bytes: Vec<u8> = vec![];
+
+            format::write_header(kind, &mut bytes);
+            bytes.extend_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+
+            header[..bytes.len()].copy_from_slice(&bytes);
+
+            file.write_all(header.as_slice())?;
+            file.sync_all()?;
+        } else {
+            let mut bytes: [u8; HEADER_SIZE + 4] = [0; HEADER_SIZE + 4];
+
+            file.read_exact(&mut bytes)
+                .map_err(|_| FormatError::Truncated)?;
+
+            format::read_header(&bytes, kind)?;
+
+            let page_size: u32 = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+
+            if page_size as usize != PAGE_SIZE {
+                bail!("pages are {page_size} bytes, expected {PAGE_SIZE}");
+            }
+
+            let mut images: Vec<u8> = vec![];
+
+            double_write.read_to_end(&mut images)?;
+
+            restore(&mut file, &images, path)?;
+        }
+
+        // Restored, or left by an earlier file of this name and of no use to this one
+        double_write.set_len(0)?;
+
+        let length: u64 = file.metadata()?.len();
+
+        // A crash while the file grew can leave part of a page at its end
+        if !length.is_multiple_of(PAGE_SIZE as u64) {
+            warn!(
+                "Ignoring the incomplete last page of \"{}\"",
+                path.display()
+            );
+        }
+
+        Ok(Self {
+            file,
+            double_write,
+            page_count: (length / PAGE_SIZE as u64) as PageId,
+            frames: HashMap::new(),
+            clock: 0,
+        })
+    }
+
+    pub fn page_count(&self) -> PageId {
+        self.page_count
+    }
+
+    pub fn page(&mut self, id: PageId) -> anyhow::Result<&Page> {
+        Ok(&*self.frame(id)?.page)
+    }
+
+    pub fn page_mut(&mut self, id: PageId) -> anyhow::Result<&mut Page> {
+        let frame: &mut Frame = self.frame(id)?;
+
+        frame.dirty = true;
+
+        Ok(&mut *frame.page)
+    }
+
+    pub fn allocate(&mut self) -> anyhow::Result<PageId> {
+        self.make_room()?;
+
+        let id: PageId = self.page_count;
+        let mut page: Box<Page> = Box::new([0; PAGE_SIZE]);
+
+        page::init(&mut page);
+
+        self.clock += 1;
+        self.frames.insert(
+            id,
+            Frame {
+                page,
+                dirty: true,
+                last_used: self.clock,
+            },
+        );
+
+        self.page_count += 1;
+
+        Ok(id)
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        let mut dirty: Vec<PageId> = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| frame.dirty)
+            .map(|(id, _)| *id)
+            .collect();
+
+        dirty.sort_unstable();
+
+        if !dirty.is_empty() {
+            self.write_images(&dirty)?;
+        }
+
+        for id in &dirty {
+            let frame: &mut Frame = self.frames.get_mut(id).expect("dirty pages are cached");
+
+            self.file
+                .seek(SeekFrom::Start(*id as u64 * PAGE_SIZE as u64))?;
+            self.file.write_all(frame.page.as_slice())?;
+
+            frame.dirty = false;
+        }
+
+        self.file.sync_all()?;
+
+        // Only needed until the pages are synced in place
+        if !dirty.is_empty() {
+            self.double_write.set_len(0)?;
+        }
+
+        Ok(())
+    }
+
+    fn frame(&mut self, id: PageId) -> anyhow::Result<&mut Frame> {
+        if id == 0 || id >= self.page_count {
+            bail!("page {id} is out of range");
+        }
+
+        if !self.frames.contains_key(&id) {
+            self.make_room()?;
+
+            let mut page: Box<Page> = Box::new([0; PAGE_SIZE]);
+
+            self.file
+                .seek(SeekFrom::Start(id as u64 * PAGE_SIZE as u64))?;
+            self.file.read_exact(page.as_mut_slice())?;
+
+            page::verify(&mut page)?;
+
+            self.frames.insert(
+                id,
+                Frame {
+                    page,
+                    dirty: false,
+                    last_used: 0,
+                },
+            );
+        }
+
+        self.clock += 1;
+
+        let frame: &mut Frame = self.frames.get_mut(&id).expect("page was just cached");
+
+        frame.last_used = self.clock;
+
+        Ok(frame)
+    }
+
+    fn make_room(&mut self) -> anyhow::Result<()> {
+        if self.frames.len() < BUFFER_POOL_PAGES {
+            return Ok(());
+        }
+
+        let Some(id) = self
+            .frames
+            .iter()
+            .min_by_key(|(_, frame)| frame.last_used)
+            .map(|(id, _)| *id)
+        else {
+            return Ok(());
+        };
+
+        // Writing every modified page at once spares the next evictions a synced write each
+        if self.frames[&id].dirty {
+            self.flush()?;
+        }
+
+        self.frames.remove(&id);
+
+        Ok(())
+    }
+
+    /// Seals the pages `ids` and syncs their images to the double-write file.
+    fn write_images(&mut self, ids: &[PageId]) -> anyhow::Result<()> {
+        let mut images: Vec<u8> = vec![];
+
+        format::write_header(FileKind::DoubleWrite, &mut images);
+
+        for id in ids {
+            let frame: &mut Frame = self.frames.get_mut(id).expect("dirty pages are cached");
+
+            page::seal(&mut frame.page);
+
+            let mut record: Vec<u8> = Vec::with_capacity(4 + PAGE_SIZE);
+
+            record.extend_from_slice(&id.to_le_bytes());
+            record.extend_from_slice(frame.page.as_slice());
+
+            format::write_record(&record, &mut images);
+        }
+
+        self.double_write.set_len(0)?;
+        self.double_write.seek(SeekFrom::Start(0))?;
+        self.double_write.write_all(&images)?;
+        self.double_write.sync_all()?;
+
+        Ok(())
+    }
+}
+
+/// The double-write file of the paged file at `path`.
+pub fn double_write_path(path: &Path) -> PathBuf {
+    let mut double_write_path: OsString = path.as_os_str().to_owned();
+
+    double_write_path.push(".");
+    double_write_path.push(FileKind::DoubleWrite.extension());
+
+    double_write_path.into()
+}
+
+/// Puts back the pages of `file` that fail their checksum from the images a crash left in
+/// its double-write file. Pages past the end of the file were never synced whole, so the
+/// log still holds their records. Images that weren't all synced are ignored: no page was
+/// written in place yet.
+fn restore(file: &mut File, images: &[u8], path: &Path) -> anyhow::Result<()> {
+    let Ok(mut remaining) = format::read_header(images, FileKind::DoubleWrite) else {
+        return Ok(());
+    };
+
+    let mut pages: Vec<(PageId, &[u8])> = vec![];
+
+    loop {
+        match format::read_record(&mut remaining) {
+            Ok(Some(record)) if record.len() == 4 + PAGE_SIZE => pages.push((
+                PageId::from_le_bytes([record[0], record[1], record[2], record[3]]),
+                &record[4..],
+            )),
+            Ok(None) => break,
+            _ => return Ok(()),
+        }
+    }
+
+    let length: u64 = file.metadata()?.len();
+    let mut restored: usize = 0;
+
+    for (id, image) in pages {
+        let offset: u64 = id as u64 * PAGE_SIZE as u64;
+
+        if id == 0 || offset + PAGE_SIZE as u64 > length {
+            continue;
+        }
+
+        let mut page: Box<Page> = Box::new([0; PAGE_SIZE]);
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(page.as_mut_slice())?;
+
+        if page::verify(&mut page).is_ok() {
+            continue;
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(image)?;
+
+        restored += 1;
+    }
+
+    if restored > 0 {
+        file.sync_all()?;
+
+        warn!("Restored {restored} torn pages of \"{}\"", path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{Seek, SeekFrom, Write},
+        path::{Path, PathBuf},
+    };
+
+    use super::{double_write_path, PageId, Pager};
+    use crate::database_manager::{
+        format::{self, FileKind},
+        page::{self, PAGE_SIZE},
+    };
+
+    /// Page 1 holds "old" on disk and "new" in the double-write file, as a crash right
+    /// before the write in place leaves them.
+    fn interrupted(name: &str) -> PathBuf {
+        let path: PathBuf = format::test_dir(name).join("data.ldat");
+        let mut pager: Pager = Pager::open(&path, FileKind::Data).unwrap();
+        let id: PageId = pager.allocate().unwrap();
+
+        page::insert(pager.page_mut(id).unwrap(), b"old").unwrap();
+        pager.flush().unwrap();
+
+        assert!(page::replace(pager.page_mut(id).unwrap(), 0, b"new"));
+        pager.write_images(&[id]).unwrap();
+
+        path
+    }
+
+    fn tear(path: &Path) {
+        let mut file: File = OpenOptions::new().write(true).open(path).unwrap();
+
+        file.seek(SeekFrom::Start((PAGE_SIZE + PAGE_SIZE / 2) as u64))
+            .unwrap();
+        file.write_all(&[0xff; 512]).unwrap();
+    }
+
+    fn record(path: &Path) -> anyhow::Result<Vec<u8>> {
+        let mut pager: Pager = Pager::open(path, FileKind::Data)?;
+
+        Ok(page::get(pager.page(1)?, 0).unwrap().to_vec())
+    }
+
+    #[test]
+    fn torn_page_is_restored() {
+        let path: PathBuf = interrupted("torn_page_is_restored");
+
+        tear(&path);
+
+        assert_eq!(record(&path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn whole_page_is_kept() {
+        let path: PathBuf = interrupted("whole_page_is_kept");
+
+        // The log redoes the change
+        assert_eq!(record(&path).unwrap(), b"old");
+    }
+
+    #[test]
+    fn partial_images_are_ignored() {
+        let path: PathBuf = interrupted("partial_images_are_ignored");
+        let images: File = OpenOptions::new()
+            .write(true)
+            .open(double_write_path(&path))
+            .unwrap();
+
+        images
+            .set_len(images.metadata().unwrap().len() - 1)
+            .unwrap();
+
+        tear(&path);
+
+        let err: format::FormatError = record(&path).unwrap_err().downcast().unwrap();
+
+        assert_eq!(err, format::FormatError::ChecksumMismatch);
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail};
+use tokio::{
+    fs,
+    sync::{Mutex, RwLock},
+    task,
+    time::{self, Interval},
+};
+use tracing::{error, info};
+
+use super::{
+    catalog::{Catalog, CollectionEntry, SCHEMA_VERSION},
+    collection::Collection,
+    configuration::Compaction,
+    document,
+    error::DbError,
+    format,
+    mvcc::Clock,
+    no_such_collection, object_id,
+    users::Logins,
+    value::Value,
+    wal::{Record, Wal, CHECKPOINT_SIZE, DROPPED_PREFIX},
+};
+
+/// A database opened by at least one session. Writers hold `writer` while they check and
+/// log their change, so records are appended in the order they are applied, and only take
+/// the write lock on `collections` to apply it: readers don't wait for the log.
+///
+/// The files of collections and catalogs are read and written synchronously, so their
+/// locks are taken owned and handed to `blocking`.
+#[derive(Debug)]
+pub struct OpenDatabase {
+    pub path: PathBuf,
+    /// Only changed by writers holding the write lock on `collections`.
+    pub catalog: Arc<Mutex<Catalog>>,
+    /// The collections used since the database was opened.
+    pub collections: Arc<RwLock<HashMap<String, Collection>>>,
+    pub wal: Wal,
+    pub writer: Mutex<()>,
+    pub clock: Arc<Clock>,
+}
+
+impl OpenDatabase {
+    /// Opens the collection called `name` the first time it is used. Does nothing if there
+    /// is no such collection, which callers report when they don't find it.
+    ///
+    /// Opening may rebuild indexes, so it holds `writer` to keep the collection from being
+    /// dropped meanwhile rather than the write lock: readers of the other collections go on.
+    /// Must be called without holding `writer`.
+    pub async fn load(&self, name: &str) -> anyhow::Result<()> {
+        if self.collections.read().await.contains_key(name) {
+            return Ok(());
+        }
+
+        let _writer = self.writer.lock().await;
+
+        if self.collections.read().await.contains_key(name) {
+            return Ok(());
+        }
+
+        let Some(entry) = self.catalog.lock().await.collection(name).cloned() else {
+            return Ok(());
+        };
+
+        let path: PathBuf = self.path.clone();
+        let opened: String = name.to_string();
+
+        let collection: Collection =
+            blocking(move || open_collection(&path, &opened, entry)).await?;
+
+        self.collections
+            .write()
+            .await
+            .insert(name.to_string(), collection);
+
+        Ok(())
+    }
+
+    /// Rewrites the files of the collection `name` without their unused space, returning
+    /// how many bytes that reclaimed.
+    pub async fn vacuum(&self, name: &str) -> anyhow::Result<u64> {
+        self.load(name).await?;
+
+        let _writer = self.writer.lock().await;
+        let mut collections = self.collections.clone().write_owned().await;
+        let name: String = name.to_string();
+
+        blocking(move || {
+            collections
+                .get_mut(&name)
+                .ok_or_else(|| no_such_collection(&name))?
+                .vacuum()
+        })
+        .await
+    }
+
+    /// Writes back the modified pages of every collection, after which the log has nothing
+    /// left to redo and is emptied. Called by writers holding `writer`, which keeps the
+    /// collections from changing: readers go on meanwhile.
+    pub async fn checkpoint(&self) -> anyhow::Result<()> {
+        let collections = self.collections.clone().read_owned().await;
+        let path: PathBuf = self.path.clone();
+
+        blocking(move || flush(&path, &collections)).await?;
+
+        self.wal.reset().await
+    }
+
+    /// Logs `records`, which only hold writes, and applies them as one commit. Called by
+    /// writers holding `writer`, once they checked the writes can be applied.
+    pub async fn commit(&self, records: Vec<Record>) -> anyhow::Result<()> {
+        let logged: Record = match records.as_slice() {
+            [record] => record.clone(),
+            _ => Record::Transaction {
+                records: records.clone(),
+            },
+        };
+
+        // A record that can't be applied would fail every replay too
+        for record in &records {
+            if let Record::Write { documents, .. } = record {
+                for body in documents {
+                    document::check(body)?;
+                }
+            }
+        }
+
+        self.wal.append(&logged).await?;
+
+        let mut collections = self.collections.clone().write_owned().await;
+        let clock: Arc<Clock> = self.clock.clone();
+
+        blocking(move || {
+            let version: u64 = clock.tick();
+
+            for record in records {
+                let (collection, ids, documents): (String, Vec<String>, Vec<Value>) = match record {
+                    Record::Write {
+                        collection,
+                        documents,
+                    } => (collection, vec![], documents),
+                    Record::Delete { collection, ids } => (collection, ids, vec![]),
+                    record => unreachable!("{record:?} isn't a write"),
+                };
+
+                collections
+                    .get_mut(&collection)
+                    .ok_or_else(|| no_such_collection(&collection))?
+                    .commit(version, &ids, documents)?;
+            }
+
+            // Versions no snapshot can see anymore
+            let oldest: u64 = clock.oldest();
+
+            for collection in collections.values_mut() {
+                collection.history.collect(oldest);
+            }
+
+            Ok(())
+        })
+        .await?;
+
+        // Once the log outgrew it, the commit is followed by a checkpoint
+        if self.wal.len() >= CHECKPOINT_SIZE {
+            self.checkpoint().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// One database, shared by every session using it.
+pub type SharedDatabase = Arc<OpenDatabase>;
+
+/// Keeps the databases sessions have opened in memory, so that a write made by one session
+/// is seen by all the others.
+#[derive(Debug, Default)]
+pub struct Store {
+    databases: Mutex<HashMap<String, SharedDatabase>>,
+    pub logins: Logins,
+}
+
+impl Store {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the database at `path`. Its collections are only opened once used.
+    pub async fn open(&self, name: &str, path: &str) -> anyhow::Result<SharedDatabase> {
+        let mut databases = self.databases.lock().await;
+
+        // The directory may have been dropped, or dropped and created again, since the
+        // database was loaded
+        if !fs::metadata(path)
+            .await
+            .is_ok_and(|metadata| metadata.is_dir())
+        {
+            databases.remove(name);
+
+            bail!(DbError::NotFound(format!("no such database \"{name}\"")));
+        }
+
+        if let Some(database) = databases.get(name) {
+            return Ok(database.clone());
+        }
+
+        let catalog_path: PathBuf = PathBuf::from(path);
+        let catalog: Catalog = blocking(move || Catalog::load(&catalog_path)).await?;
+
+        let database: SharedDatabase = Arc::new(OpenDatabase {
+            path: PathBuf::from(path),
+            catalog: Arc::new(Mutex::new(catalog)),
+            collections: Arc::new(RwLock::new(HashMap::new())),
+            wal: Wal::open(Path::new(path)).await?,
+            writer: Mutex::new(()),
+            clock: Arc::new(Clock::default()),
+        });
+
+        databases.insert(name.to_string(), database.clone());
+
+        Ok(database)
+    }
+
+    /// Drops the database at `path`, logging it in the store log at `store_path`. Sessions
+    /// still using it find out on their next command, when opening it again fails.
+    pub async fn drop_database(
+        &self,
+        name: &str,
+        path: &str,
+        store_path: &Path,
+    ) -> anyhow::Result<()> {
+        let dropped: PathBuf =
+            store_path.join(format!("{DROPPED_PREFIX}{}", object_id::generate()));
+
+        loop {
+            let database: Option<SharedDatabase> = self.databases.lock().await.get(name).cloned();
+
+            // Waited for without the databases locked, which would hold up every session
+            // opening one. Writers already holding it then find it closed
+            let _writer = match &database {
+                Some(database) => Some(database.writer.lock().await),
+                None => None,
+            };
+            let mut collections = match &database {
+                Some(database) => Some(database.collections.write().await),
+                None => None,
+            };
+
+            let mut databases = self.databases.lock().await;
+
+            // Opened meanwhile, by writers that weren't waited for
+            if databases.get(name).map(Arc::as_ptr) != database.as_ref().map(Arc::as_ptr) {
+                continue;
+            }
+
+            if fs::read_dir(path).await.is_err() {
+                bail!(DbError::NotFound(format!("no such database \"{name}\"")));
+            }
+
+            Wal::open(store_path)
+                .await?
+                .append(&Record::DropDatabase {
+                    name: name.to_string(),
+                })
+                .await?;
+
+            if let Some(database) = &database {
+                database.wal.close().await;
+            }
+
+            if let Some(collections) = &mut collections {
+                collections.clear();
+            }
+
+            // Out of the way at once, so nobody opens it while it is removed
+            fs::rename(path, &dropped).await?;
+
+            databases.remove(name);
+
+            break;
+        }
+
+        fs::remove_dir_all(&dropped).await?;
+
+        Ok(())
+    }
+
+    /// Vacuums the open collections whose data file has as much unused space as
+    /// `compaction` asks for. Collections that weren't opened haven't changed.
+    pub async fn compact(&self, compaction: &Compaction) -> anyhow::Result<()> {
+        let databases: Vec<(String, SharedDatabase)> = self
+            .databases
+            .lock()
+            .await
+            .iter()
+            .map(|(name, database)| (name.clone(), database.clone()))
+            .collect();
+
+        for (name, database) in databases {
+            let collection_names: Vec<String> =
+                database.collections.read().await.keys().cloned().collect();
+
+            // One collection at a time, so the others can be written meanwhile
+            for collection_name in collection_names {
+                let _writer = database.writer.lock().await;
+                let mut collections = database.collections.clone().write_owned().await;
+                let compaction: Compaction = compaction.clone();
+                let name: String = name.clone();
+
+                blocking(move || {
+                    // Dropped meanwhile
+                    let Some(collection) = collections.get_mut(&collection_name) else {
+                        return Ok(());
+                    };
+
+                    let (unused, size): (u64, u64) = collection.unused_space()?;
+
+                    if size < compaction.min_size
+                        || unused * 100 < size * compaction.free_percent as u64
+                    {
+                        return Ok(());
+                    }
+
+                    match collection.vacuum() {
+                        Ok(reclaimed) => info!(
+                            "Compacted collection \"{collection_name}\" of \"{name}\", reclaimed {reclaimed} bytes"
+                        ),
+                        Err(err) => error!(
+                            "Couldn't compact collection \"{collection_name}\" of \"{name}\": {err}"
+                        ),
+                    }
+
+                    Ok(())
+                })
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checkpoints every open database, so nothing is left to replay on the next start.
+    pub async fn checkpoint(&self) -> anyhow::Result<()> {
+        for database in self.databases.lock().await.values() {
+            // A writer may have logged a change it didn't apply yet
+            let _writer = database.writer.lock().await;
+
+            database.checkpoint().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Compacts `store` every `compaction.interval`, for as long as the server runs.
+pub async fn compact_periodically(store: Arc<Store>, compaction: Compaction) {
+    let Some(interval) = compaction.interval else {
+        return;
+    };
+
+    let mut ticks: Interval = time::interval(interval);
+
+    // The first tick is immediate, and nothing changed yet
+    ticks.tick().await;
+
+    loop {
+        ticks.tick().await;
+
+        if let Err(err) = store.compact(&compaction).await {
+            error!("Compaction failed: {err}");
+        }
+    }
+}
+
+/// Runs `f` on a thread where blocking is fine, for the synchronous file I/O of
+/// collections and catalogs. The locks it needs are moved into it as owned guards.
+pub async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+) -> anyhow::Result<T> {
+    task::spawn_blocking(f).await?
+}
+
+/// Writes back the modified pages of `collections`, of the database at `path`.
+fn flush(path: &Path, collections: &HashMap<String, Collection>) -> anyhow::Result<()> {
+    for collection in collections.values() {
+        collection.flush()?;
+    }
+
+    // Collections created or dropped since the last checkpoint
+    format::sync_dir(path)
+}
+
+/// Opens the collection `name` of the database at `path`, as recorded by `entry`.
+pub fn open_collection(
+    path: &Path,
+    name: &str,
+    entry: CollectionEntry,
+) -> anyhow::Result<Collection> {
+    if entry.schema_version > SCHEMA_VERSION {
+        bail!(
+            "collection \"{name}\" has schema version {}, newer than this LilDB supports ({SCHEMA_VERSION})",
+            entry.schema_version
+        );
+    }
+
+    let collection_path: PathBuf = path.join(name);
+
+    // The directory of a collection created just before a crash may be missing
+    std::fs::create_dir_all(&collection_path)?;
+
+    Collection::open(
+        name.to_string(),
+        collection_path
+            .to_str()
+            .ok_or(anyhow!("Invalid UTF-8"))?
+            .to_string(),
+        entry.indexes,
+    )
+}
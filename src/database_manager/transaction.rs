@@ -0,0 +1,399 @@
+//! The writes of a transaction are applied together, as a single log record. Until then
+//! the session reads a snapshot taken when it began, with its own writes on top.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::bail;
+
+use super::{
+    collection::Collection,
+    document::{self, RecordId},
+    error::DbError,
+    filter::Filter,
+    mvcc::Snapshot,
+    no_such_collection,
+    store::{self, SharedDatabase},
+    value::Value,
+    wal::Record,
+};
+
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    pub database: SharedDatabase,
+    snapshot: Arc<Snapshot>,
+    /// The documents written to each collection by `_id`, `None` for the deleted ones.
+    writes: BTreeMap<String, BTreeMap<String, Option<Value>>>,
+    /// When the session last ran a command in the transaction. The snapshot keeps old
+    /// versions of documents from being collected, so an idle transaction gets rolled back.
+    pub last_used: Instant,
+}
+
+impl Transaction {
+    pub fn new(database: SharedDatabase) -> Self {
+        let snapshot: Snapshot = database.clock.snapshot();
+
+        Self {
+            database,
+            snapshot: Arc::new(snapshot),
+            writes: BTreeMap::new(),
+            last_used: Instant::now(),
+        }
+    }
+
+    pub async fn matching(
+        &self,
+        collection_name: &str,
+        filter: Option<&Filter>,
+    ) -> anyhow::Result<Vec<Value>> {
+        self.database.load(collection_name).await?;
+
+        let collections = self.database.collections.clone().read_owned().await;
+
+        visible(&collections, collection_name, self.snapshot.at)?;
+
+        let name: String = collection_name.to_string();
+        let owned_filter: Option<Filter> = filter.cloned();
+        let at: u64 = self.snapshot.at;
+
+        let committed: Vec<Value> =
+            store::blocking(move || collections[&name].matching_at(owned_filter.as_ref(), at))
+                .await?;
+
+        let writes: Option<&BTreeMap<String, Option<Value>>> = self.writes.get(collection_name);
+
+        let mut bodies: Vec<Value> = vec![];
+
+        for body in committed {
+            let id: &str = document::id_of(&body)?;
+
+            if !writes.is_some_and(|writes| writes.contains_key(id)) {
+                bodies.push(body);
+            }
+        }
+
+        for body in writes
+            .into_iter()
+            .flat_map(|writes| writes.values().flatten())
+        {
+            if filter.is_none_or(|filter| filter.matches(body)) {
+                bodies.push(body.clone());
+            }
+        }
+
+        Ok(bodies)
+    }
+
+    pub async fn write(&mut self, collection_name: &str, bodies: Vec<Value>) -> anyhow::Result<()> {
+        let writes: &mut BTreeMap<String, Option<Value>> = self.writes_to(collection_name).await?;
+
+        for body in bodies {
+            writes.insert(document::id_of(&body)?.to_string(), Some(body));
+        }
+
+        Ok(())
+    }
+
+    pub async fn delete(&mut self, collection_name: &str, ids: Vec<String>) -> anyhow::Result<()> {
+        let writes: &mut BTreeMap<String, Option<Value>> = self.writes_to(collection_name).await?;
+
+        for id in ids {
+            writes.insert(id, None);
+        }
+
+        Ok(())
+    }
+
+    /// Nothing is applied if another session changed one of the documents since the
+    /// transaction began, or if the writes break a unique index.
+    pub async fn commit(self) -> anyhow::Result<usize> {
+        for collection_name in self.writes.keys() {
+            self.database.load(collection_name).await?;
+        }
+
+        let _writer = self.database.writer.lock().await;
+
+        let collections = self.database.collections.clone().read_owned().await;
+        let at: u64 = self.snapshot.at;
+        let all_writes: BTreeMap<String, BTreeMap<String, Option<Value>>> = self.writes;
+
+        let (records, written): (Vec<Record>, usize) = store::blocking(move || {
+            let mut records: Vec<Record> = vec![];
+            let mut written: usize = 0;
+
+            for (collection_name, writes) in &all_writes {
+                let collection: &Collection = visible(&collections, collection_name, at)?;
+
+                let mut bodies: Vec<(Option<RecordId>, &Value)> = vec![];
+                let mut removed: Vec<RecordId> = vec![];
+                let mut ids: Vec<String> = vec![];
+
+                for (id, body) in writes {
+                    if collection.history.changed_after(at, id) {
+                        bail!(DbError::Conflict(format!(
+                            "document \"{id}\" in \"{collection_name}\" was changed by another session since the transaction began"
+                        )));
+                    }
+
+                    let location: Option<RecordId> =
+                        collection.get(id)?.map(|document| document.location);
+
+                    match (body, location) {
+                        (Some(body), location) => bodies.push((location, body)),
+                        (None, Some(location)) => {
+                            removed.push(location);
+                            ids.push(id.clone());
+                        }
+                        // Inserted, then deleted by the transaction itself
+                        (None, None) => {}
+                    }
+                }
+
+                collection.check_writes(&bodies, &removed)?;
+
+                written += bodies.len() + ids.len();
+
+                if !ids.is_empty() {
+                    records.push(Record::Delete {
+                        collection: collection_name.clone(),
+                        ids,
+                    });
+                }
+
+                if !bodies.is_empty() {
+                    records.push(Record::Write {
+                        collection: collection_name.clone(),
+                        documents: bodies.into_iter().map(|(_, body)| body.clone()).collect(),
+                    });
+                }
+            }
+
+            Ok((records, written))
+        })
+        .await?;
+
+        if !records.is_empty() {
+            self.database.commit(records).await?;
+        }
+
+        Ok(written)
+    }
+
+    async fn writes_to(
+        &mut self,
+        collection_name: &str,
+    ) -> anyhow::Result<&mut BTreeMap<String, Option<Value>>> {
+        self.database.load(collection_name).await?;
+
+        visible(
+            &*self.database.collections.read().await,
+            collection_name,
+            self.snapshot.at,
+        )?;
+
+        Ok(self.writes.entry(collection_name.to_string()).or_default())
+    }
+}
+
+fn visible<'a>(
+    collections: &'a HashMap<String, Collection>,
+    name: &str,
+    at: u64,
+) -> anyhow::Result<&'a Collection> {
+    let collection: &Collection = collections
+        .get(name)
+        .ok_or_else(|| no_such_collection(name))?;
+
+    if collection.created > at {
+        bail!(DbError::Conflict(format!(
+            "collection \"{name}\" was created after the transaction began"
+        )));
+    }
+
+    Ok(collection)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use super::Transaction;
+    use crate::{
+        database_manager::{
+            address::Address,
+            configuration::{Compaction, Config},
+            document::Document,
+            error::DbError,
+            format,
+            store::Store,
+            update::{Modification, Update},
+            value::Value,
+            Database,
+        },
+        parser::ast::Command,
+    };
+
+    /// A session using a database whose collection "items" holds one document, "a" with a
+    /// count of 1. Returns its id too.
+    async fn session(name: &str) -> (Database, String) {
+        let config: Config = Config::new(
+            format::test_dir(name).to_string_lossy().into_owned(),
+            Address::default(),
+            None,
+            Compaction {
+                interval: None,
+                free_percent: 50,
+                min_size: 0,
+            },
+            None,
+            None,
+        );
+        let mut session: Database = Database::new(
+            String::new(),
+            String::new(),
+            0,
+            Arc::new(config),
+            Arc::new(Store::new()),
+        );
+
+        session.create_database("shop").await.unwrap();
+        session.use_database("shop").await.unwrap();
+        session.create_collection("items").await.unwrap();
+
+        let ids: Vec<String> = session.insert("items", vec![fields("a", 1)]).await.unwrap();
+
+        (session, ids[0].clone())
+    }
+
+    fn fields(name: &str, count: i64) -> BTreeMap<String, Value> {
+        [
+            ("name".to_string(), Value::String(name.to_string())),
+            ("count".to_string(), Value::Int(count)),
+        ]
+        .into()
+    }
+
+    /// The count of each document of `bodies`, by name.
+    fn counts(bodies: Vec<Value>) -> BTreeMap<String, i64> {
+        bodies
+            .iter()
+            .map(|body| {
+                match (
+                    body.get_path(&["name".to_string()]),
+                    body.get_path(&["count".to_string()]),
+                ) {
+                    (Some(Value::String(name)), Some(Value::Int(count))) => (name.clone(), *count),
+                    _ => panic!("unexpected document {body:?}"),
+                }
+            })
+            .collect()
+    }
+
+    fn expected(counts: &[(&str, i64)]) -> BTreeMap<String, i64> {
+        counts
+            .iter()
+            .map(|(name, count)| (name.to_string(), *count))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn overlapping_writes_conflict() {
+        let (session, id): (Database, String) = session("overlapping_writes_conflict").await;
+
+        let mut first: Transaction = Transaction::new(session.database().await.unwrap());
+        let mut second: Transaction = Transaction::new(session.database().await.unwrap());
+
+        first
+            .write("items", vec![Document::new_body(&id, fields("a", 2))])
+            .await
+            .unwrap();
+        second
+            .write("items", vec![Document::new_body(&id, fields("a", 3))])
+            .await
+            .unwrap();
+
+        assert_eq!(first.commit().await.unwrap(), 1);
+
+        let err: DbError = second.commit().await.unwrap_err().into();
+
+        assert!(matches!(err, DbError::Conflict(_)), "{err:?}");
+        assert_eq!(
+            counts(session.find("items", None).await.unwrap()),
+            expected(&[("a", 2)])
+        );
+    }
+
+    #[tokio::test]
+    async fn rollback_drops_the_writes() {
+        let (mut session, id): (Database, String) = session("rollback_drops_the_writes").await;
+
+        session.process_command(Command::Begin).await.unwrap();
+        session.insert("items", vec![fields("b", 1)]).await.unwrap();
+        session.delete("items", None).await.unwrap();
+
+        assert!(session.find("items", None).await.unwrap().is_empty());
+
+        session.process_command(Command::Rollback).await.unwrap();
+
+        assert!(session.transaction.is_none());
+        assert_eq!(
+            counts(session.find("items", None).await.unwrap()),
+            expected(&[("a", 1)])
+        );
+
+        // Nothing of it is left to conflict with
+        let mut transaction: Transaction = Transaction::new(session.database().await.unwrap());
+
+        transaction
+            .write("items", vec![Document::new_body(&id, fields("a", 2))])
+            .await
+            .unwrap();
+
+        assert_eq!(transaction.commit().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn reads_see_the_snapshot_and_their_writes() {
+        let (mut session, id): (Database, String) =
+            session("reads_see_the_snapshot_and_their_writes").await;
+
+        let mut transaction: Transaction = Transaction::new(session.database().await.unwrap());
+
+        session.insert("items", vec![fields("b", 1)]).await.unwrap();
+        session
+            .update(
+                "items",
+                &Update::new(vec![Modification::Set {
+                    path: vec!["count".to_string()],
+                    value: Value::Int(5),
+                }]),
+                None,
+            )
+            .await
+            .unwrap();
+
+        transaction
+            .write("items", vec![Document::new_body("c", fields("c", 1))])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            counts(transaction.matching("items", None).await.unwrap()),
+            expected(&[("a", 1), ("c", 1)])
+        );
+
+        transaction.delete("items", vec![id]).await.unwrap();
+
+        assert_eq!(
+            counts(transaction.matching("items", None).await.unwrap()),
+            expected(&[("c", 1)])
+        );
+        assert_eq!(
+            counts(session.find("items", None).await.unwrap()),
+            expected(&[("a", 5), ("b", 5)])
+        );
+    }
+}
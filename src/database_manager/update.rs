@@ -0,0 +1,49 @@
+use super::value::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Modification {
+    Set { path: Vec<String>, value: Value },
+    Unset { path: Vec<String> },
+    Increment { path: Vec<String>, amount: Value },
+}
+
+impl Modification {
+    pub fn path(&self) -> &[String] {
+        match self {
+            Self::Set { path, .. } | Self::Unset { path } | Self::Increment { path, .. } => path,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Update {
+    pub modifications: Vec<Modification>,
+}
+
+impl Update {
+    pub fn new(modifications: Vec<Modification>) -> Self {
+        Self { modifications }
+    }
+
+    /// Applies every modification to `document`, returning whether anything changed.
+    pub fn apply(&self, document: &mut Value) -> anyhow::Result<bool> {
+        let mut modified: bool = false;
+
+        for modification in &self.modifications {
+            modified |= match modification {
+                Modification::Set { path, value } => document.set_path(path, value.clone())?,
+                Modification::Unset { path } => document.remove_path(path),
+                Modification::Increment { path, amount } => {
+                    let value: Value = match document.get_path(path) {
+                        Some(current) => current.add(amount)?,
+                        None => amount.clone(),
+                    };
+
+                    document.set_path(path, value)?
+                }
+            };
+        }
+
+        Ok(modified)
+    }
+}
@@ -0,0 +1,404 @@
+//! Users: the accounts clients log in with when they connect. They are documents of the
+//! system database, whose name isn't an identifier so no client can reach it, holding a
+//! salted PBKDF2 hash of the password. Once a user exists, clients have to log in, and get
+//! a session token that every later request carries.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+
+use anyhow::{anyhow, bail};
+use ring::{
+    pbkdf2,
+    rand::{SecureRandom, SystemRandom},
+};
+use tokio::{
+    fs,
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    task,
+};
+
+use super::{
+    error::DbError,
+    filter::{Filter, Operator},
+    index::IndexDefinition,
+    value::Value,
+    Database,
+};
+
+pub const SYSTEM_DATABASE: &str = ".system";
+
+const USERS_COLLECTION: &str = "users";
+
+const NAME_FIELD: &str = "name";
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+const TOKEN_LEN: usize = 32;
+
+/// Anyone can try to log in, so this bounds the blocking threads they can keep busy.
+const MAX_CONCURRENT_HASHES: usize = 4;
+
+/// Who a session token was given to. `user` is `None` for the sessions that connected
+/// before any user existed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Login {
+    pub user: Option<String>,
+    pub session_id: String,
+}
+
+/// The session tokens given out since the server started.
+#[derive(Debug)]
+pub struct Logins {
+    tokens: RwLock<HashMap<String, Login>>,
+    /// Set once a user exists, from then on every client has to log in.
+    required: AtomicBool,
+    /// Held while users are created or dropped.
+    changes: Mutex<()>,
+    hashes: Arc<Semaphore>,
+}
+
+impl Default for Logins {
+    fn default() -> Self {
+        Self {
+            tokens: RwLock::default(),
+            required: AtomicBool::default(),
+            changes: Mutex::default(),
+            hashes: Arc::new(Semaphore::new(MAX_CONCURRENT_HASHES)),
+        }
+    }
+}
+
+impl Logins {
+    pub fn required(&self) -> bool {
+        self.required.load(Ordering::Acquire)
+    }
+
+    /// A new token for `login`.
+    pub fn open(&self, login: Login) -> anyhow::Result<String> {
+        let token: String = hex(&random::<TOKEN_LEN>()?);
+
+        self.tokens
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(token.clone(), login);
+
+        Ok(token)
+    }
+
+    pub fn get(&self, token: &str) -> Option<Login> {
+        self.tokens
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(token)
+            .cloned()
+    }
+
+    /// Whether `login` still has a token, so its streams can go on.
+    pub fn is_open(&self, login: &Login) -> bool {
+        self.tokens
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .any(|open| open == login)
+    }
+
+    /// The sessions that still have a token.
+    pub fn sessions(&self) -> HashSet<String> {
+        self.tokens
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .map(|login| login.session_id.clone())
+            .collect()
+    }
+
+    /// Revokes the tokens of a session, once it disconnected.
+    pub fn close_session(&self, session_id: &str) {
+        self.tokens
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, login| login.session_id != session_id);
+    }
+
+    /// Revokes the tokens of a user once it was dropped, or with `None` those of the
+    /// sessions that connected without one once the first user was created.
+    fn close_user(&self, user: Option<&str>) {
+        self.tokens
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, login| login.user.as_deref() != user);
+    }
+}
+
+/// Creates the system database if it doesn't exist yet, and requires clients to log in if
+/// it has users.
+pub async fn init(context: &Database) -> anyhow::Result<()> {
+    let _changes = context.store.logins.changes.lock().await;
+
+    let system: Database = system(context);
+
+    ensure(&system).await?;
+
+    if !system.find(USERS_COLLECTION, None).await?.is_empty() {
+        context.store.logins.required.store(true, Ordering::Release);
+    }
+
+    Ok(())
+}
+
+pub async fn create(context: &Database, name: &str, password: &str) -> anyhow::Result<()> {
+    let _changes = context.store.logins.changes.lock().await;
+
+    let mut system: Database = system(context);
+
+    ensure(&system).await?;
+
+    if !system
+        .find(USERS_COLLECTION, Some(&named(name)))
+        .await?
+        .is_empty()
+    {
+        bail!(DbError::AlreadyExists(format!(
+            "user \"{name}\" already exists"
+        )));
+    }
+
+    let salt: [u8; SALT_LEN] = random::<SALT_LEN>()?;
+    let password: String = password.to_string();
+
+    let hash: [u8; HASH_LEN] = hashing(&context.store.logins, move || {
+        let mut hash: [u8; HASH_LEN] = [0; HASH_LEN];
+
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).expect("the iterations aren't 0"),
+            &salt,
+            password.as_bytes(),
+            &mut hash,
+        );
+
+        hash
+    })
+    .await?;
+
+    system
+        .insert(
+            USERS_COLLECTION,
+            vec![[
+                (NAME_FIELD.to_string(), Value::String(name.to_string())),
+                ("salt".to_string(), Value::String(hex(&salt))),
+                ("hash".to_string(), Value::String(hex(&hash))),
+                (
+                    "iterations".to_string(),
+                    Value::Int(i64::from(PBKDF2_ITERATIONS)),
+                ),
+            ]
+            .into()],
+        )
+        .await?;
+
+    if !context.store.logins.required.swap(true, Ordering::AcqRel) {
+        context.store.logins.close_user(None);
+    }
+
+    Ok(())
+}
+
+/// Drops a user and revokes its tokens. The last user can't be dropped, as anyone could
+/// connect again without it.
+pub async fn remove(context: &Database, name: &str) -> anyhow::Result<()> {
+    let _changes = context.store.logins.changes.lock().await;
+
+    let mut system: Database = system(context);
+
+    ensure(&system).await?;
+
+    if system
+        .find(USERS_COLLECTION, Some(&named(name)))
+        .await?
+        .is_empty()
+    {
+        bail!(DbError::NotFound(format!("no such user \"{name}\"")));
+    }
+
+    if system.find(USERS_COLLECTION, None).await?.len() == 1 {
+        bail!(DbError::FailedPrecondition(format!(
+            "\"{name}\" is the last user, create another one first"
+        )));
+    }
+
+    system.delete(USERS_COLLECTION, Some(&named(name))).await?;
+
+    context.store.logins.close_user(Some(name));
+
+    Ok(())
+}
+
+/// Whether `password` is the password of the user `name`. Unknown users are checked
+/// against a made-up hash, so they take as long as known ones and the answer doesn't tell
+/// which users exist.
+pub async fn verify(context: &Database, name: &str, password: &str) -> anyhow::Result<bool> {
+    let system: Database = system(context);
+
+    let user: Option<BTreeMap<String, Value>> = if is_dir(&system.path).await {
+        match system
+            .find(USERS_COLLECTION, Some(&named(name)))
+            .await?
+            .pop()
+        {
+            Some(Value::Object(user)) => Some(user),
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    let (salt, hash, iterations): (Vec<u8>, Vec<u8>, NonZeroU32) = match &user {
+        Some(user) => {
+            let (
+                Some(Value::String(salt)),
+                Some(Value::String(hash)),
+                Some(Value::Int(iterations)),
+            ) = (user.get("salt"), user.get("hash"), user.get("iterations"))
+            else {
+                bail!("user \"{name}\" is damaged");
+            };
+
+            let iterations: NonZeroU32 = u32::try_from(*iterations)
+                .ok()
+                .and_then(NonZeroU32::new)
+                .ok_or_else(|| anyhow!("user \"{name}\" is damaged"))?;
+
+            (unhex(salt)?, unhex(hash)?, iterations)
+        }
+        None => (
+            vec![0; SALT_LEN],
+            vec![0; HASH_LEN],
+            NonZeroU32::new(PBKDF2_ITERATIONS).expect("the iterations aren't 0"),
+        ),
+    };
+
+    let password: String = password.to_string();
+
+    let valid: bool = hashing(&context.store.logins, move || {
+        pbkdf2::verify(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &hash,
+        )
+        .is_ok()
+    })
+    .await?;
+
+    Ok(user.is_some() && valid)
+}
+
+/// Runs a password hash on a blocking thread, once fewer than `MAX_CONCURRENT_HASHES` run.
+/// The permit goes with the hash, so a caller that gives up doesn't free it early.
+async fn hashing<T: Send + 'static>(
+    logins: &Logins,
+    hash: impl FnOnce() -> T + Send + 'static,
+) -> anyhow::Result<T> {
+    let permit: OwnedSemaphorePermit = logins.hashes.clone().acquire_owned().await?;
+
+    Ok(task::spawn_blocking(move || {
+        let _permit = permit;
+
+        hash()
+    })
+    .await?)
+}
+
+/// A context using the system database.
+fn system(context: &Database) -> Database {
+    Database::new(
+        SYSTEM_DATABASE.to_string(),
+        format!("{}/{}", context.config.store_path, SYSTEM_DATABASE),
+        0_usize,
+        context.config.clone(),
+        context.store.clone(),
+    )
+}
+
+/// Creates whatever part of the system database is missing, such as after a crash while
+/// it was created.
+async fn ensure(system: &Database) -> anyhow::Result<()> {
+    if !is_dir(&system.path).await {
+        system.create_database(SYSTEM_DATABASE).await?;
+    }
+
+    if !system
+        .collections(None)
+        .await?
+        .contains_key(USERS_COLLECTION)
+    {
+        system.create_collection(USERS_COLLECTION).await?;
+    }
+
+    if system.indexes(USERS_COLLECTION).await?.is_empty() {
+        system
+            .create_index(
+                USERS_COLLECTION,
+                IndexDefinition {
+                    name: NAME_FIELD.to_string(),
+                    fields: vec![vec![NAME_FIELD.to_string()]],
+                    unique: true,
+                },
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+async fn is_dir(path: &str) -> bool {
+    fs::metadata(path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+}
+
+fn named(name: &str) -> Filter {
+    Filter::Compare {
+        path: vec![NAME_FIELD.to_string()],
+        operator: Operator::Equal,
+        value: Value::String(name.to_string()),
+    }
+}
+
+fn random<const N: usize>() -> anyhow::Result<[u8; N]> {
+    let mut bytes: [u8; N] = [0; N];
+
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow!("no random bytes available"))?;
+
+    Ok(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(text: &str) -> anyhow::Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        bail!("invalid hex \"{text}\"");
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            text.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| anyhow!("invalid hex \"{text}\""))
+        })
+        .collect()
+}
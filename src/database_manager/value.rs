@@ -0,0 +1,367 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+};
+
+use anyhow::{anyhow, bail};
+
+// Type tags of the binary encoding
+const TAG_NULL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_FLOAT: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_ARRAY: u8 = 6;
+const TAG_OBJECT: u8 = 7;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    /// Appends the binary encoding of the value: a type tag, then little-endian numbers,
+    /// length-prefixed strings and count-prefixed arrays and objects.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::Null => out.push(TAG_NULL),
+            Self::Bool(false) => out.push(TAG_FALSE),
+            Self::Bool(true) => out.push(TAG_TRUE),
+            Self::Int(integer) => {
+                out.push(TAG_INT);
+                out.extend_from_slice(&integer.to_le_bytes());
+            }
+            Self::Float(float) => {
+                out.push(TAG_FLOAT);
+                out.extend_from_slice(&float.to_le_bytes());
+            }
+            Self::String(string) => {
+                out.push(TAG_STRING);
+                encode_str(string, out);
+            }
+            Self::Array(values) => {
+                out.push(TAG_ARRAY);
+                encode_length(values.len(), out);
+
+                for value in values {
+                    value.encode(out);
+                }
+            }
+            Self::Object(fields) => {
+                out.push(TAG_OBJECT);
+                encode_length(fields.len(), out);
+
+                for (key, value) in fields {
+                    encode_str(key, out);
+                    value.encode(out);
+                }
+            }
+        }
+    }
+
+    /// Reads a value written by `encode` and advances `bytes` past it.
+    pub fn decode(bytes: &mut &[u8]) -> anyhow::Result<Self> {
+        let Some((&tag, rest)) = bytes.split_first() else {
+            bail!("unexpected end of value");
+        };
+
+        *bytes = rest;
+
+        Ok(match tag {
+            TAG_NULL => Self::Null,
+            TAG_FALSE => Self::Bool(false),
+            TAG_TRUE => Self::Bool(true),
+            TAG_INT => Self::Int(i64::from_le_bytes(take(bytes)?)),
+            TAG_FLOAT => Self::Float(f64::from_le_bytes(take(bytes)?)),
+            TAG_STRING => Self::String(decode_str(bytes)?),
+            TAG_ARRAY => {
+                let count: usize = decode_length(bytes)?;
+
+                // Not preallocated from `count`, which a damaged file could make huge
+                let mut values: Vec<Self> = vec![];
+
+                for _ in 0..count {
+                    values.push(Self::decode(bytes)?);
+                }
+
+                Self::Array(values)
+            }
+            TAG_OBJECT => {
+                let count: usize = decode_length(bytes)?;
+                let mut fields: BTreeMap<String, Self> = BTreeMap::new();
+
+                for _ in 0..count {
+                    let key: String = decode_str(bytes)?;
+
+                    fields.insert(key, Self::decode(bytes)?);
+                }
+
+                Self::Object(fields)
+            }
+            tag => bail!("unknown value tag {tag}"),
+        })
+    }
+
+    /// Follows a dotted path such as `address.city` through nested objects.
+    pub fn get_path(&self, path: &[String]) -> Option<&Self> {
+        path.iter().try_fold(self, |value, key| match value {
+            Self::Object(fields) => fields.get(key),
+            _ => None,
+        })
+    }
+
+    /// Sets the value at a dotted path, creating the missing objects along the way.
+    /// Returns whether the document changed.
+    pub fn set_path(&mut self, path: &[String], value: Self) -> anyhow::Result<bool> {
+        let Some((key, parents)) = path.split_last() else {
+            bail!("empty field path");
+        };
+
+        let mut current: &mut Self = self;
+
+        for parent in parents {
+            let Self::Object(fields) = current else {
+                bail!(
+                    "cannot set \"{}\" on a value that isn't an object",
+                    path.join(".")
+                );
+            };
+
+            current = fields
+                .entry(parent.clone())
+                .or_insert_with(|| Self::Object(BTreeMap::new()));
+        }
+
+        let Self::Object(fields) = current else {
+            bail!(
+                "cannot set \"{}\" on a value that isn't an object",
+                path.join(".")
+            );
+        };
+
+        if fields.get(key) == Some(&value) {
+            return Ok(false);
+        }
+
+        fields.insert(key.clone(), value);
+
+        Ok(true)
+    }
+
+    /// Removes the value at a dotted path. Returns whether it existed.
+    pub fn remove_path(&mut self, path: &[String]) -> bool {
+        let Some((key, parents)) = path.split_last() else {
+            return false;
+        };
+
+        let mut current: &mut Self = self;
+
+        for parent in parents {
+            match current {
+                Self::Object(fields) => match fields.get_mut(parent) {
+                    Some(child) => current = child,
+                    None => return false,
+                },
+                _ => return false,
+            }
+        }
+
+        match current {
+            Self::Object(fields) => fields.remove(key).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Adds two numbers, keeping integers as integers as long as they don't overflow.
+    pub fn add(&self, other: &Self) -> anyhow::Result<Self> {
+        let sum: f64 = match (self, other) {
+            (Self::Int(left), Self::Int(right)) => match left.checked_add(*right) {
+                Some(sum) => return Ok(Self::Int(sum)),
+                None => bail!("integer overflow"),
+            },
+            (Self::Int(left), Self::Float(right)) => *left as f64 + right,
+            (Self::Float(left), Self::Int(right)) => left + *right as f64,
+            (Self::Float(left), Self::Float(right)) => left + right,
+            _ => bail!("cannot add {other} to {self}"),
+        };
+
+        if !sum.is_finite() {
+            bail!("float overflow");
+        }
+
+        Ok(Self::Float(sum))
+    }
+
+    /// Orders values of the same kind, treating integers and floats as one kind.
+    /// Values of different kinds aren't comparable.
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Self::Null, Self::Null) => Some(Ordering::Equal),
+            (Self::Bool(left), Self::Bool(right)) => left.partial_cmp(right),
+            (Self::Int(left), Self::Int(right)) => left.partial_cmp(right),
+            (Self::Int(left), Self::Float(right)) => compare_int_float(*left, *right),
+            (Self::Float(left), Self::Int(right)) => {
+                compare_int_float(*right, *left).map(Ordering::reverse)
+            }
+            (Self::Float(left), Self::Float(right)) => left.partial_cmp(right),
+            (Self::String(left), Self::String(right)) => left.partial_cmp(right),
+            (Self::Array(_), Self::Array(_)) | (Self::Object(_), Self::Object(_))
+                if self == other =>
+            {
+                Some(Ordering::Equal)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Compares exactly, where converting `integer` to a float could round it.
+fn compare_int_float(integer: i64, float: f64) -> Option<Ordering> {
+    if float.is_nan() {
+        return None;
+    }
+
+    // i64::MAX as f64 rounds up to 2^63
+    if float >= i64::MAX as f64 {
+        return Some(Ordering::Less);
+    }
+
+    if float < i64::MIN as f64 {
+        return Some(Ordering::Greater);
+    }
+
+    let whole: i64 = float.trunc() as i64;
+
+    Some(
+        integer
+            .cmp(&whole)
+            .then(0.0_f64.partial_cmp(&float.fract())?),
+    )
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(boolean) => write!(f, "{boolean}"),
+            Self::Int(integer) => write!(f, "{integer}"),
+            Self::Float(float) if float.fract() == 0.0 && float.is_finite() => {
+                write!(f, "{float:.1}")
+            }
+            Self::Float(float) => write!(f, "{float}"),
+            Self::String(string) => write_escaped(f, string),
+            Self::Array(values) => {
+                write!(f, "[")?;
+
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+
+                    write!(f, "{value}")?;
+                }
+
+                write!(f, "]")
+            }
+            Self::Object(fields) => {
+                write!(f, "{{")?;
+
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+
+                    write_escaped(f, key)?;
+                    write!(f, ": {value}")?;
+                }
+
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn encode_length(length: usize, out: &mut Vec<u8>) {
+    let length: u32 = u32::try_from(length).expect("value larger than 4 GiB");
+
+    out.extend_from_slice(&length.to_le_bytes());
+}
+
+fn encode_str(string: &str, out: &mut Vec<u8>) {
+    encode_length(string.len(), out);
+    out.extend_from_slice(string.as_bytes());
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> anyhow::Result<[u8; N]> {
+    let Some((head, rest)) = bytes.split_first_chunk::<N>() else {
+        bail!("unexpected end of value");
+    };
+
+    *bytes = rest;
+
+    Ok(*head)
+}
+
+fn decode_length(bytes: &mut &[u8]) -> anyhow::Result<usize> {
+    Ok(u32::from_le_bytes(take(bytes)?) as usize)
+}
+
+fn decode_str(bytes: &mut &[u8]) -> anyhow::Result<String> {
+    let length: usize = decode_length(bytes)?;
+
+    if bytes.len() < length {
+        bail!("unexpected end of value");
+    }
+
+    let (string, rest) = bytes.split_at(length);
+
+    *bytes = rest;
+
+    String::from_utf8(string.to_vec()).map_err(|_| anyhow!("invalid UTF-8 in string"))
+}
+
+/// Writes a quoted string the lexer can read back.
+fn write_escaped(f: &mut Formatter, string: &str) -> fmt::Result {
+    write!(f, "\"")?;
+
+    for c in string.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            '\0' => write!(f, "\\0")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+
+    write!(f, "\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value;
+
+    #[test]
+    fn add_rejects_overflow() {
+        assert_eq!(Value::Int(1).add(&Value::Int(2)).unwrap(), Value::Int(3));
+        assert_eq!(
+            Value::Int(1).add(&Value::Float(0.5)).unwrap(),
+            Value::Float(1.5)
+        );
+
+        assert!(Value::Int(i64::MAX).add(&Value::Int(1)).is_err());
+        assert!(Value::Float(f64::MAX).add(&Value::Float(f64::MAX)).is_err());
+        assert!(Value::Float(f64::MIN).add(&Value::Int(i64::MIN)).is_ok());
+    }
+}
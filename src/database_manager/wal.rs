@@ -0,0 +1,622 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use anyhow::{anyhow, bail};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+use tracing::{info, warn};
+
+use super::{
+    catalog::{Catalog, CollectionEntry},
+    collection::Collection,
+    error::DbError,
+    format::{self, FileKind, FormatError},
+    index::IndexDefinition,
+    store,
+    value::Value,
+};
+
+/// The log is `journal.lwal` in its directory.
+pub const WAL_NAME: &str = "journal";
+
+/// Size past which a database log is checkpointed.
+pub const CHECKPOINT_SIZE: u64 = 4 * 1024 * 1024;
+
+/// A dropped database is renamed with this prefix before it is removed. Names never start
+/// with a dot, so these directories can't be databases.
+pub const DROPPED_PREFIX: &str = ".dropped-";
+
+/// A change logged before it is applied, so it can be redone after a crash. Records hold
+/// the state an operation leads to rather than the operation itself, which makes replaying
+/// one that was already applied harmless.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    CreateDatabase {
+        name: String,
+    },
+    DropDatabase {
+        name: String,
+    },
+    CreateCollection {
+        name: String,
+    },
+    DropCollection {
+        name: String,
+    },
+    /// Inserted or updated documents, each with its `_id`.
+    Write {
+        collection: String,
+        documents: Vec<Value>,
+    },
+    Delete {
+        collection: String,
+        ids: Vec<String>,
+    },
+    CreateIndex {
+        collection: String,
+        definition: IndexDefinition,
+    },
+    DropIndex {
+        collection: String,
+        name: String,
+    },
+    /// The writes of a committed transaction, which are redone all together or not at all.
+    Transaction {
+        records: Vec<Record>,
+    },
+}
+
+impl Record {
+    fn to_value(&self) -> Value {
+        let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+
+        let (op, name): (&str, &str) = match self {
+            Self::CreateDatabase { name } => ("create_database", name),
+            Self::DropDatabase { name } => ("drop_database", name),
+            Self::CreateCollection { name } => ("create_collection", name),
+            Self::DropCollection { name } => ("drop_collection", name),
+            Self::Write {
+                collection,
+                documents,
+            } => {
+                fields.insert("documents".into(), Value::Array(documents.clone()));
+
+                ("write", collection)
+            }
+            Self::Delete { collection, ids } => {
+                fields.insert(
+                    "ids".into(),
+                    Value::Array(ids.iter().cloned().map(Value::String).collect()),
+                );
+
+                ("delete", collection)
+            }
+            Self::CreateIndex {
+                collection,
+                definition,
+            } => {
+                fields.insert("index".into(), definition.to_value());
+
+                ("create_index", collection)
+            }
+            Self::DropIndex { collection, name } => {
+                fields.insert("index".into(), Value::String(name.clone()));
+
+                ("drop_index", collection)
+            }
+            Self::Transaction { records } => {
+                fields.insert(
+                    "records".into(),
+                    Value::Array(records.iter().map(Self::to_value).collect()),
+                );
+
+                ("transaction", "")
+            }
+        };
+
+        fields.insert("op".into(), Value::String(op.into()));
+        fields.insert("name".into(), Value::String(name.into()));
+
+        Value::Object(fields)
+    }
+
+    fn from_value(value: Value) -> anyhow::Result<Self> {
+        let Value::Object(mut fields) = value else {
+            bail!("record is not an object");
+        };
+
+        let Some(Value::String(op)) = fields.remove("op") else {
+            bail!("record has no operation");
+        };
+
+        let Some(Value::String(name)) = fields.remove("name") else {
+            bail!("record has no name");
+        };
+
+        Ok(match op.as_str() {
+            "create_database" => Self::CreateDatabase { name },
+            "drop_database" => Self::DropDatabase { name },
+            "create_collection" => Self::CreateCollection { name },
+            "drop_collection" => Self::DropCollection { name },
+            "write" => {
+                let Some(Value::Array(documents)) = fields.remove("documents") else {
+                    bail!("write record has no documents");
+                };
+
+                Self::Write {
+                    collection: name,
+                    documents,
+                }
+            }
+            "delete" => {
+                let Some(Value::Array(ids)) = fields.remove("ids") else {
+                    bail!("delete record has no ids");
+                };
+
+                let ids: Vec<String> = ids
+                    .into_iter()
+                    .map(|id| match id {
+                        Value::String(id) => Ok(id),
+                        id => Err(anyhow!("invalid document id {id}")),
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+
+                Self::Delete {
+                    collection: name,
+                    ids,
+                }
+            }
+            "create_index" => {
+                let Some(definition) = fields.remove("index") else {
+                    bail!("create_index record has no index");
+                };
+
+                Self::CreateIndex {
+                    collection: name,
+                    definition: IndexDefinition::from_value(definition)?,
+                }
+            }
+            "drop_index" => {
+                let Some(Value::String(index)) = fields.remove("index") else {
+                    bail!("drop_index record has no index");
+                };
+
+                Self::DropIndex {
+                    collection: name,
+                    name: index,
+                }
+            }
+            "transaction" => {
+                let Some(Value::Array(records)) = fields.remove("records") else {
+                    bail!("transaction record has no records");
+                };
+
+                Self::Transaction {
+                    records: records
+                        .into_iter()
+                        .map(Self::from_value)
+                        .collect::<anyhow::Result<_>>()?,
+                }
+            }
+            op => bail!("unknown operation \"{op}\""),
+        })
+    }
+}
+
+/// The write-ahead log of a database directory. The store directory has one too, for
+/// `CREATE DB` and `DROP DB`.
+#[derive(Debug)]
+pub struct Wal {
+    file: Mutex<fs::File>,
+    len: AtomicU64,
+    /// Set once the database was dropped, so writers that were waiting can't log to it.
+    closed: AtomicBool,
+}
+
+impl Wal {
+    pub async fn open(dir: &Path) -> anyhow::Result<Self> {
+        let mut file: fs::File = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(wal_path(dir))
+            .await?;
+
+        if file.metadata().await?.len() == 0 {
+            let mut header: Vec<u8> = vec![];
+
+            format::write_header(FileKind::Log, &mut header);
+
+            file.write_all(&header).await?;
+            file.sync_data().await?;
+        }
+
+        let len: u64 = file.metadata().await?.len();
+
+        Ok(Self {
+            file: Mutex::new(file),
+            len: AtomicU64::new(len),
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Appends a record and only returns once it reached the disk.
+    pub async fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let mut payload: Vec<u8> = vec![];
+
+        record.to_value().encode(&mut payload);
+
+        let mut bytes: Vec<u8> = vec![];
+
+        format::write_record(&payload, &mut bytes);
+
+        let mut file = self.file.lock().await;
+
+        if self.closed.load(Ordering::Acquire) {
+            bail!(DbError::NotFound("the database was dropped".to_string()));
+        }
+
+        file.write_all(&bytes).await?;
+        file.sync_data().await?;
+
+        self.len.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    pub async fn close(&self) {
+        let _file = self.file.lock().await;
+
+        self.closed.store(true, Ordering::Release);
+    }
+
+    /// Drops every record, once what they describe is durable elsewhere.
+    pub async fn reset(&self) -> anyhow::Result<()> {
+        let file = self.file.lock().await;
+
+        file.set_len(format::HEADER_SIZE as u64).await?;
+        file.sync_data().await?;
+
+        self.len
+            .store(format::HEADER_SIZE as u64, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+/// Redoes the operations logged in the store directory and in every database directory,
+/// then empties the logs. Has to run before any session opens the store.
+pub async fn replay(store_path: &str) -> anyhow::Result<()> {
+    let store_path: &Path = Path::new(store_path);
+
+    // Only the last operation on a database matters: replaying an older drop would delete
+    // a database that was created again since, along with everything written to it
+    let mut databases: HashMap<String, bool> = HashMap::new();
+
+    for record in read_records(store_path).await? {
+        match record {
+            Record::CreateDatabase { name } => databases.insert(name, true),
+            Record::DropDatabase { name } => databases.insert(name, false),
+            record => {
+                warn!("Ignoring {record:?} logged outside of a database");
+                continue;
+            }
+        };
+    }
+
+    for (name, exists) in databases {
+        let path: PathBuf = store_path.join(name);
+
+        if exists {
+            fs::create_dir_all(&path).await?;
+        } else {
+            remove_dir_all(&path).await?;
+        }
+    }
+
+    let mut entries: fs::ReadDir = fs::read_dir(store_path).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+
+        // Left behind by a drop that didn't finish
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(DROPPED_PREFIX)
+        {
+            remove_dir_all(&entry.path()).await?;
+
+            continue;
+        }
+
+        replay_database(&entry.path()).await?;
+    }
+
+    format::sync_dir(store_path)?;
+    truncate(store_path).await
+}
+
+async fn replay_database(path: &Path) -> anyhow::Result<()> {
+    let records: Vec<Record> = read_records(path).await?;
+
+    // Still truncated when empty, as a torn record would corrupt the next append
+    if records.is_empty() {
+        return truncate(path).await;
+    }
+
+    info!(
+        "Replaying {} logged operations in \"{}\"",
+        records.len(),
+        path.display()
+    );
+
+    let mut catalog: Catalog = Catalog::load(path)?;
+    let mut collections: HashMap<String, Collection> = HashMap::new();
+
+    // A transaction is logged as a single record, so it is either read whole or dropped
+    // with a torn tail
+    let records = records.into_iter().flat_map(|record| match record {
+        Record::Transaction { records } => records,
+        record => vec![record],
+    });
+
+    for record in records {
+        match record {
+            Record::CreateCollection { name } => {
+                open_collection(&mut collections, &mut catalog, path, &name)?;
+            }
+            Record::DropCollection { name } => {
+                collections.remove(&name);
+                catalog.remove_collection(&name)?;
+
+                remove_dir_all(&path.join(name)).await?;
+            }
+            Record::Write {
+                collection,
+                documents,
+            } => {
+                let collection: &mut Collection =
+                    open_collection(&mut collections, &mut catalog, path, &collection)?;
+
+                for document in documents {
+                    collection.upsert(document)?;
+                }
+            }
+            Record::Delete { collection, ids } => {
+                open_collection(&mut collections, &mut catalog, path, &collection)?.delete(&ids)?;
+            }
+            Record::CreateIndex {
+                collection,
+                definition,
+            } => {
+                let collection: &mut Collection =
+                    open_collection(&mut collections, &mut catalog, path, &collection)?;
+
+                if collection.index(&definition.name).is_none() {
+                    collection.create_index(definition.clone())?;
+                    catalog.add_index(&collection.name, definition)?;
+                }
+            }
+            Record::DropIndex { collection, name } => {
+                let collection: &mut Collection =
+                    open_collection(&mut collections, &mut catalog, path, &collection)?;
+
+                if collection.index(&name).is_some() {
+                    collection.drop_index(&name)?;
+                    catalog.remove_index(&collection.name, &name)?;
+                }
+            }
+            record => warn!("Ignoring {record:?} logged inside a database"),
+        }
+    }
+
+    for collection in collections.values_mut() {
+        collection.flush()?;
+    }
+
+    format::sync_dir(path)?;
+
+    truncate(path).await
+}
+
+/// Returns the collection called `name`, creating it if needed: a collection written to
+/// in the log may have been dropped further down.
+fn open_collection<'a>(
+    collections: &'a mut HashMap<String, Collection>,
+    catalog: &mut Catalog,
+    path: &Path,
+    name: &str,
+) -> anyhow::Result<&'a mut Collection> {
+    if !collections.contains_key(name) {
+        catalog.add_collection(name)?;
+
+        let entry: CollectionEntry = catalog
+            .collection(name)
+            .cloned()
+            .expect("collection was just added");
+
+        collections.insert(name.to_string(), store::open_collection(path, name, entry)?);
+    }
+
+    Ok(collections
+        .get_mut(name)
+        .expect("collection was just opened"))
+}
+
+/// Reads the records of a log. A crash during an append leaves a torn last record, which
+/// is dropped since its operation was never applied. Any other damaged record fails the
+/// replay, so the records after it aren't lost by truncating the log.
+async fn read_records(dir: &Path) -> anyhow::Result<Vec<Record>> {
+    let path: PathBuf = wal_path(dir);
+
+    let bytes: Vec<u8> = match fs::read(&path).await {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut remaining: &[u8] = match format::read_header(&bytes, FileKind::Log) {
+        Ok(remaining) => remaining,
+        Err(FormatError::Truncated) => return Ok(vec![]),
+        Err(err) => bail!("can't replay \"{}\": {err}", path.display()),
+    };
+
+    let mut records: Vec<Record> = vec![];
+
+    loop {
+        let torn: bool = is_last_record(remaining);
+
+        let record: anyhow::Result<Record> = match format::read_record(&mut remaining) {
+            Ok(Some(mut payload)) => Value::decode(&mut payload).and_then(Record::from_value),
+            Ok(None) => break,
+            Err(FormatError::Truncated) => {
+                warn!("Dropping the torn last record of \"{}\"", path.display());
+                break;
+            }
+            // The length reached the disk but not all of the payload
+            Err(FormatError::ChecksumMismatch) if torn => {
+                warn!("Dropping the torn last record of \"{}\"", path.display());
+                break;
+            }
+            Err(err) => Err(err.into()),
+        };
+
+        match record {
+            Ok(record) => records.push(record),
+            Err(err) => bail!(
+                "can't replay \"{}\": record {} is corrupt ({err}) and isn't the last one, \
+                 move the log aside to start without the operations it holds",
+                path.display(),
+                records.len() + 1
+            ),
+        }
+    }
+
+    Ok(records)
+}
+
+/// Whether the record at the start of `bytes` ends the log.
+fn is_last_record(bytes: &[u8]) -> bool {
+    let Some(length) = bytes.first_chunk::<4>() else {
+        return true;
+    };
+
+    8 + u32::from_le_bytes(*length) as usize >= bytes.len()
+}
+
+/// Empties a log once its records were applied.
+async fn truncate(dir: &Path) -> anyhow::Result<()> {
+    let mut file: fs::File = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(wal_path(dir))
+        .await?;
+
+    let mut header: Vec<u8> = vec![];
+
+    format::write_header(FileKind::Log, &mut header);
+
+    file.write_all(&header).await?;
+    file.sync_all().await?;
+
+    Ok(())
+}
+
+fn wal_path(dir: &Path) -> PathBuf {
+    dir.join(WAL_NAME).with_extension(FileKind::Log.extension())
+}
+
+/// Removes the directory at `path`, if there is one.
+pub async fn remove_dir_all(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_dir_all(path).await {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{read_records, replay_database, wal_path, Record, Wal};
+    use crate::database_manager::format;
+
+    fn record(i: usize) -> Record {
+        Record::CreateCollection {
+            name: format!("c{i}"),
+        }
+    }
+
+    async fn log(name: &str, count: usize) -> (PathBuf, Vec<u8>) {
+        let dir: PathBuf = format::test_dir(name);
+        let wal: Wal = Wal::open(&dir).await.unwrap();
+
+        for i in 0..count {
+            wal.append(&record(i)).await.unwrap();
+        }
+
+        let bytes: Vec<u8> = std::fs::read(wal_path(&dir)).unwrap();
+
+        (dir, bytes)
+    }
+
+    #[tokio::test]
+    async fn records_round_trip() {
+        let (dir, _) = log("records_round_trip", 3).await;
+
+        assert_eq!(
+            read_records(&dir).await.unwrap(),
+            (0..3).map(record).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn torn_tail_is_dropped() {
+        let (dir, bytes) = log("torn_tail_is_dropped", 3).await;
+
+        // Cut in the payload of the last record
+        std::fs::write(wal_path(&dir), &bytes[..bytes.len() - 3]).unwrap();
+
+        assert_eq!(
+            read_records(&dir).await.unwrap(),
+            (0..2).map(record).collect::<Vec<_>>()
+        );
+
+        // Whole, but with a payload that never reached the disk
+        let mut damaged: Vec<u8> = bytes.clone();
+        let last: usize = damaged.len() - 1;
+
+        damaged[last] ^= 0xff;
+
+        std::fs::write(wal_path(&dir), &damaged).unwrap();
+
+        assert_eq!(read_records(&dir).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn corruption_before_the_tail_fails() {
+        let (dir, mut bytes) = log("corruption_before_the_tail_fails", 3).await;
+
+        // In the payload of the first record
+        bytes[format::HEADER_SIZE + 10] ^= 0xff;
+
+        std::fs::write(wal_path(&dir), &bytes).unwrap();
+
+        assert!(replay_database(&dir).await.is_err());
+        assert_eq!(std::fs::read(wal_path(&dir)).unwrap(), bytes);
+    }
+}
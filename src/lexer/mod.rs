@@ -42,7 +42,7 @@ impl<'a> Iterator for Lexer<'a> {
             Some((tok_type, span)) => Some(Token::new(
                 self.input[..span.start].matches('\n').count(),
                 span.start - self.input[..span.start].rfind('\n').map_or(0, |i| i + 1),
-                tok_type.ok()?,
+                tok_type.unwrap_or_else(TokenType::Error),
                 &self.input[span.start..span.end],
                 span.into(),
             )),
@@ -50,3 +50,135 @@ impl<'a> Iterator for Lexer<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        token::{LexError, Span, Token, TokenType},
+        Lexer,
+    };
+    use crate::token_list::TokenList;
+
+    /// The tokens of `input`, without whitespace and comments.
+    fn tokens(input: &str) -> Vec<Token<'_>> {
+        TokenList::from(Lexer::new(input)).tokens
+    }
+
+    fn types(input: &str) -> Vec<TokenType<'_>> {
+        tokens(input)
+            .into_iter()
+            .map(|token| token.tok_type)
+            .collect()
+    }
+
+    #[test]
+    fn numbers_have_no_sign() {
+        assert_eq!(
+            types("5-1"),
+            [
+                TokenType::Integer(5),
+                TokenType::Minus,
+                TokenType::Integer(1)
+            ]
+        );
+        assert_eq!(
+            types("x-1"),
+            [
+                TokenType::Identifier("x"),
+                TokenType::Minus,
+                TokenType::Integer(1)
+            ]
+        );
+        assert_eq!(
+            types("-2.5e3"),
+            [TokenType::Minus, TokenType::Float(2500.0)]
+        );
+        assert_eq!(
+            types("99999999999999999999"),
+            [TokenType::Error(LexError::NumberOutOfRange)]
+        );
+    }
+
+    #[test]
+    fn literals_and_punctuation() {
+        assert_eq!(
+            types(r#"{a: "b\"c", n: [1, 2.5, true, null]};"#),
+            [
+                TokenType::LeftBrace,
+                TokenType::Identifier("a"),
+                TokenType::Colon,
+                TokenType::String(r#"b\"c"#),
+                TokenType::Comma,
+                TokenType::Identifier("n"),
+                TokenType::Colon,
+                TokenType::LeftBracket,
+                TokenType::Integer(1),
+                TokenType::Comma,
+                TokenType::Float(2.5),
+                TokenType::Comma,
+                TokenType::Boolean(true),
+                TokenType::Comma,
+                TokenType::NullValue,
+                TokenType::RightBracket,
+                TokenType::RightBrace,
+                TokenType::Semicolon,
+            ]
+        );
+        assert_eq!(
+            types(r#""open"#),
+            [TokenType::Error(LexError::UnterminatedString)]
+        );
+        assert_eq!(
+            types(r#""\q""#),
+            [TokenType::Error(LexError::InvalidEscape)]
+        );
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(
+            types("use shop -- the rest\n/* a\nblock */;"),
+            [
+                TokenType::Use,
+                TokenType::Identifier("shop"),
+                TokenType::Semicolon
+            ]
+        );
+        assert_eq!(
+            types("/* open"),
+            [TokenType::Error(LexError::UnterminatedComment)]
+        );
+    }
+
+    #[test]
+    fn unknown_characters_keep_their_place() {
+        let tokens: Vec<Token> = tokens("find x\n  @ y");
+
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(
+            tokens[2].tok_type,
+            TokenType::Error(LexError::UnexpectedCharacter)
+        );
+        assert_eq!((tokens[2].line, tokens[2].column), (1, 2));
+        assert_eq!(tokens[2].span, Span { start: 9, end: 10 });
+        assert_eq!(tokens[3].tok_type, TokenType::Identifier("y"));
+    }
+
+    #[test]
+    fn keywords_ignore_case() {
+        let tokens: Vec<Token> = tokens("CREATE Db Shop");
+
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| token.tok_type)
+                .collect::<Vec<TokenType>>(),
+            [
+                TokenType::Create,
+                TokenType::Db,
+                TokenType::Identifier("Shop")
+            ]
+        );
+        assert_eq!(tokens[1].slice, "Db");
+    }
+}
@@ -1,58 +1,251 @@
 use core::fmt;
 use std::fmt::{Display, Formatter};
 
-use logos::Logos;
+use logos::{Lexer, Logos};
+
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub enum LexError {
+    #[default]
+    UnexpectedCharacter,
+    UnterminatedString,
+    InvalidEscape,
+    NumberOutOfRange,
+    UnterminatedComment,
+}
+
+impl Display for LexError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let message: &str = match self {
+            Self::UnexpectedCharacter => "unexpected character",
+            Self::UnterminatedString => "unterminated string",
+            Self::InvalidEscape => "invalid escape sequence",
+            Self::NumberOutOfRange => "number out of range",
+            Self::UnterminatedComment => "unterminated comment",
+        };
+
+        write!(f, "{message}")
+    }
+}
 
 #[derive(Logos, Debug, PartialEq, Copy, Clone)]
+#[logos(error = LexError)]
 pub enum TokenType<'a> {
     // Database management
-    #[token("create")]
+    #[token("create", ignore(case))]
     Create,
 
-    #[token("use")]
+    #[token("use", ignore(case))]
     Use,
 
-    #[token("drop")]
+    #[token("drop", ignore(case))]
     Drop,
 
-    #[token("collection")]
+    #[token("collection", ignore(case))]
     Collection,
 
-    #[token("db")]
+    #[token("db", ignore(case))]
     Db,
 
-    #[token("dbs")]
+    #[token("dbs", ignore(case))]
     Dbs,
 
+    #[token("index", ignore(case))]
+    Index,
+
+    #[token("indexes", ignore(case))]
+    Indexes,
+
+    #[token("unique", ignore(case))]
+    Unique,
+
+    #[token("on", ignore(case))]
+    On,
+
+    #[token("vacuum", ignore(case))]
+    Vacuum,
+
     #[regex("[a-zA-Z_][a-zA-Z0-9_]*")]
     Identifier(&'a str),
 
     // Document manipulation
-    #[token("insert")]
+    #[token("insert", ignore(case))]
     Insert,
 
-    #[token("update")]
+    #[token("update", ignore(case))]
     Update,
 
-    #[token("delete")]
+    #[token("delete", ignore(case))]
     Delete,
 
-    #[token("find")]
+    #[token("find", ignore(case))]
     Find,
 
-    #[token("help")]
+    #[token("help", ignore(case))]
     Help,
 
-    #[token("list")]
+    #[token("exit", ignore(case))]
+    #[token("quit", ignore(case))]
+    Exit,
+
+    #[token("list", ignore(case))]
     List,
 
-    #[token("show")]
+    #[token("show", ignore(case))]
     Show,
 
+    #[token("into", ignore(case))]
+    Into,
+
+    #[token("from", ignore(case))]
+    From,
+
+    // Transactions
+    #[token("begin", ignore(case))]
+    Begin,
+
+    #[token("commit", ignore(case))]
+    Commit,
+
+    #[token("rollback", ignore(case))]
+    Rollback,
+
+    // Cursors
+    #[token("batch", ignore(case))]
+    Batch,
+
+    #[token("next", ignore(case))]
+    Next,
+
+    #[token("close", ignore(case))]
+    Close,
+
+    // Users
+    #[token("user", ignore(case))]
+    User,
+
+    #[token("password", ignore(case))]
+    Password,
+
+    // Filters
+    #[token("where", ignore(case))]
+    Where,
+
+    #[token("and", ignore(case))]
+    And,
+
+    #[token("or", ignore(case))]
+    Or,
+
+    #[token("in", ignore(case))]
+    In,
+
+    #[token("exists", ignore(case))]
+    Exists,
+
+    // Updates
+    #[token("set", ignore(case))]
+    Set,
+
+    #[token("unset", ignore(case))]
+    Unset,
+
+    #[token("inc", ignore(case))]
+    Inc,
+
+    // Operators
+    #[token("=")]
+    Equal,
+
+    #[token("!=")]
+    NotEqual,
+
+    #[token("<")]
+    Less,
+
+    #[token("<=")]
+    LessEqual,
+
+    #[token(">")]
+    Greater,
+
+    #[token(">=")]
+    GreaterEqual,
+
+    // Arithmetic
+    #[token("+")]
+    Plus,
+
+    #[token("-")]
+    Minus,
+
+    #[token("*")]
+    Star,
+
+    #[token("/")]
+    Slash,
+
+    #[token("%")]
+    Percent,
+
+    // Literals
+    #[regex(r#""([^"\\]|\\.)*""#, string_literal)]
+    #[regex(r#""([^"\\]|\\.)*"#, |_| Err(LexError::UnterminatedString))]
+    String(&'a str),
+
+    /// Numbers are lexed without a sign, so `x-1` is a subtraction. The parser reads a
+    /// leading `-` as a unary minus.
+    #[regex("[0-9]+", |lex| lex.slice().parse::<u64>().map_err(|_| LexError::NumberOutOfRange))]
+    Integer(u64),
+
+    #[regex(r"[0-9]+\.[0-9]+([eE][+-]?[0-9]+)?", float_literal)]
+    #[regex(r"[0-9]+[eE][+-]?[0-9]+", float_literal)]
+    Float(f64),
+
+    #[token("true", |_| true, ignore(case))]
+    #[token("false", |_| false, ignore(case))]
+    Boolean(bool),
+
+    #[token("null", ignore(case))]
+    NullValue,
+
+    // Punctuation
+    #[token("{")]
+    LeftBrace,
+
+    #[token("}")]
+    RightBrace,
+
+    #[token("[")]
+    LeftBracket,
+
+    #[token("]")]
+    RightBracket,
+
+    #[token("(")]
+    LeftParen,
+
+    #[token(")")]
+    RightParen,
+
+    #[token(":")]
+    Colon,
+
+    #[token(",")]
+    Comma,
+
+    #[token(".")]
+    Dot,
+
+    #[token(";")]
+    Semicolon,
+
     // Misc
     #[token("\n")]
     LineFeed,
 
+    #[token("\r")]
+    CarriageReturn,
+
     #[token(" ")]
     Space,
 
@@ -61,6 +254,89 @@ pub enum TokenType<'a> {
 
     #[token("\0")]
     Null,
+
+    #[regex("--[^\n]*", allow_greedy = true)]
+    #[regex(r"/\*([^*]|\*+[^*/])*\*+/")]
+    #[regex(r"/\*([^*]|\*+[^*/])*\**", unterminated_comment)]
+    Comment,
+
+    /// Input the lexer couldn't make sense of. It is kept as a token, so the parser can
+    /// report it with its position instead of the command being silently cut short.
+    Error(LexError),
+}
+
+fn string_literal<'a>(lex: &Lexer<'a, TokenType<'a>>) -> Result<&'a str, LexError> {
+    let slice: &'a str = lex.slice();
+    let contents: &'a str = &slice[1..slice.len() - 1];
+
+    let mut chars = contents.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            continue;
+        }
+
+        match chars.next() {
+            Some('"' | '\\' | '/' | 'n' | 'r' | 't' | '0') => {}
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+
+                if code.len() != 4
+                    || u32::from_str_radix(&code, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .is_none()
+                {
+                    return Err(LexError::InvalidEscape);
+                }
+            }
+            _ => return Err(LexError::InvalidEscape),
+        }
+    }
+
+    Ok(contents)
+}
+
+fn unterminated_comment<'a>(_: &Lexer<'a, TokenType<'a>>) -> Result<(), LexError> {
+    Err(LexError::UnterminatedComment)
+}
+
+fn float_literal<'a>(lex: &Lexer<'a, TokenType<'a>>) -> Result<f64, LexError> {
+    match lex.slice().parse::<f64>() {
+        Ok(float) if float.is_finite() => Ok(float),
+        _ => Err(LexError::NumberOutOfRange),
+    }
+}
+
+/// Resolves the escape sequences of a string literal the lexer already validated.
+pub fn unescape(contents: &str) -> String {
+    let mut unescaped: String = String::with_capacity(contents.len());
+    let mut chars = contents.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some('0') => unescaped.push('\0'),
+            Some('u') => {
+                let code: String = chars.by_ref().take(4).collect();
+
+                if let Some(c) = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    unescaped.push(c);
+                }
+            }
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+
+    unescaped
 }
 
 impl Display for TokenType<'_> {
@@ -100,4 +376,17 @@ impl<'a> Token<'a> {
             span,
         }
     }
+
+    /// Whether the token can be used as a field name. Keywords count too, so fields
+    /// such as `db` or `find` don't need quoting.
+    pub fn is_word(&self) -> bool {
+        self.slice
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && self
+                .slice
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
 }
@@ -1,10 +1,21 @@
-use database_manager::{configuration::RawConfig, Database};
-use lexer::token::TokenType;
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use database_manager::{
+    configuration::RawConfig,
+    error::DbError,
+    store::{self, Store},
+    users, wal, Database, Output,
+};
+use parser::Parser;
+use std::{sync::Arc, time::Duration};
 use token_list::TokenList;
 use tokio::{signal, sync::Mutex};
 use tonic::transport::{Server, ServerTlsConfig};
-use tonic_grpc_manager::MyLilDBShell;
+use tonic_grpc_manager::{
+    auth::Authenticator,
+    data_service::MyLilDBData,
+    send_output,
+    sessions::{self, Sessions},
+    MyLilDBShell, ResponseSender,
+};
 use tracing::{error, info};
 
 #[cfg(feature = "tracy")]
@@ -12,11 +23,15 @@ use std::alloc::System;
 
 use crate::{
     database_manager::configuration::Config,
-    lildb::lil_db_shell_service_server::LilDbShellServiceServer,
+    lildb::{
+        lil_db_data_service_server::LilDbDataServiceServer,
+        lil_db_shell_service_server::LilDbShellServiceServer,
+    },
 };
 
 mod database_manager;
 mod lexer;
+mod parser;
 mod token_list;
 mod tonic_grpc_manager;
 
@@ -29,29 +44,69 @@ pub mod lildb {
 static GLOBAL: tracy_client::ProfiledAllocator<System> =
     tracy_client::ProfiledAllocator::new(System, 100);
 
+/// Runs every `;` separated statement of `input`, sending the result of each one to `tx`
+/// as soon as it is ready. Execution stops at the first failing statement unless
+/// `continue_on_error` is set. Returns whether the stream is over: the session asked to
+/// exit, a fatal error ended it or the client went away.
 async fn lex_input(
     input: String,
+    continue_on_error: bool,
     database: Arc<Mutex<Database>>,
-) -> anyhow::Result<(String, bool, Arc<Mutex<Database>>)> {
-    let lexer: lexer::Lexer<'_> = lexer::Lexer::new(&input);
-
-    let mut token_list: TokenList = TokenList::new(vec![]);
-
-    for token in lexer {
-        if token.tok_type != TokenType::Null
-            && token.tok_type != TokenType::Space
-            && token.tok_type != TokenType::LineFeed
-            && token.tok_type != TokenType::Tab
-        {
-            token_list.tokens.push(token);
+    tx: &ResponseSender,
+) -> bool {
+    let parser: Parser = Parser::new(TokenList::from(lexer::Lexer::new(&input)));
+    let mut ran: bool = false;
+
+    for statement in parser {
+        ran = true;
+
+        // The session is unlocked again before the output is sent
+        let result: Result<(Output, bool), DbError> = match statement {
+            Ok(command) => database
+                .lock()
+                .await
+                .process_command(command)
+                .await
+                .map_err(DbError::from),
+            Err(err) => Err(err.into()),
+        };
+
+        // The rows of an output may still fail once part of them is sent
+        let result: Result<bool, DbError> = match result {
+            Ok((output, should_exit)) => send_output(tx, output)
+                .await
+                .map(|sent| !sent || should_exit),
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(err) if err.is_fatal() => {
+                let _ = tx.send(Err(err.into())).await;
+
+                return true;
+            }
+            Err(err) => {
+                if tx.send(Ok(err.into())).await.is_err() {
+                    return true;
+                }
+
+                if !continue_on_error {
+                    break;
+                }
+            }
         }
     }
 
-    token_list.current_token = token_list.tokens[0];
+    // Input with nothing but separators and comments still gets an answer
+    if !ran {
+        let output: Output = Output::Message("No statements to run\n\r".to_string());
 
-    let (result, exit) = database.lock().await.process_tokens(token_list).await?;
+        return !matches!(send_output(tx, output).await, Ok(true));
+    }
 
-    Ok((result, exit, database))
+    false
 }
 
 #[allow(clippy::needless_return)]
@@ -69,13 +124,16 @@ async fn main() -> anyhow::Result<()> {
     let config: Config = config.check_config().await?;
     let config_arc: Arc<Config> = Arc::new(config);
 
-    let database: Database = Database::new(
-        String::new(),
-        String::new(),
-        HashSet::new(),
-        0_usize,
-        config_arc.clone(),
-    );
+    // Finish whatever a crash interrupted before any session can see the store
+    wal::replay(&config_arc.store_path).await?;
+
+    let store: Arc<Store> = Arc::new(Store::new());
+    let sessions: Sessions = Sessions::new(config_arc.clone(), store.clone());
+
+    tokio::spawn(store::compact_periodically(
+        store.clone(),
+        config_arc.compaction.clone(),
+    ));
 
     let server: Server = Server::builder();
 
@@ -85,12 +143,27 @@ async fn main() -> anyhow::Result<()> {
         server
     };
 
-    let ddb_shell: MyLilDBShell = MyLilDBShell::new(Arc::new(Mutex::new(database)));
+    let sessions: Arc<Sessions> = Arc::new(sessions);
+
+    users::init(&sessions.new_database()).await?;
+
+    tokio::spawn(sessions::expire_cursors_periodically(sessions.clone()));
+    tokio::spawn(sessions::expire_transactions_periodically(sessions.clone()));
+
+    let ddb_shell: MyLilDBShell = MyLilDBShell::new(sessions.clone());
+    let ddb_data: MyLilDBData = MyLilDBData::new(sessions);
 
     let server = server
         .http2_keepalive_interval(Some(Duration::from_secs(5)))
         .http2_keepalive_timeout(Some(Duration::from_secs(10)))
-        .add_service(LilDbShellServiceServer::new(ddb_shell))
+        .add_service(LilDbShellServiceServer::with_interceptor(
+            ddb_shell,
+            Authenticator::new(store.clone()),
+        ))
+        .add_service(LilDbDataServiceServer::with_interceptor(
+            ddb_data,
+            Authenticator::strict(store.clone()),
+        ))
         .serve(config_arc.address.use_addr.parse()?);
 
     let is_http_or_s = if config_arc.id.is_some() {
@@ -114,5 +187,8 @@ async fn main() -> anyhow::Result<()> {
         _ = signal::ctrl_c() => info!("Ctrl+C received, shutting down"),
     }
 
+    // Write the buffered pages back, so the next start has no log to replay
+    store.checkpoint().await?;
+
     Ok(())
 }
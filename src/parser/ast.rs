@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+use crate::database_manager::{
+    filter::Filter, index::IndexDefinition, update::Update, value::Value,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    CreateDatabase {
+        name: String,
+    },
+    CreateCollection {
+        name: String,
+    },
+    DropDatabase {
+        name: String,
+    },
+    DropCollection {
+        name: String,
+    },
+    /// `CREATE [UNIQUE] INDEX <name> ON <collection>(<field>, ...)`
+    CreateIndex {
+        collection: String,
+        definition: IndexDefinition,
+    },
+    /// `DROP INDEX <name> ON <collection>`
+    DropIndex {
+        collection: String,
+        name: String,
+    },
+    Use {
+        name: String,
+    },
+    ShowDatabases,
+    /// `SHOW <database_name>`, or the current database when no name is given.
+    ShowCollections {
+        database: Option<String>,
+    },
+    ShowIndexes {
+        collection: String,
+    },
+    /// `VACUUM <collection>`
+    Vacuum {
+        collection: String,
+    },
+    /// `CREATE USER <name> PASSWORD "<password>"`
+    CreateUser {
+        name: String,
+        password: String,
+    },
+    /// `DROP USER <name>`
+    DropUser {
+        name: String,
+    },
+    Help,
+    /// `EXIT` or `QUIT`, which ends the `RunCommand` stream.
+    Exit,
+    Begin,
+    Commit,
+    Rollback,
+    /// `NEXT <cursor>`
+    Next {
+        cursor: u64,
+    },
+    /// `CLOSE <cursor>`
+    Close {
+        cursor: u64,
+    },
+    Insert {
+        collection: String,
+        document: BTreeMap<String, Value>,
+    },
+    /// `FIND <collection> [WHERE ...] [BATCH <size>]`, which keeps what doesn't fit in the
+    /// first batch in a cursor.
+    Find {
+        collection: String,
+        filter: Option<Filter>,
+        batch: Option<usize>,
+    },
+    Update {
+        collection: String,
+        update: Update,
+        filter: Option<Filter>,
+    },
+    Delete {
+        collection: String,
+        filter: Option<Filter>,
+    },
+}
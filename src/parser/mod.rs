@@ -0,0 +1,842 @@
+pub(crate) mod ast;
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{
+    database_manager::{
+        document::ID_FIELD,
+        filter::{Filter, Operator},
+        index::IndexDefinition,
+        update::{Modification, Update},
+        value::Value,
+    },
+    lexer::token::{unescape, LexError, Span, Token, TokenType},
+    token_list::TokenList,
+};
+
+use self::ast::Command;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
+}
+
+impl ParseError {
+    pub fn new(message: String, line: usize, column: usize, span: Span) -> Self {
+        Self {
+            message,
+            line,
+            column,
+            span,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (line {}, column {})",
+            self.message,
+            self.line + 1,
+            self.column + 1
+        )
+    }
+}
+
+impl Error for ParseError {}
+
+pub struct Parser<'a> {
+    token_list: TokenList<'a>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(token_list: TokenList<'a>) -> Self {
+        Self { token_list }
+    }
+
+    /// Parses one command, failing if anything but `;` is left after it.
+    fn parse_statement(&mut self) -> Result<Command, ParseError> {
+        self.check_lex_errors()?;
+
+        if self.at_statement_end() {
+            return Err(self.expected("a command"));
+        }
+
+        let command: Command = match self.token_list.current_token.tok_type {
+            TokenType::Create => {
+                self.token_list.next(1);
+
+                if self.eat(TokenType::Db) {
+                    Command::CreateDatabase {
+                        name: self.parse_name("database")?,
+                    }
+                } else if self.eat(TokenType::Collection) {
+                    Command::CreateCollection {
+                        name: self.parse_name("collection")?,
+                    }
+                } else if self.eat(TokenType::Unique) {
+                    self.expect(TokenType::Index, "\"index\"")?;
+
+                    self.parse_create_index(true)?
+                } else if self.eat(TokenType::Index) {
+                    self.parse_create_index(false)?
+                } else if self.eat(TokenType::User) {
+                    let name: String = self.parse_name("user")?;
+
+                    self.expect(TokenType::Password, "\"password\"")?;
+
+                    Command::CreateUser {
+                        name,
+                        password: self.parse_password()?,
+                    }
+                } else {
+                    return Err(self.expected("\"db\", \"collection\", \"index\" or \"user\""));
+                }
+            }
+            TokenType::Drop => {
+                self.token_list.next(1);
+
+                if self.eat(TokenType::Db) {
+                    Command::DropDatabase {
+                        name: self.parse_name("database")?,
+                    }
+                } else if self.eat(TokenType::Collection) {
+                    Command::DropCollection {
+                        name: self.parse_name("collection")?,
+                    }
+                } else if self.eat(TokenType::Index) {
+                    let name: String = self.parse_name("index")?;
+
+                    self.expect(TokenType::On, "\"on\"")?;
+
+                    Command::DropIndex {
+                        collection: self.parse_name("collection")?,
+                        name,
+                    }
+                } else if self.eat(TokenType::User) {
+                    Command::DropUser {
+                        name: self.parse_name("user")?,
+                    }
+                } else {
+                    return Err(self.expected("\"db\", \"collection\", \"index\" or \"user\""));
+                }
+            }
+            TokenType::Use => {
+                self.token_list.next(1);
+
+                Command::Use {
+                    name: self.parse_name("database")?,
+                }
+            }
+            TokenType::Show => {
+                self.token_list.next(1);
+
+                if self.eat(TokenType::Dbs) {
+                    Command::ShowDatabases
+                } else if self.eat(TokenType::Indexes) {
+                    Command::ShowIndexes {
+                        collection: self.parse_name("collection")?,
+                    }
+                } else if self.at_statement_end() {
+                    Command::ShowCollections { database: None }
+                } else {
+                    Command::ShowCollections {
+                        database: Some(self.parse_name("database")?),
+                    }
+                }
+            }
+            TokenType::Vacuum => {
+                self.token_list.next(1);
+
+                Command::Vacuum {
+                    collection: self.parse_name("collection")?,
+                }
+            }
+            TokenType::Help => {
+                self.token_list.next(1);
+
+                Command::Help
+            }
+            TokenType::Exit => {
+                self.token_list.next(1);
+
+                Command::Exit
+            }
+            TokenType::Begin => {
+                self.token_list.next(1);
+
+                Command::Begin
+            }
+            TokenType::Commit => {
+                self.token_list.next(1);
+
+                Command::Commit
+            }
+            TokenType::Rollback => {
+                self.token_list.next(1);
+
+                Command::Rollback
+            }
+            TokenType::Next => {
+                self.token_list.next(1);
+
+                Command::Next {
+                    cursor: self.parse_cursor()?,
+                }
+            }
+            TokenType::Close => {
+                self.token_list.next(1);
+
+                Command::Close {
+                    cursor: self.parse_cursor()?,
+                }
+            }
+            TokenType::Insert => {
+                self.token_list.next(1);
+                self.expect(TokenType::Into, "\"into\"")?;
+
+                let collection: String = self.parse_name("collection")?;
+
+                if !self.check(TokenType::LeftBrace) {
+                    return Err(self.expected("a document"));
+                }
+
+                let Value::Object(document) = self.parse_value()? else {
+                    unreachable!("a value starting with \"{{\" is an object");
+                };
+
+                Command::Insert {
+                    collection,
+                    document,
+                }
+            }
+            TokenType::Find => {
+                self.token_list.next(1);
+
+                Command::Find {
+                    collection: self.parse_name("collection")?,
+                    filter: self.parse_where()?,
+                    batch: self.parse_batch()?,
+                }
+            }
+            TokenType::Update => {
+                self.token_list.next(1);
+
+                Command::Update {
+                    collection: self.parse_name("collection")?,
+                    update: self.parse_update()?,
+                    filter: self.parse_where()?,
+                }
+            }
+            TokenType::Delete => {
+                self.token_list.next(1);
+                self.expect(TokenType::From, "\"from\"")?;
+
+                Command::Delete {
+                    collection: self.parse_name("collection")?,
+                    filter: self.parse_where()?,
+                }
+            }
+            _ => {
+                return Err(self.error(format!(
+                    "unknown command \"{}\"",
+                    self.token_list.current_token.slice
+                )))
+            }
+        };
+
+        if !self.at_statement_end() {
+            return Err(self.error(format!(
+                "unexpected \"{}\"",
+                self.token_list.current_token.slice
+            )));
+        }
+
+        Ok(command)
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        if self.token_list.is_at_end() {
+            return Err(self.expected("a value"));
+        }
+
+        let value: Value = match self.token_list.current_token.tok_type {
+            TokenType::NullValue => Value::Null,
+            TokenType::Boolean(boolean) => Value::Bool(boolean),
+            TokenType::Integer(integer) => Value::Int(
+                i64::try_from(integer)
+                    .map_err(|_| self.error("number out of range".to_string()))?,
+            ),
+            TokenType::Float(float) => Value::Float(float),
+            TokenType::Minus => {
+                self.token_list.next(1);
+
+                match self.peek() {
+                    // Down to i64::MIN, which has no positive counterpart
+                    Some(TokenType::Integer(integer)) => Value::Int(
+                        0_i64
+                            .checked_sub_unsigned(integer)
+                            .ok_or_else(|| self.error("number out of range".to_string()))?,
+                    ),
+                    Some(TokenType::Float(float)) => Value::Float(-float),
+                    _ => return Err(self.expected("a number after \"-\"")),
+                }
+            }
+            TokenType::String(string) => Value::String(unescape(string)),
+            TokenType::LeftBracket => {
+                self.token_list.next(1);
+
+                let mut values: Vec<Value> = vec![];
+
+                while !self.check(TokenType::RightBracket) {
+                    values.push(self.parse_value()?);
+
+                    if !self.eat(TokenType::Comma) {
+                        break;
+                    }
+                }
+
+                if !self.check(TokenType::RightBracket) {
+                    return Err(self.expected("\"]\" to close the array"));
+                }
+
+                Value::Array(values)
+            }
+            TokenType::LeftBrace => {
+                self.token_list.next(1);
+
+                let mut fields: BTreeMap<String, Value> = BTreeMap::new();
+
+                while !self.token_list.is_at_end() && !self.check(TokenType::RightBrace) {
+                    let key: String = self.parse_field_name()?;
+
+                    self.expect(TokenType::Colon, "\":\" after the field name")?;
+
+                    fields.insert(key, self.parse_value()?);
+
+                    if !self.eat(TokenType::Comma) {
+                        break;
+                    }
+                }
+
+                if !self.check(TokenType::RightBrace) {
+                    return Err(self.expected("\"}\" to close the document"));
+                }
+
+                Value::Object(fields)
+            }
+            _ => return Err(self.expected("a value")),
+        };
+
+        self.token_list.next(1);
+
+        Ok(value)
+    }
+
+    fn parse_field_name(&mut self) -> Result<String, ParseError> {
+        if self.token_list.is_at_end() {
+            return Err(self.expected("a field name"));
+        }
+
+        let key: String = match self.token_list.current_token.tok_type {
+            TokenType::String(key) => unescape(key),
+            _ if self.token_list.current_token.is_word() => {
+                self.token_list.current_token.slice.to_string()
+            }
+            _ => return Err(self.expected("a field name")),
+        };
+
+        self.token_list.next(1);
+
+        Ok(key)
+    }
+
+    /// Parses a dotted field path such as `address.city`.
+    fn parse_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut path: Vec<String> = vec![self.parse_field_name()?];
+
+        while self.eat(TokenType::Dot) {
+            path.push(self.parse_field_name()?);
+        }
+
+        Ok(path)
+    }
+
+    /// Parses the rest of `create [unique] index <name> on <collection>(<field>, ...)`.
+    fn parse_create_index(&mut self, unique: bool) -> Result<Command, ParseError> {
+        let name: String = self.parse_name("index")?;
+
+        self.expect(TokenType::On, "\"on\"")?;
+
+        let collection: String = self.parse_name("collection")?;
+
+        self.expect(TokenType::LeftParen, "\"(\"")?;
+
+        let mut fields: Vec<Vec<String>> = vec![self.parse_path()?];
+
+        while self.eat(TokenType::Comma) {
+            fields.push(self.parse_path()?);
+        }
+
+        self.expect(TokenType::RightParen, "\")\" to close the field list")?;
+
+        Ok(Command::CreateIndex {
+            collection,
+            definition: IndexDefinition {
+                name,
+                fields,
+                unique,
+            },
+        })
+    }
+
+    /// Parses an optional trailing `where <filter>` clause.
+    fn parse_where(&mut self) -> Result<Option<Filter>, ParseError> {
+        if self.eat(TokenType::Where) {
+            Ok(Some(self.parse_filter()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// `and` binds tighter than `or`.
+    fn parse_filter(&mut self) -> Result<Filter, ParseError> {
+        let mut filter: Filter = self.parse_and()?;
+
+        while self.eat(TokenType::Or) {
+            filter = Filter::Or(Box::new(filter), Box::new(self.parse_and()?));
+        }
+
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, ParseError> {
+        let mut filter: Filter = self.parse_predicate()?;
+
+        while self.eat(TokenType::And) {
+            filter = Filter::And(Box::new(filter), Box::new(self.parse_predicate()?));
+        }
+
+        Ok(filter)
+    }
+
+    fn parse_predicate(&mut self) -> Result<Filter, ParseError> {
+        let path: Vec<String> = self.parse_path()?;
+
+        if self.eat(TokenType::Exists) {
+            return Ok(Filter::Exists { path });
+        }
+
+        if self.eat(TokenType::In) {
+            if !self.check(TokenType::LeftBracket) {
+                return Err(self.expected("a list of values after \"in\""));
+            }
+
+            let Value::Array(values) = self.parse_value()? else {
+                unreachable!("a value starting with \"[\" is an array");
+            };
+
+            return Ok(Filter::In { path, values });
+        }
+
+        let operator: Operator = match self.peek() {
+            Some(TokenType::Equal) => Operator::Equal,
+            Some(TokenType::NotEqual) => Operator::NotEqual,
+            Some(TokenType::Less) => Operator::Less,
+            Some(TokenType::LessEqual) => Operator::LessEqual,
+            Some(TokenType::Greater) => Operator::Greater,
+            Some(TokenType::GreaterEqual) => Operator::GreaterEqual,
+            _ => return Err(self.expected("an operator")),
+        };
+
+        self.token_list.next(1);
+
+        Ok(Filter::Compare {
+            path,
+            operator,
+            value: self.parse_value()?,
+        })
+    }
+
+    /// Parses any sequence of `set a = 1, b.c = "x"`, `unset d, e` and `inc n 5, m -1`
+    /// clauses.
+    fn parse_update(&mut self) -> Result<Update, ParseError> {
+        let mut modifications: Vec<Modification> = vec![];
+
+        while let Some(clause @ (TokenType::Set | TokenType::Unset | TokenType::Inc)) = self.peek()
+        {
+            self.token_list.next(1);
+
+            loop {
+                let path_token: Token = self.token_list.current_token;
+                let path: Vec<String> = self.parse_path()?;
+
+                if path.first().is_some_and(|key| key == ID_FIELD) {
+                    return Err(ParseError::new(
+                        format!("\"{ID_FIELD}\" cannot be modified"),
+                        path_token.line,
+                        path_token.column,
+                        path_token.span,
+                    ));
+                }
+
+                let modification: Modification = match clause {
+                    TokenType::Set => {
+                        self.expect(TokenType::Equal, "\"=\"")?;
+
+                        Modification::Set {
+                            path,
+                            value: self.parse_value()?,
+                        }
+                    }
+                    TokenType::Inc => {
+                        if !matches!(
+                            self.peek(),
+                            Some(TokenType::Integer(_) | TokenType::Float(_) | TokenType::Minus)
+                        ) {
+                            return Err(self.expected("a number"));
+                        }
+
+                        Modification::Increment {
+                            path,
+                            amount: self.parse_value()?,
+                        }
+                    }
+                    _ => Modification::Unset { path },
+                };
+
+                modifications.push(modification);
+
+                if !self.eat(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        if modifications.is_empty() {
+            return Err(self.expected("\"set\", \"unset\" or \"inc\""));
+        }
+
+        Ok(Update::new(modifications))
+    }
+
+    fn parse_batch(&mut self) -> Result<Option<usize>, ParseError> {
+        if !self.eat(TokenType::Batch) {
+            return Ok(None);
+        }
+
+        match self.peek() {
+            Some(TokenType::Integer(size)) if size > 0 => {
+                self.token_list.next(1);
+
+                Ok(Some(size as usize))
+            }
+            _ => Err(self.expected("a positive batch size")),
+        }
+    }
+
+    fn parse_cursor(&mut self) -> Result<u64, ParseError> {
+        match self.peek() {
+            Some(TokenType::Integer(cursor)) if cursor > 0 => {
+                self.token_list.next(1);
+
+                Ok(cursor)
+            }
+            _ => Err(self.expected("a cursor id")),
+        }
+    }
+
+    fn parse_password(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(TokenType::String(password)) if !password.is_empty() => {
+                self.token_list.next(1);
+
+                Ok(unescape(password))
+            }
+            _ => Err(self.expected("a non-empty password string")),
+        }
+    }
+
+    // Utilities
+    /// Reports the first lexer error between the current token and the end of the statement.
+    fn check_lex_errors(&self) -> Result<(), ParseError> {
+        for token in self
+            .token_list
+            .tokens
+            .iter()
+            .skip(self.token_list.current_index())
+            .take_while(|token| token.tok_type != TokenType::Semicolon)
+        {
+            if let TokenType::Error(err) = token.tok_type {
+                let message: String = match err {
+                    LexError::UnexpectedCharacter => format!("{err} \"{}\"", token.slice),
+                    _ => err.to_string(),
+                };
+
+                return Err(ParseError::new(
+                    message,
+                    token.line,
+                    token.column,
+                    token.span,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<TokenType<'a>> {
+        if self.token_list.is_at_end() {
+            None
+        } else {
+            Some(self.token_list.current_token.tok_type)
+        }
+    }
+
+    fn check(&self, tok_type: TokenType<'_>) -> bool {
+        self.peek() == Some(tok_type)
+    }
+
+    fn eat(&mut self, tok_type: TokenType<'_>) -> bool {
+        let found: bool = self.check(tok_type);
+
+        if found {
+            self.token_list.next(1);
+        }
+
+        found
+    }
+
+    fn expect(&mut self, tok_type: TokenType<'_>, expected: &str) -> Result<(), ParseError> {
+        if self.eat(tok_type) {
+            Ok(())
+        } else {
+            Err(self.expected(expected))
+        }
+    }
+
+    fn at_statement_end(&self) -> bool {
+        self.token_list.is_at_end() || self.check(TokenType::Semicolon)
+    }
+
+    /// Names of databases and collections double as directory names, so a name is a
+    /// single word: an identifier, or a keyword such as `index` or `user`. Only `dbs` and
+    /// `indexes` are reserved, since `SHOW` reads them before a database name.
+    fn parse_name(&mut self, kind: &str) -> Result<String, ParseError> {
+        if self.token_list.is_at_end() || !self.token_list.current_token.is_word() {
+            return Err(self.expected(&format!("a {kind} name")));
+        }
+
+        if matches!(
+            self.token_list.current_token.tok_type,
+            TokenType::Dbs | TokenType::Indexes
+        ) {
+            return Err(self.error(format!(
+                "\"{}\" is reserved, it can't be a {kind} name",
+                self.token_list.current_token.slice
+            )));
+        }
+
+        let name: String = self.token_list.current_token.slice.to_string();
+
+        self.token_list.next(1);
+
+        Ok(name)
+    }
+
+    fn expected(&self, expected: &str) -> ParseError {
+        let found: String = if self.token_list.is_at_end() {
+            String::from("end of input")
+        } else {
+            format!("\"{}\"", self.token_list.current_token.slice)
+        };
+
+        self.error(format!("expected {expected}, found {found}"))
+    }
+
+    /// Points at the current token, or right after the last one at the end of input.
+    fn error(&self, message: String) -> ParseError {
+        if !self.token_list.is_at_end() {
+            let token: Token = self.token_list.current_token;
+
+            return ParseError::new(message, token.line, token.column, token.span);
+        }
+
+        match self.token_list.tokens.last() {
+            Some(token) => ParseError::new(
+                message,
+                token.line,
+                token.column + token.slice.chars().count(),
+                Span {
+                    start: token.span.end,
+                    end: token.span.end,
+                },
+            ),
+            None => ParseError::new(message, 0, 0, Span { start: 0, end: 0 }),
+        }
+    }
+}
+
+impl Iterator for Parser<'_> {
+    type Item = Result<Command, ParseError>;
+
+    /// Yields the `;` separated statements one at a time. After an error the rest of the
+    /// failed statement is skipped, so the caller can decide whether to carry on.
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.eat(TokenType::Semicolon) {}
+
+        if self.token_list.is_at_end() {
+            return None;
+        }
+
+        let statement: Result<Command, ParseError> = self.parse_statement();
+
+        if statement.is_err() {
+            while !self.at_statement_end() {
+                self.token_list.next(1);
+            }
+        }
+
+        Some(statement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ast::Command, ParseError, Parser};
+    use crate::{database_manager::value::Value, lexer::Lexer, token_list::TokenList};
+
+    fn parse(input: &str) -> Vec<Result<Command, ParseError>> {
+        Parser::new(TokenList::from(Lexer::new(input))).collect()
+    }
+
+    fn error(input: &str) -> ParseError {
+        match parse(input).remove(0) {
+            Ok(command) => panic!("{input:?} parsed as {command:?}"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn statements_run_in_order() {
+        assert_eq!(
+            parse("use shop; ;create collection orders;\nshow dbs;"),
+            [
+                Ok(Command::Use {
+                    name: "shop".to_string()
+                }),
+                Ok(Command::CreateCollection {
+                    name: "orders".to_string()
+                }),
+                Ok(Command::ShowDatabases),
+            ]
+        );
+        assert!(parse(" ; -- nothing\n").is_empty());
+    }
+
+    #[test]
+    fn a_failed_statement_is_skipped_whole() {
+        let statements: Vec<Result<Command, ParseError>> = parse("create db; drop db x y; use b");
+
+        assert_eq!(statements.len(), 3);
+        assert_eq!(
+            statements[0].as_ref().unwrap_err().message,
+            "expected a database name, found \";\""
+        );
+        assert_eq!(
+            statements[1].as_ref().unwrap_err().message,
+            "unexpected \"y\""
+        );
+        assert_eq!(
+            statements[2],
+            Ok(Command::Use {
+                name: "b".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn errors_point_at_their_token() {
+        let err: ParseError = error("\ninsert into c {a: @}");
+
+        assert_eq!(err.message, "unexpected character \"@\"");
+        assert_eq!((err.line, err.column), (1, 18));
+        assert_eq!((err.span.start, err.span.end), (19, 20));
+
+        assert_eq!(
+            error("drop").message,
+            "expected \"db\", \"collection\", \"index\" or \"user\", found end of input"
+        );
+    }
+
+    #[test]
+    fn keywords_ignore_case_but_names_keep_it() {
+        assert_eq!(
+            parse("CREATE DB Shop; Show INDEXES Orders"),
+            [
+                Ok(Command::CreateDatabase {
+                    name: "Shop".to_string()
+                }),
+                Ok(Command::ShowIndexes {
+                    collection: "Orders".to_string()
+                }),
+            ]
+        );
+        assert_eq!(
+            error("CREATE Bogus").message,
+            "expected \"db\", \"collection\", \"index\" or \"user\", found \"Bogus\""
+        );
+    }
+
+    #[test]
+    fn keywords_are_names_but_show_words() {
+        assert_eq!(
+            parse("create collection index; drop user user; show find"),
+            [
+                Ok(Command::CreateCollection {
+                    name: "index".to_string()
+                }),
+                Ok(Command::DropUser {
+                    name: "user".to_string()
+                }),
+                Ok(Command::ShowCollections {
+                    database: Some("find".to_string())
+                }),
+            ]
+        );
+        assert!(error("create db dbs").message.contains("reserved"));
+        assert!(error("create collection indexes")
+            .message
+            .contains("reserved"));
+    }
+
+    #[test]
+    fn minus_negates_numbers() {
+        let Ok(Command::Insert { document, .. }) =
+            parse("insert into c {a: -5, b: -9223372036854775808, c: - 2.5}").remove(0)
+        else {
+            panic!("not an insert");
+        };
+
+        assert_eq!(document["a"], Value::Int(-5));
+        assert_eq!(document["b"], Value::Int(i64::MIN));
+        assert_eq!(document["c"], Value::Float(-2.5));
+
+        assert_eq!(
+            error("insert into c {a: 9223372036854775808}").message,
+            "number out of range"
+        );
+        assert_eq!(
+            error("insert into c {a: -x}").message,
+            "expected a number after \"-\", found \"x\""
+        );
+    }
+}
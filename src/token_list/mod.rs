@@ -1,4 +1,7 @@
-use crate::lexer::token::{Span, Token, TokenType};
+use crate::lexer::{
+    token::{Span, Token, TokenType},
+    Lexer,
+};
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct TokenList<'a> {
@@ -23,4 +26,36 @@ impl<'a> TokenList<'a> {
             self.current_token = self.tokens[self.current_index];
         }
     }
+
+    pub fn current_index(&self) -> usize {
+        self.current_index
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.current_index >= self.tokens.len()
+    }
+}
+
+impl<'a> From<Lexer<'a>> for TokenList<'a> {
+    /// Collects every token except whitespace and comments and positions the list on the first one.
+    fn from(lexer: Lexer<'a>) -> Self {
+        let mut token_list: Self = Self::new(
+            lexer
+                .filter(|token| {
+                    token.tok_type != TokenType::Null
+                        && token.tok_type != TokenType::Space
+                        && token.tok_type != TokenType::LineFeed
+                        && token.tok_type != TokenType::CarriageReturn
+                        && token.tok_type != TokenType::Tab
+                        && token.tok_type != TokenType::Comment
+                })
+                .collect(),
+        );
+
+        if let Some(first_token) = token_list.tokens.first() {
+            token_list.current_token = *first_token;
+        }
+
+        token_list
+    }
 }
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use tonic::{service::Interceptor, Request, Status};
+
+use crate::database_manager::{store::Store, users::Login};
+
+/// Metadata key carrying the token `ConnectToDB` gave the session.
+pub const TOKEN_METADATA: &str = "session-token";
+
+/// Puts the `Login` of the token each request carries in its extensions, rejecting unknown
+/// tokens. A strict one also rejects requests without a token once clients have to log in,
+/// the others leave that to `require_login` so some methods stay open.
+#[derive(Clone)]
+pub struct Authenticator {
+    store: Arc<Store>,
+    strict: bool,
+}
+
+impl Authenticator {
+    pub fn new(store: Arc<Store>) -> Self {
+        Self {
+            store,
+            strict: false,
+        }
+    }
+
+    pub fn strict(store: Arc<Store>) -> Self {
+        Self {
+            store,
+            strict: true,
+        }
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let Some(token) = request.metadata().get(TOKEN_METADATA) else {
+            if self.strict {
+                require_login(&self.store, &request)?;
+            }
+
+            return Ok(request);
+        };
+
+        let token: &str = token.to_str().map_err(|_| {
+            Status::invalid_argument(format!("Invalid \"{TOKEN_METADATA}\" metadata"))
+        })?;
+
+        let login: Login = self.store.logins.get(token).ok_or_else(|| {
+            Status::unauthenticated("Unknown session token, connect with ConnectToDB again")
+        })?;
+
+        request.extensions_mut().insert(login);
+
+        Ok(request)
+    }
+}
+
+/// Rejects a request that didn't log in, once clients have to.
+pub fn require_login<T>(store: &Store, request: &Request<T>) -> Result<(), Status> {
+    if store.logins.required() && request.extensions().get::<Login>().is_none() {
+        return Err(Status::unauthenticated(format!(
+            "Log in with ConnectToDB and send the token it gives as \"{TOKEN_METADATA}\" metadata"
+        )));
+    }
+
+    Ok(())
+}
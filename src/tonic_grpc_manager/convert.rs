@@ -0,0 +1,281 @@
+//! Conversions between the protobuf messages of the gRPC services and the types of the
+//! database engine.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail};
+use tonic::{Code, Status};
+
+use crate::{
+    database_manager::{
+        error::DbError,
+        filter::{Filter, Operator},
+        index::IndexDefinition,
+        update::{Modification, Update},
+        value::Value,
+    },
+    lexer::token::Span,
+    lildb,
+};
+
+impl DbError {
+    pub fn code(&self) -> Code {
+        match self {
+            Self::Syntax { .. } | Self::InvalidArgument(_) => Code::InvalidArgument,
+            Self::NotFound(_) => Code::NotFound,
+            Self::AlreadyExists(_) => Code::AlreadyExists,
+            Self::FailedPrecondition(_) => Code::FailedPrecondition,
+            Self::Conflict(_) => Code::Aborted,
+            Self::Internal(_) => Code::Internal,
+        }
+    }
+}
+
+impl From<DbError> for Status {
+    fn from(err: DbError) -> Self {
+        Self::new(err.code(), err.message())
+    }
+}
+
+impl From<&DbError> for lildb::ErrorKind {
+    fn from(err: &DbError) -> Self {
+        match err {
+            DbError::Syntax { .. } => Self::Syntax,
+            DbError::NotFound(_) => Self::NotFound,
+            DbError::AlreadyExists(_) => Self::AlreadyExists,
+            DbError::InvalidArgument(_) => Self::InvalidArgument,
+            DbError::FailedPrecondition(_) => Self::FailedPrecondition,
+            DbError::Conflict(_) => Self::Conflict,
+            DbError::Internal(_) => Self::Internal,
+        }
+    }
+}
+
+/// The answer to a statement that failed, keeping the text the shell prints.
+impl From<DbError> for lildb::RunCommandResponse {
+    fn from(err: DbError) -> Self {
+        Self {
+            output: format!("Error: {err}\n\r"),
+            status: err.code() as i32,
+            error_kind: lildb::ErrorKind::from(&err).into(),
+            message: err.message().to_string(),
+            span: err.span().map(Into::into),
+            end_of_result: true,
+            cursor_id: 0,
+        }
+    }
+}
+
+impl From<Span> for lildb::Span {
+    fn from(span: Span) -> Self {
+        Self {
+            start: span.start as u64,
+            end: span.end as u64,
+        }
+    }
+}
+
+impl TryFrom<lildb::Value> for Value {
+    type Error = anyhow::Error;
+
+    fn try_from(value: lildb::Value) -> anyhow::Result<Self> {
+        use lildb::value::Kind;
+
+        let kind: Kind = value.kind.ok_or_else(|| anyhow!("value has no kind"))?;
+
+        Ok(match kind {
+            Kind::NullValue(_) => Self::Null,
+            Kind::BoolValue(boolean) => Self::Bool(boolean),
+            Kind::IntValue(integer) => Self::Int(integer),
+            Kind::FloatValue(float) => Self::Float(float),
+            Kind::StringValue(string) => Self::String(string),
+            Kind::ArrayValue(array) => Self::Array(
+                array
+                    .values
+                    .into_iter()
+                    .map(Self::try_from)
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            Kind::ObjectValue(object) => Self::Object(fields(object)?),
+        })
+    }
+}
+
+impl From<Value> for lildb::Value {
+    fn from(value: Value) -> Self {
+        use lildb::value::Kind;
+
+        let kind: Kind = match value {
+            Value::Null => Kind::NullValue(lildb::NullValue::NullValue.into()),
+            Value::Bool(boolean) => Kind::BoolValue(boolean),
+            Value::Int(integer) => Kind::IntValue(integer),
+            Value::Float(float) => Kind::FloatValue(float),
+            Value::String(string) => Kind::StringValue(string),
+            Value::Array(values) => Kind::ArrayValue(lildb::ArrayValue {
+                values: values.into_iter().map(Self::from).collect(),
+            }),
+            Value::Object(fields) => Kind::ObjectValue(fields.into()),
+        };
+
+        Self { kind: Some(kind) }
+    }
+}
+
+impl From<BTreeMap<String, Value>> for lildb::Document {
+    fn from(fields: BTreeMap<String, Value>) -> Self {
+        Self {
+            fields: fields
+                .into_iter()
+                .map(|(key, value)| (key, value.into()))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<lildb::Filter> for Filter {
+    type Error = anyhow::Error;
+
+    fn try_from(filter: lildb::Filter) -> anyhow::Result<Self> {
+        use lildb::filter::Kind;
+
+        let kind: Kind = filter.kind.ok_or_else(|| anyhow!("filter has no kind"))?;
+
+        Ok(match kind {
+            Kind::Compare(comparison) => {
+                let operator: Operator = match lildb::Operator::try_from(comparison.operator) {
+                    Ok(lildb::Operator::Equal) => Operator::Equal,
+                    Ok(lildb::Operator::NotEqual) => Operator::NotEqual,
+                    Ok(lildb::Operator::Less) => Operator::Less,
+                    Ok(lildb::Operator::LessEqual) => Operator::LessEqual,
+                    Ok(lildb::Operator::Greater) => Operator::Greater,
+                    Ok(lildb::Operator::GreaterEqual) => Operator::GreaterEqual,
+                    Err(_) => bail!("unknown operator {}", comparison.operator),
+                };
+
+                Self::Compare {
+                    path: path(&comparison.field)?,
+                    operator,
+                    value: required(comparison.value, "value")?.try_into()?,
+                }
+            }
+            Kind::In(filter) => Self::In {
+                path: path(&filter.field)?,
+                values: filter
+                    .values
+                    .into_iter()
+                    .map(Value::try_from)
+                    .collect::<anyhow::Result<_>>()?,
+            },
+            Kind::Exists(filter) => Self::Exists {
+                path: path(&filter.field)?,
+            },
+            Kind::And(pair) => {
+                let (left, right): (Self, Self) = operands(*pair)?;
+
+                Self::And(Box::new(left), Box::new(right))
+            }
+            Kind::Or(pair) => {
+                let (left, right): (Self, Self) = operands(*pair)?;
+
+                Self::Or(Box::new(left), Box::new(right))
+            }
+        })
+    }
+}
+
+impl TryFrom<Vec<lildb::Modification>> for Update {
+    type Error = anyhow::Error;
+
+    fn try_from(modifications: Vec<lildb::Modification>) -> anyhow::Result<Self> {
+        use lildb::modification::Kind;
+
+        let mut update: Vec<Modification> = vec![];
+
+        for modification in modifications {
+            let kind: Kind = modification
+                .kind
+                .ok_or_else(|| anyhow!("modification has no kind"))?;
+
+            update.push(match kind {
+                Kind::Set(set) => Modification::Set {
+                    path: path(&set.field)?,
+                    value: required(set.value, "value")?.try_into()?,
+                },
+                Kind::Unset(unset) => Modification::Unset {
+                    path: path(&unset.field)?,
+                },
+                Kind::Increment(increment) => Modification::Increment {
+                    path: path(&increment.field)?,
+                    amount: required(increment.amount, "amount")?.try_into()?,
+                },
+            });
+        }
+
+        Ok(Self::new(update))
+    }
+}
+
+impl TryFrom<lildb::IndexDefinition> for IndexDefinition {
+    type Error = anyhow::Error;
+
+    fn try_from(definition: lildb::IndexDefinition) -> anyhow::Result<Self> {
+        if definition.fields.is_empty() {
+            bail!("index \"{}\" has no fields", definition.name);
+        }
+
+        Ok(Self {
+            fields: definition
+                .fields
+                .iter()
+                .map(|field| path(field))
+                .collect::<anyhow::Result<_>>()?,
+            name: definition.name,
+            unique: definition.unique,
+        })
+    }
+}
+
+impl From<IndexDefinition> for lildb::IndexDefinition {
+    fn from(definition: IndexDefinition) -> Self {
+        Self {
+            name: definition.name,
+            fields: definition
+                .fields
+                .iter()
+                .map(|path| path.join("."))
+                .collect(),
+            unique: definition.unique,
+        }
+    }
+}
+
+/// The fields of a document sent by a client.
+pub fn fields(document: lildb::Document) -> anyhow::Result<BTreeMap<String, Value>> {
+    document
+        .fields
+        .into_iter()
+        .map(|(key, value)| Ok((key, value.try_into()?)))
+        .collect()
+}
+
+/// Splits a dotted field path such as `address.city`.
+fn path(field: &str) -> anyhow::Result<Vec<String>> {
+    let path: Vec<String> = field.split('.').map(String::from).collect();
+
+    if path.iter().any(String::is_empty) {
+        bail!("invalid field path \"{field}\"");
+    }
+
+    Ok(path)
+}
+
+fn operands(pair: lildb::FilterPair) -> anyhow::Result<(Filter, Filter)> {
+    Ok((
+        (*required(pair.left, "left")?).try_into()?,
+        (*required(pair.right, "right")?).try_into()?,
+    ))
+}
+
+fn required<T>(field: Option<T>, name: &str) -> anyhow::Result<T> {
+    field.ok_or_else(|| anyhow!("missing \"{name}\""))
+}
@@ -0,0 +1,535 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+use super::{convert, sessions::Sessions};
+use crate::{
+    database_manager::{
+        catalog::CollectionEntry, cursor::Batch, error::DbError, filter::Filter,
+        index::IndexDefinition, update::Update, value::Value, Database,
+    },
+    lildb::{
+        lil_db_data_service_server::LilDbDataService, CollectionInfo, CreateCollectionRequest,
+        CreateCollectionResponse, CreateDatabaseRequest, CreateDatabaseResponse,
+        CreateIndexRequest, CreateIndexResponse, DeleteRequest, DeleteResponse, Document,
+        DropCollectionRequest, DropCollectionResponse, DropDatabaseRequest, DropDatabaseResponse,
+        DropIndexRequest, DropIndexResponse, FindRequest, FindResponse, GetMoreRequest,
+        GetMoreResponse, InsertRequest, InsertResponse, KillCursorRequest, KillCursorResponse,
+        ListCollectionsRequest, ListCollectionsResponse, ListDatabasesRequest,
+        ListDatabasesResponse, ListIndexesRequest, ListIndexesResponse, UpdateRequest,
+        UpdateResponse,
+    },
+};
+
+/// Runs each request in a context of its own, on the same store as the shell sessions.
+pub struct MyLilDBData {
+    pub sessions: Arc<Sessions>,
+}
+
+impl MyLilDBData {
+    pub fn new(sessions: Arc<Sessions>) -> Self {
+        Self { sessions }
+    }
+
+    /// A context using the database `name`.
+    async fn database(&self, name: &str) -> Result<Database, Status> {
+        check_name("database", name)?;
+
+        let mut database: Database = self.sessions.new_database();
+
+        database.use_database(name).await.map_err(failed)?;
+
+        Ok(database)
+    }
+
+    /// The context of the session the request names, which keeps its cursors.
+    async fn session<T>(&self, request: &Request<T>) -> Result<Arc<Mutex<Database>>, Status> {
+        self.sessions.of_request(request).await?.ok_or_else(|| {
+            Status::failed_precondition(
+                "cursors belong to a session, connect with ConnectToDB and send its id as \"session-id\" metadata",
+            )
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl LilDbDataService for MyLilDBData {
+    async fn create_database(
+        &self,
+        request: Request<CreateDatabaseRequest>,
+    ) -> Result<Response<CreateDatabaseResponse>, Status> {
+        let name: &str = &request.get_ref().database;
+
+        check_name("database", name)?;
+
+        self.sessions
+            .new_database()
+            .create_database(name)
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(CreateDatabaseResponse {}))
+    }
+
+    async fn drop_database(
+        &self,
+        request: Request<DropDatabaseRequest>,
+    ) -> Result<Response<DropDatabaseResponse>, Status> {
+        let name: &str = &request.get_ref().database;
+
+        check_name("database", name)?;
+
+        self.sessions
+            .new_database()
+            .drop_database(name)
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(DropDatabaseResponse {}))
+    }
+
+    async fn list_databases(
+        &self,
+        _request: Request<ListDatabasesRequest>,
+    ) -> Result<Response<ListDatabasesResponse>, Status> {
+        let databases: Vec<String> = self
+            .sessions
+            .new_database()
+            .databases()
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(ListDatabasesResponse { databases }))
+    }
+
+    async fn create_collection(
+        &self,
+        request: Request<CreateCollectionRequest>,
+    ) -> Result<Response<CreateCollectionResponse>, Status> {
+        let request: &CreateCollectionRequest = request.get_ref();
+
+        check_name("collection", &request.collection)?;
+
+        self.database(&request.database)
+            .await?
+            .create_collection(&request.collection)
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(CreateCollectionResponse {}))
+    }
+
+    async fn drop_collection(
+        &self,
+        request: Request<DropCollectionRequest>,
+    ) -> Result<Response<DropCollectionResponse>, Status> {
+        let request: &DropCollectionRequest = request.get_ref();
+
+        check_name("collection", &request.collection)?;
+
+        self.database(&request.database)
+            .await?
+            .drop_collection(&request.collection)
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(DropCollectionResponse {}))
+    }
+
+    async fn list_collections(
+        &self,
+        request: Request<ListCollectionsRequest>,
+    ) -> Result<Response<ListCollectionsResponse>, Status> {
+        let name: &str = &request.get_ref().database;
+
+        check_name("database", name)?;
+
+        let collections: BTreeMap<String, CollectionEntry> = self
+            .sessions
+            .new_database()
+            .collections(Some(name))
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(ListCollectionsResponse {
+            collections: collections
+                .into_iter()
+                .map(|(name, entry)| CollectionInfo {
+                    name,
+                    created_at: entry.created_at,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn create_index(
+        &self,
+        request: Request<CreateIndexRequest>,
+    ) -> Result<Response<CreateIndexResponse>, Status> {
+        let request: CreateIndexRequest = request.into_inner();
+
+        let definition: IndexDefinition = request
+            .index
+            .ok_or_else(|| Status::invalid_argument("missing \"index\""))?
+            .try_into()
+            .map_err(invalid)?;
+
+        check_name("index", &definition.name)?;
+
+        self.database(&request.database)
+            .await?
+            .create_index(&request.collection, definition)
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(CreateIndexResponse {}))
+    }
+
+    async fn drop_index(
+        &self,
+        request: Request<DropIndexRequest>,
+    ) -> Result<Response<DropIndexResponse>, Status> {
+        let request: &DropIndexRequest = request.get_ref();
+
+        self.database(&request.database)
+            .await?
+            .drop_index(&request.collection, &request.name)
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(DropIndexResponse {}))
+    }
+
+    async fn list_indexes(
+        &self,
+        request: Request<ListIndexesRequest>,
+    ) -> Result<Response<ListIndexesResponse>, Status> {
+        let request: &ListIndexesRequest = request.get_ref();
+
+        let indexes: Vec<IndexDefinition> = self
+            .database(&request.database)
+            .await?
+            .indexes(&request.collection)
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(ListIndexesResponse {
+            indexes: indexes.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn insert(
+        &self,
+        request: Request<InsertRequest>,
+    ) -> Result<Response<InsertResponse>, Status> {
+        let request: InsertRequest = request.into_inner();
+
+        let documents: Vec<BTreeMap<String, Value>> = request
+            .documents
+            .into_iter()
+            .map(convert::fields)
+            .collect::<anyhow::Result<_>>()
+            .map_err(invalid)?;
+
+        let ids: Vec<String> = self
+            .database(&request.database)
+            .await?
+            .insert(&request.collection, documents)
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(InsertResponse { ids }))
+    }
+
+    async fn find(&self, request: Request<FindRequest>) -> Result<Response<FindResponse>, Status> {
+        let session: Option<Arc<Mutex<Database>>> = match request.get_ref().batch_size {
+            0 => None,
+            _ => Some(self.session(&request).await?),
+        };
+
+        let request: FindRequest = request.into_inner();
+
+        let filter: Option<Filter> = request
+            .filter
+            .map(Filter::try_from)
+            .transpose()
+            .map_err(invalid)?;
+
+        let bodies: Vec<Value> = self
+            .database(&request.database)
+            .await?
+            .find(&request.collection, filter.as_ref())
+            .await
+            .map_err(failed)?;
+
+        let batch: Batch = match session {
+            Some(session) => session
+                .lock()
+                .await
+                .cursors
+                .open(bodies, request.batch_size as usize)
+                .map_err(failed)?,
+            None => Batch {
+                documents: bodies,
+                cursor: None,
+            },
+        };
+
+        Ok(Response::new(FindResponse {
+            documents: documents(batch.documents)?,
+            cursor_id: batch.cursor.unwrap_or(0),
+        }))
+    }
+
+    async fn get_more(
+        &self,
+        request: Request<GetMoreRequest>,
+    ) -> Result<Response<GetMoreResponse>, Status> {
+        let session: Arc<Mutex<Database>> = self.session(&request).await?;
+
+        let batch: Batch = session
+            .lock()
+            .await
+            .cursors
+            .next(request.get_ref().cursor_id)
+            .map_err(failed)?;
+
+        Ok(Response::new(GetMoreResponse {
+            documents: documents(batch.documents)?,
+            cursor_id: batch.cursor.unwrap_or(0),
+        }))
+    }
+
+    async fn kill_cursor(
+        &self,
+        request: Request<KillCursorRequest>,
+    ) -> Result<Response<KillCursorResponse>, Status> {
+        let session: Arc<Mutex<Database>> = self.session(&request).await?;
+
+        session
+            .lock()
+            .await
+            .cursors
+            .close(request.get_ref().cursor_id)
+            .map_err(failed)?;
+
+        Ok(Response::new(KillCursorResponse {}))
+    }
+
+    async fn update(
+        &self,
+        request: Request<UpdateRequest>,
+    ) -> Result<Response<UpdateResponse>, Status> {
+        let request: UpdateRequest = request.into_inner();
+
+        let update: Update = request.modifications.try_into().map_err(invalid)?;
+        let filter: Option<Filter> = request
+            .filter
+            .map(Filter::try_from)
+            .transpose()
+            .map_err(invalid)?;
+
+        let (matched, modified): (usize, usize) = self
+            .database(&request.database)
+            .await?
+            .update(&request.collection, &update, filter.as_ref())
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(UpdateResponse {
+            matched: matched as u64,
+            modified: modified as u64,
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<DeleteRequest>,
+    ) -> Result<Response<DeleteResponse>, Status> {
+        let request: DeleteRequest = request.into_inner();
+
+        let filter: Option<Filter> = request
+            .filter
+            .map(Filter::try_from)
+            .transpose()
+            .map_err(invalid)?;
+
+        let deleted: usize = self
+            .database(&request.database)
+            .await?
+            .delete(&request.collection, filter.as_ref())
+            .await
+            .map_err(failed)?;
+
+        Ok(Response::new(DeleteResponse {
+            deleted: deleted as u64,
+        }))
+    }
+}
+
+/// Names end up in file paths, so they are held to the identifiers the shell accepts.
+fn check_name(kind: &str, name: &str) -> Result<(), Status> {
+    let mut chars = name.chars();
+
+    let valid: bool = chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    if !valid {
+        return Err(Status::invalid_argument(format!(
+            "invalid {kind} name \"{name}\""
+        )));
+    }
+
+    Ok(())
+}
+
+fn documents(bodies: Vec<Value>) -> Result<Vec<Document>, Status> {
+    bodies
+        .into_iter()
+        .map(|body| match body {
+            Value::Object(fields) => Ok(fields.into()),
+            _ => Err(Status::internal("document is not an object")),
+        })
+        .collect()
+}
+
+/// A request the engine couldn't carry out.
+fn failed(err: anyhow::Error) -> Status {
+    DbError::from(err).into()
+}
+
+/// A request whose messages don't convert to a valid command.
+fn invalid(err: anyhow::Error) -> Status {
+    Status::invalid_argument(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tonic::{service::Interceptor, Code, Request, Status};
+
+    use super::{check_name, MyLilDBData};
+    use crate::{
+        database_manager::{
+            address::Address,
+            configuration::{Compaction, Config},
+            format,
+            store::Store,
+            users::{self, Login},
+        },
+        lildb::{lil_db_data_service_server::LilDbDataService, CreateDatabaseRequest},
+        tonic_grpc_manager::{
+            auth::{Authenticator, TOKEN_METADATA},
+            sessions::Sessions,
+        },
+    };
+
+    fn sessions(name: &str) -> Arc<Sessions> {
+        let config: Config = Config::new(
+            format::test_dir(name).to_string_lossy().into_owned(),
+            Address::default(),
+            None,
+            Compaction {
+                interval: None,
+                free_percent: 50,
+                min_size: 0,
+            },
+            None,
+            None,
+        );
+
+        Arc::new(Sessions::new(Arc::new(config), Arc::new(Store::new())))
+    }
+
+    /// A request to create the database `name`, past the interceptor the server puts in
+    /// front of the service.
+    fn create_database(
+        authenticator: &mut Authenticator,
+        token: Option<&str>,
+        name: &str,
+    ) -> Result<Request<CreateDatabaseRequest>, Status> {
+        let mut request: Request<()> = Request::new(());
+
+        if let Some(token) = token {
+            request
+                .metadata_mut()
+                .insert(TOKEN_METADATA, token.parse().unwrap());
+        }
+
+        let (metadata, extensions, ()) = authenticator.call(request)?.into_parts();
+
+        Ok(Request::from_parts(
+            metadata,
+            extensions,
+            CreateDatabaseRequest {
+                database: name.to_string(),
+            },
+        ))
+    }
+
+    #[test]
+    fn names_stay_in_the_store() {
+        for name in [
+            "",
+            "..",
+            "../shop",
+            "shop/items",
+            ".system",
+            "1shop",
+            "shop items",
+        ] {
+            let status: Status = check_name("database", name).unwrap_err();
+
+            assert_eq!(status.code(), Code::InvalidArgument, "{name:?}");
+        }
+
+        for name in ["shop", "_shop", "Shop_2"] {
+            assert!(check_name("database", name).is_ok(), "{name:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_need_a_valid_token() {
+        let sessions: Arc<Sessions> = sessions("requests_need_a_valid_token");
+        let data: MyLilDBData = MyLilDBData::new(sessions.clone());
+        let mut authenticator: Authenticator = Authenticator::strict(sessions.store.clone());
+
+        // Open to anyone until there are users
+        assert!(create_database(&mut authenticator, None, "open").is_ok());
+
+        users::create(&sessions.new_database(), "alice", "secret")
+            .await
+            .unwrap();
+
+        for token in [None, Some("forged")] {
+            let status: Status = create_database(&mut authenticator, token, "shop").unwrap_err();
+
+            assert_eq!(status.code(), Code::Unauthenticated, "{token:?}");
+        }
+
+        let token: String = sessions
+            .connect(Login {
+                user: Some("alice".to_string()),
+                session_id: "a".to_string(),
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let request: Request<CreateDatabaseRequest> =
+            create_database(&mut authenticator, Some(&token), "shop").unwrap();
+
+        data.create_database(request).await.unwrap();
+
+        // Logged in, the name is still checked
+        let request: Request<CreateDatabaseRequest> =
+            create_database(&mut authenticator, Some(&token), "../shop").unwrap();
+        let status: Status = data.create_database(request).await.unwrap_err();
+
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
@@ -1,12 +1,22 @@
+pub mod auth;
+pub mod convert;
+pub mod data_service;
+pub mod sessions;
+
 use std::sync::Arc;
 
+use sessions::Sessions;
 use tokio::sync::{mpsc, Mutex};
-use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
 use tracing::info;
 
 use crate::{
-    database_manager::Database,
+    database_manager::{
+        error::DbError,
+        users::{self, Login},
+        Database, Output, Rows,
+    },
     lex_input,
     lildb::{
         lil_db_shell_service_server::LilDbShellService, ConnectToDbRequest, ConnectToDbResponse,
@@ -14,13 +24,19 @@ use crate::{
     },
 };
 
+/// Largest `output` of a response. Bigger results are split over several responses, between
+/// lines unless a line is bigger on its own.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub type ResponseSender = mpsc::Sender<Result<RunCommandResponse, Status>>;
+
 pub struct MyLilDBShell {
-    pub database: Arc<Mutex<Database>>,
+    pub sessions: Arc<Sessions>,
 }
 
 impl MyLilDBShell {
-    pub fn new(database: Arc<Mutex<Database>>) -> Self {
-        Self { database }
+    pub fn new(sessions: Arc<Sessions>) -> Self {
+        Self { sessions }
     }
 }
 
@@ -32,32 +48,49 @@ impl LilDbShellService for MyLilDBShell {
         &self,
         request: Request<Streaming<RunCommandRequest>>,
     ) -> Result<Response<Self::RunCommandStream>, Status> {
-        let mut stream: Streaming<RunCommandRequest> = request.into_inner();
-        let (tx, rx) = mpsc::channel(1024);
+        auth::require_login(&self.sessions.store, &request)?;
 
-        let db: Arc<Mutex<Database>> = self.database.clone();
+        // Streams without a session id get a private context that lives as long as the stream
+        let db: Arc<Mutex<Database>> = match self.sessions.of_request(&request).await? {
+            Some(database) => database,
+            None => Arc::new(Mutex::new(self.sessions.new_database())),
+        };
 
-        tokio::spawn(async move {
-            while let Some(req) = stream.message().await.unwrap_or(None) {
-                let command: String = req.command;
+        let login: Option<Login> = request.extensions().get::<Login>().cloned();
+        let sessions: Arc<Sessions> = self.sessions.clone();
 
-                let execution_result: Result<(String, bool, Arc<Mutex<Database>>), anyhow::Error> =
-                    { lex_input(command, db.clone()).await };
+        let mut stream: Streaming<RunCommandRequest> = request.into_inner();
+        let (tx, rx) = mpsc::channel(1024);
 
-                let output_message: String = match execution_result {
-                    Ok((output, _should_exit, _)) => output,
-                    Err(e) => {
-                        format!("Error executing command: {e}\n")
+        tokio::spawn(async move {
+            loop {
+                let req: RunCommandRequest = match stream.message().await {
+                    Ok(Some(req)) => req,
+                    Ok(None) => break,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        break;
                     }
                 };
 
-                if tx
-                    .send(Ok(RunCommandResponse {
-                        output: output_message,
-                    }))
-                    .await
-                    .is_err()
-                {
+                // Since the stream started, the session may have disconnected, its user been
+                // dropped, or a first user been created so that it has to log in
+                let revoked: bool = match &login {
+                    Some(login) => !sessions.store.logins.is_open(login),
+                    None => sessions.store.logins.required(),
+                };
+
+                if revoked {
+                    let _ = tx
+                        .send(Err(Status::unauthenticated(
+                            "The session token was revoked, connect with ConnectToDB again",
+                        )))
+                        .await;
+                    break;
+                }
+
+                // Dropping `tx` ends the response stream once the final output is read
+                if lex_input(req.command, req.continue_on_error, db.clone(), &tx).await {
                     break;
                 }
             }
@@ -70,11 +103,58 @@ impl LilDbShellService for MyLilDBShell {
         &self,
         request: Request<ConnectToDbRequest>,
     ) -> Result<Response<ConnectToDbResponse>, Status> {
-        info!("New session with id: {}", request.get_ref().session_id);
+        let request: ConnectToDbRequest = request.into_inner();
+        let session_id: &str = &request.session_id;
+
+        if session_id.is_empty() {
+            return Ok(refused("A session id is required".into()));
+        }
+
+        let user: Option<String> = match request.credential {
+            Some(credential) => {
+                let valid: bool = users::verify(
+                    &self.sessions.new_database(),
+                    &credential.user,
+                    &credential.password,
+                )
+                .await
+                .map_err(DbError::from)?;
+
+                if !valid {
+                    return Ok(refused("Invalid user name or password".into()));
+                }
+
+                Some(credential.user)
+            }
+            None if self.sessions.store.logins.required() => {
+                return Ok(refused("A user name and password are required".into()));
+            }
+            None => None,
+        };
+
+        let Some(token) = self
+            .sessions
+            .connect(Login {
+                user: user.clone(),
+                session_id: session_id.to_string(),
+            })
+            .await
+            .map_err(DbError::from)?
+        else {
+            return Ok(refused(format!(
+                "Session \"{session_id}\" is already connected"
+            )));
+        };
+
+        match user {
+            Some(user) => info!("New session with id: {session_id}, user: {user}"),
+            None => info!("New session with id: {session_id}"),
+        }
 
         return Ok(Response::new(ConnectToDbResponse {
             success: true,
             message: "Connected!".into(),
+            token,
         }));
     }
 
@@ -82,10 +162,20 @@ impl LilDbShellService for MyLilDBShell {
         &self,
         request: Request<DisconnectFromDbRequest>,
     ) -> Result<Response<DisconnectFromDbResponse>, Status> {
-        info!(
-            "Session disconnected with id: {}",
-            request.get_ref().session_id
-        );
+        auth::require_login(&self.sessions.store, &request)?;
+
+        let session_id: &str = &request.get_ref().session_id;
+
+        sessions::check_owner(&request, session_id)?;
+
+        if !self.sessions.disconnect(session_id).await {
+            return Ok(Response::new(DisconnectFromDbResponse {
+                success: false,
+                message: format!("Unknown session \"{session_id}\""),
+            }));
+        }
+
+        info!("Session disconnected with id: {session_id}");
 
         return Ok(Response::new(DisconnectFromDbResponse {
             success: true,
@@ -93,3 +183,138 @@ impl LilDbShellService for MyLilDBShell {
         }));
     }
 }
+
+fn refused(message: String) -> Response<ConnectToDbResponse> {
+    Response::new(ConnectToDbResponse {
+        success: false,
+        message,
+        token: String::new(),
+    })
+}
+
+/// Sends the output of a statement, a chunk at a time. Waits while the channel is full, so
+/// a client that reads slowly only slows down its own stream. Returns `false` if the client
+/// went away, and the error of a row that couldn't be produced once the rows before it are
+/// sent.
+pub async fn send_output(tx: &ResponseSender, output: Output) -> Result<bool, DbError> {
+    let (mut rows, empty, cursor): (Rows, &str, Option<u64>) = match output {
+        Output::Message(output) => return Ok(send_chunk(tx, output, false, None).await),
+        Output::Rows {
+            rows,
+            empty,
+            cursor,
+        } => (rows, empty, cursor),
+    };
+
+    let mut chunk: String = String::new();
+
+    while let Some(row) = rows.next().await {
+        let row: String = match row {
+            Ok(row) => row,
+            Err(err) => {
+                if !chunk.is_empty() && !send_chunk(tx, chunk, true, None).await {
+                    return Ok(false);
+                }
+
+                return Err(err.into());
+            }
+        };
+
+        if !chunk.is_empty() && chunk.len() + row.len() + 2 > CHUNK_SIZE {
+            if !send_chunk(tx, chunk, true, None).await {
+                return Ok(false);
+            }
+
+            chunk = String::new();
+        }
+
+        chunk.push_str(&row);
+        chunk.push_str("\n\r");
+
+        // A row larger than a chunk is split across several
+        while chunk.len() > CHUNK_SIZE {
+            let rest: String = chunk.split_off(chunk.floor_char_boundary(CHUNK_SIZE));
+
+            if !send_chunk(tx, chunk, true, None).await {
+                return Ok(false);
+            }
+
+            chunk = rest;
+        }
+    }
+
+    if chunk.is_empty() {
+        chunk = format!("{empty}\n\r");
+    }
+
+    Ok(send_chunk(tx, chunk, false, cursor).await)
+}
+
+async fn send_chunk(tx: &ResponseSender, output: String, more: bool, cursor: Option<u64>) -> bool {
+    tx.send(Ok(RunCommandResponse {
+        output,
+        end_of_result: !more,
+        cursor_id: cursor.unwrap_or(0),
+        ..Default::default()
+    }))
+    .await
+    .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::{send_output, CHUNK_SIZE};
+    use crate::database_manager::{error::DbError, Output};
+
+    #[tokio::test]
+    async fn large_rows_are_split() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let row: String = "é".repeat(CHUNK_SIZE);
+
+        assert!(
+            send_output(&tx, Output::rows([row.clone(), "b".to_string()], ""))
+                .await
+                .unwrap()
+        );
+
+        drop(tx);
+
+        let mut output: String = String::new();
+
+        while let Some(response) = rx.recv().await {
+            let response = response.unwrap();
+
+            assert!(response.output.len() <= CHUNK_SIZE);
+            assert_eq!(response.end_of_result, rx.is_empty());
+
+            output.push_str(&response.output);
+        }
+
+        assert_eq!(output, format!("{row}\n\rb\n\r"));
+    }
+
+    #[tokio::test]
+    async fn failed_rows_follow_the_rows_before() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let (rows_tx, rows_rx) = mpsc::channel(16);
+
+        rows_tx.send(Ok("a".to_string())).await.unwrap();
+        rows_tx
+            .send(Err(DbError::NotFound("gone".to_string()).into()))
+            .await
+            .unwrap();
+
+        let err: DbError = send_output(&tx, Output::streamed(rows_rx, ""))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, DbError::NotFound(_)), "{err:?}");
+
+        let response = rx.recv().await.unwrap().unwrap();
+
+        assert_eq!(response.output, "a\n\r");
+        assert!(!response.end_of_result);
+    }
+}
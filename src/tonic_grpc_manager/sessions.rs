@@ -0,0 +1,328 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    sync::Mutex,
+    time::{self, Interval},
+};
+use tonic::{Request, Status};
+use tracing::info;
+
+use crate::database_manager::{configuration::Config, store::Store, users::Login, Database};
+
+/// Metadata key `RunCommand` streams use to pick the session opened with `ConnectToDB`.
+pub const SESSION_ID_METADATA: &str = "session-id";
+
+/// Every connected session and the database context it is working in.
+pub struct Sessions {
+    pub config: Arc<Config>,
+    pub store: Arc<Store>,
+    sessions: Mutex<HashMap<String, Arc<Mutex<Database>>>>,
+}
+
+impl Sessions {
+    pub fn new(config: Arc<Config>, store: Arc<Store>) -> Self {
+        Self {
+            config,
+            store,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A fresh context that isn't using any database yet.
+    pub fn new_database(&self) -> Database {
+        Database::new(
+            String::new(),
+            String::new(),
+            0_usize,
+            self.config.clone(),
+            self.store.clone(),
+        )
+    }
+
+    /// Registers the session of `login` and returns its token, `None` if the id is already
+    /// taken. Sessions whose tokens were revoked, by the first user being created or theirs
+    /// being dropped, can't disconnect anymore: they are dropped here, freeing their id.
+    pub async fn connect(&self, login: Login) -> anyhow::Result<Option<String>> {
+        let mut sessions = self.sessions.lock().await;
+
+        let live: HashSet<String> = self.store.logins.sessions();
+
+        let revoked: Vec<Arc<Mutex<Database>>> = sessions
+            .extract_if(|session_id, _| !live.contains(session_id))
+            .map(|(_, database)| database)
+            .collect();
+
+        let token: Option<String> = if sessions.contains_key(&login.session_id) {
+            None
+        } else {
+            sessions.insert(
+                login.session_id.clone(),
+                Arc::new(Mutex::new(self.new_database())),
+            );
+
+            // Given while the sessions are locked, so no other connection drops this one
+            // before it has a token
+            Some(self.store.logins.open(login)?)
+        };
+
+        drop(sessions);
+
+        for database in revoked {
+            release(&database).await;
+        }
+
+        Ok(token)
+    }
+
+    pub async fn get(&self, session_id: &str) -> Option<Arc<Mutex<Database>>> {
+        self.sessions.lock().await.get(session_id).cloned()
+    }
+
+    /// The context of the session named by the metadata of `request`, `None` if it names
+    /// none. A request that logged in can only name its own session.
+    pub async fn of_request<T>(
+        &self,
+        request: &Request<T>,
+    ) -> Result<Option<Arc<Mutex<Database>>>, Status> {
+        let Some(session_id) = request.metadata().get(SESSION_ID_METADATA) else {
+            return Ok(None);
+        };
+
+        let session_id: &str = session_id.to_str().map_err(|_| {
+            Status::invalid_argument(format!("Invalid \"{SESSION_ID_METADATA}\" metadata"))
+        })?;
+
+        check_owner(request, session_id)?;
+
+        match self.get(session_id).await {
+            Some(database) => Ok(Some(database)),
+            None => Err(Status::not_found(format!(
+                "Unknown session \"{session_id}\", connect with ConnectToDB first"
+            ))),
+        }
+    }
+
+    /// Drops a session, its context and its tokens, returning `false` if it didn't exist.
+    pub async fn disconnect(&self, session_id: &str) -> bool {
+        let Some(database) = self.sessions.lock().await.remove(session_id) else {
+            return false;
+        };
+
+        self.store.logins.close_session(session_id);
+
+        release(&database).await;
+
+        true
+    }
+
+    /// Closes the cursors of every session left unused for `timeout`.
+    pub async fn expire_cursors(&self, timeout: Duration) {
+        let databases: Vec<Arc<Mutex<Database>>> =
+            self.sessions.lock().await.values().cloned().collect();
+
+        let mut expired: usize = 0;
+
+        for database in databases {
+            expired += database.lock().await.cursors.expire(timeout);
+        }
+
+        if expired > 0 {
+            info!("Closed {expired} idle cursors");
+        }
+    }
+
+    /// Rolls back the transactions of every session left unused for `timeout`.
+    pub async fn expire_transactions(&self, timeout: Duration) {
+        let databases: Vec<Arc<Mutex<Database>>> =
+            self.sessions.lock().await.values().cloned().collect();
+
+        let mut expired: usize = 0;
+
+        for database in databases {
+            if database.lock().await.expire_transaction(timeout) {
+                expired += 1;
+            }
+        }
+
+        if expired > 0 {
+            info!("Rolled back {expired} idle transactions");
+        }
+    }
+}
+
+/// Drops the cursors and the transaction of a session that ended. Streams of the session
+/// still running keep its context until they end.
+async fn release(database: &Mutex<Database>) {
+    let mut database = database.lock().await;
+
+    database.cursors.clear();
+    database.transaction = None;
+}
+
+/// Rejects a request whose token was given to another session than `session_id`.
+pub fn check_owner<T>(request: &Request<T>, session_id: &str) -> Result<(), Status> {
+    match request.extensions().get::<Login>() {
+        Some(login) if login.session_id != session_id => Err(Status::permission_denied(format!(
+            "The session token doesn't belong to session \"{session_id}\""
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Closes idle cursors every `config.cursor_timeout`, for as long as the server runs. A
+/// cursor is closed at most twice the timeout after it was last used.
+pub async fn expire_cursors_periodically(sessions: Arc<Sessions>) {
+    let Some(timeout) = sessions.config.cursor_timeout else {
+        return;
+    };
+
+    let mut ticks: Interval = time::interval(timeout);
+
+    loop {
+        ticks.tick().await;
+
+        sessions.expire_cursors(timeout).await;
+    }
+}
+
+/// Rolls back idle transactions every `config.transaction_timeout`, for as long as the
+/// server runs, so that their snapshots don't hold old versions of documents forever.
+pub async fn expire_transactions_periodically(sessions: Arc<Sessions>) {
+    let Some(timeout) = sessions.config.transaction_timeout else {
+        return;
+    };
+
+    let mut ticks: Interval = time::interval(timeout);
+
+    loop {
+        ticks.tick().await;
+
+        sessions.expire_transactions(timeout).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tonic::{Code, Request};
+
+    use super::{check_owner, Sessions};
+    use crate::database_manager::{
+        address::Address,
+        configuration::{Compaction, Config},
+        format,
+        store::Store,
+        users::{self, Login},
+        Database,
+    };
+
+    fn sessions(name: &str) -> Sessions {
+        let config: Config = Config::new(
+            format::test_dir(name).to_string_lossy().into_owned(),
+            Address::default(),
+            None,
+            Compaction {
+                interval: None,
+                free_percent: 50,
+                min_size: 0,
+            },
+            None,
+            None,
+        );
+
+        Sessions::new(Arc::new(config), Arc::new(Store::new()))
+    }
+
+    fn login(user: Option<&str>, session_id: &str) -> Login {
+        Login {
+            user: user.map(str::to_string),
+            session_id: session_id.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn first_user_frees_anonymous_sessions() {
+        let sessions: Sessions = sessions("first_user_frees_anonymous_sessions");
+
+        let token: String = sessions.connect(login(None, "a")).await.unwrap().unwrap();
+
+        assert_eq!(sessions.connect(login(None, "a")).await.unwrap(), None);
+
+        users::create(&sessions.new_database(), "alice", "secret")
+            .await
+            .unwrap();
+
+        assert_eq!(sessions.store.logins.get(&token), None);
+
+        let token: String = sessions
+            .connect(login(Some("alice"), "a"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            sessions.store.logins.get(&token),
+            Some(login(Some("alice"), "a"))
+        );
+        assert!(sessions.get("a").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn dropped_user_frees_its_sessions() {
+        let sessions: Sessions = sessions("dropped_user_frees_its_sessions");
+        let context: Database = sessions.new_database();
+
+        users::create(&context, "alice", "secret").await.unwrap();
+        users::create(&context, "bob", "secret").await.unwrap();
+
+        let alice: String = sessions
+            .connect(login(Some("alice"), "a"))
+            .await
+            .unwrap()
+            .unwrap();
+        let bob: String = sessions
+            .connect(login(Some("bob"), "b"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        users::remove(&context, "alice").await.unwrap();
+
+        assert_eq!(sessions.store.logins.get(&alice), None);
+        assert_eq!(
+            sessions.store.logins.get(&bob),
+            Some(login(Some("bob"), "b"))
+        );
+
+        assert!(sessions
+            .connect(login(Some("bob"), "a"))
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            sessions.connect(login(Some("bob"), "b")).await.unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn tokens_only_name_their_own_session() {
+        let mut request: Request<()> = Request::new(());
+
+        request.extensions_mut().insert(login(None, "a"));
+
+        assert!(check_owner(&request, "a").is_ok());
+        assert_eq!(
+            check_owner(&request, "b").unwrap_err().code(),
+            Code::PermissionDenied
+        );
+
+        // Without a token, `require_login` decides
+        assert!(check_owner(&Request::new(()), "b").is_ok());
+    }
+}